@@ -0,0 +1,1624 @@
+use crate::file::{attached_comments_start, content_end, HostsChange, HostsFile, IGNORE_MARKER};
+use crate::parse::{
+    canonical_ip, comb_ipaddr, ip_family, maybe_hostname_alias, try_parse_hosts, CommentMarker,
+    HostsPart, HostsPartFamily,
+};
+use nom::branch::alt;
+use nom::bytes::complete::{tag, take_while1};
+use nom::character::complete::digit1;
+use nom::combinator::{eof, map, map_res, rest, value, verify};
+use nom::sequence::{delimited, preceded, separated_pair, terminated, tuple};
+use nom::IResult;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::cmp::min;
+use std::collections::BTreeSet;
+use std::net::IpAddr;
+
+/// A modification of a hosts file, see `HostsFile::apply`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Action {
+    /// Removes a hostname from all entries, dropping entries left without one. May contain `*`
+    /// and `?` wildcards to remove all matching hostnames.
+    Remove(String),
+    /// Like `Remove`, but only affects entries of the address family.
+    RemoveFamily(String, HostsPartFamily),
+    /// Adds a mapping of IP address to hostname, leaving other mappings of the hostname alone.
+    Define(IpAddr, String),
+    /// Like `Define`, but inserts a new entry at the given index of the file's parts instead of
+    /// near related entries. Indices past the end append to the file.
+    DefineAt(usize, IpAddr, String),
+    /// Adds a mapping of IP address to hostname, removing all other mappings of the hostname. The
+    /// new entry takes the place of the first removed one, comments attached to the others (see
+    /// `HostsFile::sort_canonical`) move along.
+    DefineExclusive(IpAddr, String),
+    /// Sets the trailing comment of all active entries containing the hostname or, given `None`,
+    /// strips it. The comment text follows the `#` character.
+    SetComment(String, Option<String>),
+}
+
+impl Action {
+    /// Returns the address family an action is restricted to: that of the IP address of a
+    /// definition or the one of `RemoveFamily`. `None` if it affects entries of either family.
+    pub fn family(&self) -> Option<HostsPartFamily> {
+        match self {
+            Action::Define(ip, _) | Action::DefineAt(_, ip, _) | Action::DefineExclusive(ip, _) => {
+                Some(ip_family(ip))
+            }
+            Action::RemoveFamily(_, family) => Some(*family),
+            Action::Remove(_) | Action::SetComment(..) => None,
+        }
+    }
+}
+
+/// Parses an action from its command line syntax: `-host` for `Remove`, `-host/v4` or `-host/v6`
+/// for `RemoveFamily`, `IP+=host` for `Define`, `@index:IP+=host` for `DefineAt`, `IP=host` for
+/// `DefineExclusive` and `host#comment` or `host#` for `SetComment`.
+///
+/// ```
+/// use hostsmod::{try_parse_action, Action};
+/// use std::net::{IpAddr, Ipv4Addr};
+///
+/// let define = Action::Define(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), "somehost".into());
+/// assert_eq!(Ok(define), try_parse_action("10.0.0.1+=somehost"));
+/// assert!(try_parse_action("10.0.0.1 somehost").is_err());
+/// ```
+pub fn try_parse_action(str_action: &str) -> Result<Action, String> {
+    comb_action(str_action)
+        .map_err(|err| format!("unable to parse action {:?}: {}", str_action, err))
+        .map(|(_, action)| action)
+}
+
+fn comb_action(input: &str) -> IResult<&str, Action> {
+    alt((
+        map(
+            terminated(
+                separated_pair(
+                    preceded(
+                        tag("-"),
+                        take_while1(|c| maybe_hostname_alias(c) || c == '*' || c == '?'),
+                    ),
+                    tag("/"),
+                    alt((
+                        value(HostsPartFamily::IPv4, tag("v4")),
+                        value(HostsPartFamily::IPv6, tag("v6")),
+                    )),
+                ),
+                eof,
+            ),
+            |(host, family): (&str, _)| Action::RemoveFamily(host.to_string(), family),
+        ),
+        map(
+            terminated(
+                preceded(
+                    tag("-"),
+                    take_while1(|c| maybe_hostname_alias(c) || c == '*' || c == '?'),
+                ),
+                eof,
+            ),
+            |host: &str| Action::Remove(host.to_string()),
+        ),
+        map(
+            terminated(
+                tuple((
+                    delimited(tag("@"), map_res(digit1, str::parse), tag(":")),
+                    terminated(comb_ipaddr, tag("+=")),
+                    take_while1(maybe_hostname_alias),
+                )),
+                eof,
+            ),
+            |(index, ip, host): (usize, _, &str)| Action::DefineAt(index, ip, host.to_string()),
+        ),
+        map(
+            terminated(
+                separated_pair(comb_ipaddr, tag("+="), take_while1(maybe_hostname_alias)),
+                eof,
+            ),
+            |(ip, host)| Action::Define(ip, host.to_string()),
+        ),
+        map(
+            terminated(
+                separated_pair(comb_ipaddr, tag("="), take_while1(maybe_hostname_alias)),
+                eof,
+            ),
+            |(ip, host)| Action::DefineExclusive(ip, host.to_string()),
+        ),
+        map(
+            separated_pair(
+                take_while1(maybe_hostname_alias),
+                tag("#"),
+                verify(rest, |comment: &str| !comment.contains(['\r', '\n'])),
+            ),
+            |(host, comment): (&str, &str)| {
+                let comment = Some(comment.to_string()).filter(|comment| !comment.is_empty());
+                Action::SetComment(host.to_string(), comment)
+            },
+        ),
+    ))(input)
+}
+
+/// Where `Define` places a new entry if there is no related entry to anchor to: a new entry always
+/// follows the last entry sharing its IP address or hostname. Unless `Policy::group_by_ip`
+/// applies.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum InsertPosition {
+    /// At the end of the file.
+    End,
+    /// At the beginning of the file.
+    Start,
+    /// At the end of the file, same as `End`.
+    #[default]
+    NearMatch,
+}
+
+/// Rules restricting and shaping the modifications made by `HostsFile::apply`.
+#[derive(Debug, Clone, Default)]
+pub struct Policy {
+    /// Hostnames which may be defined and removed, compared ignoring ASCII case.
+    pub whitelist: BTreeSet<String>,
+    /// Hostnames which may be defined, but not removed, compared ignoring ASCII case.
+    pub add_only: BTreeSet<String>,
+    /// Mappings of IP address to hostname which must not be removed if present before.
+    pub reserved: Vec<(IpAddr, String)>,
+    /// Whether `Remove` also affects commented-out entries.
+    pub include_commented: bool,
+    /// Whether `Remove` comments out an entry losing its last hostname instead of deleting it.
+    pub comment_on_remove: bool,
+    /// Whether `Define` appends the hostname to an existing entry with the same IP address.
+    pub merge_same_ip: bool,
+    /// Where `Define` places a new entry.
+    pub insert_position: InsertPosition,
+    /// Whether `Define` places a new entry right after the last entry with the same IP address,
+    /// even if another entry with the same hostname comes later.
+    pub group_by_ip: bool,
+    /// Whether comments and commented-out entries must be left untouched.
+    pub preserve_comments: bool,
+    /// Whether defining a multicast, broadcast, unspecified or documentation address is refused
+    /// instead of only warned about.
+    pub reject_special_ips: bool,
+    /// Whether an action may undo an earlier one for the same hostname, eg. `IP+=host` followed
+    /// by `-host`. Defining exclusively first and then adding with `+=` is never a conflict.
+    pub allow_conflicting_actions: bool,
+}
+
+/// Decides which hostnames actions may modify, see `HostsFile::apply_with`. Implemented by
+/// `Policy` using its `whitelist` and `add_only`, but library users may supply their own.
+pub trait HostPolicy {
+    /// Whether `host` may be defined. Unless it is add-only, it may also be removed.
+    fn allows(&self, host: &str) -> bool;
+    /// Whether `host` may only be defined, but not removed.
+    fn is_add_only(&self, _host: &str) -> bool {
+        false
+    }
+    /// Whether safety checks are skipped, currently that reserved mappings must be kept.
+    fn is_dangerous_enabled(&self) -> bool;
+}
+
+impl HostPolicy for Policy {
+    fn allows(&self, host: &str) -> bool {
+        is_listed(&self.whitelist, host) || is_listed(&self.add_only, host)
+    }
+
+    fn is_add_only(&self, host: &str) -> bool {
+        is_listed(&self.add_only, host)
+    }
+
+    fn is_dangerous_enabled(&self) -> bool {
+        false
+    }
+}
+
+/// Outcome of successfully applying actions.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ApplyReport {
+    /// Whether any modification was made.
+    pub changed: bool,
+    /// Indices of the actions which had no effect, eg. because the mapping already existed.
+    pub unchanged: Vec<usize>,
+    /// Suspicious but permitted actions, eg. defining a multicast address.
+    pub warnings: Vec<String>,
+    /// Every change made, along with the index of the action making it. Changes of one action
+    /// are in the order they were made.
+    pub changes: Vec<(usize, HostsChange<'static>)>,
+}
+
+/// Reason for rejecting actions, see `HostsFile::apply`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ApplyError {
+    /// The hostname is not whitelisted.
+    NotWhitelisted(String),
+    /// A hostname matching the pattern (second) is not whitelisted.
+    NotWhitelistedMatch(String, String),
+    /// The hostname may only be added, not removed or have its comment changed.
+    AddOnly(String),
+    /// The hostname already has an entry of the address family.
+    Duplicate(String, HostsPartFamily),
+    /// Commented-out entries may not be modified, see `Policy::preserve_comments`.
+    CommentsPreserved(String),
+    /// A reserved mapping would be removed.
+    Reserved(IpAddr, String),
+    /// The address is of a kind (second) no hostname should map to, see
+    /// `Policy::reject_special_ips`.
+    SpecialIp(IpAddr, &'static str),
+    /// The action at the first index (starting at 0) undoes the one at the second index for the
+    /// hostname, see `Policy::allow_conflicting_actions`.
+    ConflictingActions(usize, usize, String),
+    /// The hostname would not be parsed back as written, eg. because it contains `#`.
+    InvalidHostname(String),
+}
+
+impl std::fmt::Display for ApplyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ApplyError::NotWhitelisted(host) => write!(f, "HOST {:?} not whitelisted!", host),
+            ApplyError::NotWhitelistedMatch(host, pattern) => {
+                write!(f, "HOST {:?} matching {:?} not whitelisted!", host, pattern)
+            }
+            ApplyError::AddOnly(host) => {
+                write!(
+                    f,
+                    "HOST {:?} may only be added, not removed or modified!",
+                    host
+                )
+            }
+            ApplyError::Duplicate(host, family) => {
+                write!(f, "duplicate entry for host {:?} {:?}", host, family)
+            }
+            ApplyError::CommentsPreserved(pattern) => write!(
+                f,
+                "removing {:?} from commented entries forbidden by preserve_comments",
+                pattern
+            ),
+            ApplyError::Reserved(ip, host) => {
+                write!(f, "reserved entry {} {} would be removed", ip, host)
+            }
+            ApplyError::SpecialIp(ip, kind) => {
+                write!(f, "refusing to define {} address {}", kind, ip)
+            }
+            ApplyError::ConflictingActions(idx, idx_prev, host) => write!(
+                f,
+                "action {} undoes action {} for host {:?}",
+                idx + 1,
+                idx_prev + 1,
+                host
+            ),
+            ApplyError::InvalidHostname(host) => write!(
+                f,
+                "HOST {:?} would not be read back correctly from the hosts file",
+                host
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ApplyError {}
+
+impl<'a> HostsFile<'a> {
+    /// Applies all actions in order, subject to `policy`. Either all actions are applied or, on
+    /// error, the hosts file is left unchanged.
+    pub fn apply(
+        &mut self,
+        actions: &[Action],
+        policy: &Policy,
+    ) -> Result<ApplyReport, ApplyError> {
+        self.apply_with(actions, policy, policy)
+    }
+
+    /// Defines every mapping of IP address to hostname, like applying an `Action::Define` for
+    /// each. Either all are defined or none.
+    pub fn define_all<I: IntoIterator<Item = (IpAddr, String)>>(
+        &mut self,
+        mappings: I,
+        policy: &Policy,
+    ) -> Result<ApplyReport, ApplyError> {
+        let actions: Vec<Action> = mappings
+            .into_iter()
+            .map(|(ip, host)| Action::Define(ip, host))
+            .collect();
+        self.apply(&actions, policy)
+    }
+
+    /// Removes every hostname, like applying an `Action::Remove` for each. Either all are removed
+    /// or none.
+    pub fn remove_all<I: IntoIterator<Item = String>>(
+        &mut self,
+        hosts: I,
+        policy: &Policy,
+    ) -> Result<ApplyReport, ApplyError> {
+        let actions: Vec<Action> = hosts.into_iter().map(Action::Remove).collect();
+        self.apply(&actions, policy)
+    }
+
+    /// Like `apply`, but `host_policy` instead of `policy` decides which hostnames may be
+    /// modified.
+    pub fn apply_with(
+        &mut self,
+        actions: &[Action],
+        policy: &Policy,
+        host_policy: &dyn HostPolicy,
+    ) -> Result<ApplyReport, ApplyError> {
+        let reserved: &[(IpAddr, String)] = if host_policy.is_dangerous_enabled() {
+            &[]
+        } else {
+            &policy.reserved
+        };
+        let reserved_pre: Vec<bool> = reserved
+            .iter()
+            .map(|(ip, host)| has_mapping(self.parts(), ip, host))
+            .collect();
+
+        if !policy.allow_conflicting_actions {
+            if let Some((idx, idx_prev, host)) = find_conflicting_actions(actions) {
+                return Err(ApplyError::ConflictingActions(idx, idx_prev, host));
+            }
+        }
+
+        // modified in place, undoing every modification should anything fail
+        let mut journal = Journal::new(self.parts_mut());
+        let result =
+            apply_journaled(&mut journal, actions, policy, host_policy).and_then(|report| {
+                for ((ip, host), found) in reserved.iter().zip(reserved_pre) {
+                    if found && !has_mapping(journal.parts, ip, host) {
+                        return Err(ApplyError::Reserved(*ip, host.clone()));
+                    }
+                }
+                Ok(report)
+            });
+        if result.is_err() {
+            journal.rollback();
+        }
+        result
+    }
+}
+
+/// Applies all actions in order, see `HostsFile::apply_with`. Leaves undoing the modifications
+/// made so far on error to the caller.
+fn apply_journaled(
+    journal: &mut Journal,
+    actions: &[Action],
+    policy: &Policy,
+    host_policy: &dyn HostPolicy,
+) -> Result<ApplyReport, ApplyError> {
+    let mut report = ApplyReport::default();
+    for (idx, action) in actions.iter().enumerate() {
+        if let Action::Define(ip, host)
+        | Action::DefineAt(_, ip, host)
+        | Action::DefineExclusive(ip, host) = action
+        {
+            if !round_trips(ip, host) {
+                return Err(ApplyError::InvalidHostname(host.clone()));
+            }
+            if let Some(kind) = special_ip_kind(ip) {
+                if policy.reject_special_ips {
+                    return Err(ApplyError::SpecialIp(*ip, kind));
+                }
+                report
+                    .warnings
+                    .push(format!("defining {} address {} for {:?}", kind, ip, host));
+            }
+        }
+        if apply_action(journal, action, policy, host_policy, &mut report.warnings)? {
+            report.changed = true;
+        } else {
+            report.unchanged.push(idx);
+        }
+        report
+            .changes
+            .extend(journal.changes.drain(..).map(|change| (idx, change)));
+    }
+    Ok(report)
+}
+
+/// Finds the first action undoing an earlier definition of the same hostname: an exclusive
+/// definition with another IP address or a removal following any definition. Returns both
+/// indices and the hostname.
+fn find_conflicting_actions(actions: &[Action]) -> Option<(usize, usize, String)> {
+    for (idx, action) in actions.iter().enumerate() {
+        let undoes = |ip_prev: &IpAddr, host: &str| match action {
+            Action::DefineExclusive(ip, other) => {
+                other.eq_ignore_ascii_case(host) && canonical_ip(ip) != canonical_ip(ip_prev)
+            }
+            Action::Remove(pattern) if is_glob(pattern) => glob_matches(pattern, host),
+            Action::Remove(other) => other.eq_ignore_ascii_case(host),
+            Action::RemoveFamily(pattern, family) if ip_family(ip_prev) == *family => {
+                if is_glob(pattern) {
+                    glob_matches(pattern, host)
+                } else {
+                    pattern.eq_ignore_ascii_case(host)
+                }
+            }
+            Action::RemoveFamily(..) => false,
+            Action::Define(..) | Action::DefineAt(..) | Action::SetComment(..) => false,
+        };
+        for (idx_prev, prev) in actions[..idx].iter().enumerate() {
+            if let Action::Define(ip_prev, host)
+            | Action::DefineAt(_, ip_prev, host)
+            | Action::DefineExclusive(ip_prev, host) = prev
+            {
+                if undoes(ip_prev, host) {
+                    return Some((idx, idx_prev, host.clone()));
+                }
+            }
+        }
+    }
+    None
+}
+
+fn has_mapping(hosts: &[HostsPart], ip: &IpAddr, host: &str) -> bool {
+    hosts
+        .iter()
+        .any(|part| part.matches_hostname(host) && part.matches_ip_canonical(ip))
+}
+
+/// Classifies addresses a hostname is almost certainly not meant to map to. Loopback addresses
+/// are fine, they are common in hosts files.
+pub(crate) fn special_ip_kind(ip: &IpAddr) -> Option<&'static str> {
+    if ip.is_unspecified() {
+        return Some("unspecified");
+    }
+    if ip.is_multicast() {
+        return Some("multicast");
+    }
+    match ip {
+        IpAddr::V4(ip) if ip.is_broadcast() => Some("broadcast"),
+        IpAddr::V4(ip) if ip.is_documentation() => Some("documentation"),
+        // 2001:db8::/32
+        IpAddr::V6(ip) if ip.segments()[..2] == [0x2001, 0x0db8] => Some("documentation"),
+        _ => None,
+    }
+}
+
+/// Checks whether a hostname pattern contains glob wildcards (`*` or `?`).
+fn is_glob(pattern: &str) -> bool {
+    pattern.contains(['*', '?'])
+}
+
+/// Matches a hostname against a glob pattern, where `*` matches any sequence of characters and
+/// `?` any single character. Ignores ASCII case, same as the whitelist.
+fn glob_matches(pattern: &str, host: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().map(|c| c.to_ascii_lowercase()).collect();
+    let host: Vec<char> = host.chars().map(|c| c.to_ascii_lowercase()).collect();
+    let (mut p, mut h) = (0, 0);
+    // position of the last `*` and the host position it was tried at
+    let mut backtrack = None;
+    while h < host.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, h));
+                p += 1;
+            }
+            Some(c) if *c == '?' || *c == host[h] => {
+                p += 1;
+                h += 1;
+            }
+            _ => match backtrack {
+                Some((p_star, h_star)) => {
+                    p = p_star + 1;
+                    h = h_star + 1;
+                    backtrack = Some((p_star, h_star + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Checks whether an entry mapping `ip` to `host` is parsed back as written.
+fn round_trips(ip: &IpAddr, host: &str) -> bool {
+    let line = format!("{}\t{}", ip, host);
+    let entry = HostsPart::Entry(*ip, vec![Cow::Borrowed(host)], None);
+    try_parse_hosts(&line).is_ok_and(|parts| parts == [entry])
+}
+
+/// Checks whether `host` is in `list`, ignoring ASCII case like DNS does.
+fn is_listed(list: &BTreeSet<String>, host: &str) -> bool {
+    list.iter().any(|listed| listed.eq_ignore_ascii_case(host))
+}
+
+/// Checks whether an entry (commented out or not) is pinned by `IGNORE_MARKER` in its trailing
+/// comment.
+fn is_pinned(part: &HostsPart) -> bool {
+    match part {
+        HostsPart::Entry(_, _, Some(comment))
+        | HostsPart::CommentedEntry(_, _, Some(comment), ..) => {
+            comment.split_whitespace().any(|word| word == IGNORE_MARKER)
+        }
+        _ => false,
+    }
+}
+
+/// Like `is_pinned`, but adds a warning about leaving the entry alone to `warnings`.
+fn skip_pinned(part: &HostsPart, warnings: &mut Vec<String>) -> bool {
+    match part {
+        HostsPart::Entry(ip, hosts, _) | HostsPart::CommentedEntry(ip, hosts, ..)
+            if is_pinned(part) =>
+        {
+            warnings.push(format!(
+                "entry {} {} is marked {}, not modified",
+                ip,
+                hosts.join(" "),
+                IGNORE_MARKER
+            ));
+            true
+        }
+        _ => false,
+    }
+}
+
+/// The parts of a hosts file being modified by actions. Every modification is recorded as it is
+/// made, to be reported as a `HostsChange` and to be undone should a later action fail.
+struct Journal<'p, 'a> {
+    parts: &'p mut Vec<HostsPart<'a>>,
+    /// Changes not yet reported, see `ApplyReport::changes`.
+    changes: Vec<HostsChange<'static>>,
+    undo: Vec<Undo<'a>>,
+}
+
+/// Reverts a single modification recorded by `Journal`.
+enum Undo<'a> {
+    /// Removes the part inserted at the index.
+    Insert(usize),
+    /// Reinserts the part removed from the index.
+    Remove(usize, HostsPart<'a>),
+    /// Restores the part replaced at the index.
+    Modify(usize, HostsPart<'a>),
+}
+
+impl<'p, 'a> Journal<'p, 'a> {
+    fn new(parts: &'p mut Vec<HostsPart<'a>>) -> Self {
+        Journal {
+            parts,
+            changes: vec![],
+            undo: vec![],
+        }
+    }
+
+    fn insert(&mut self, idx: usize, part: HostsPart<'a>) {
+        self.changes
+            .push(HostsChange::Added(part.clone().into_owned()));
+        self.parts.insert(idx, part);
+        self.undo.push(Undo::Insert(idx));
+    }
+
+    fn remove(&mut self, idx: usize) -> HostsPart<'a> {
+        let part = self.parts.remove(idx);
+        self.changes
+            .push(HostsChange::Removed(part.clone().into_owned()));
+        self.undo.push(Undo::Remove(idx, part.clone()));
+        part
+    }
+
+    /// Reverts all modifications, newest first.
+    fn rollback(&mut self) {
+        while let Some(undo) = self.undo.pop() {
+            match undo {
+                Undo::Insert(idx) => {
+                    self.parts.remove(idx);
+                }
+                Undo::Remove(idx, part) => self.parts.insert(idx, part),
+                Undo::Modify(idx, part) => self.parts[idx] = part,
+            }
+        }
+        self.changes.clear();
+    }
+
+    /// Modifies the part at `idx` in place by `f`, which returns whether it changed anything.
+    fn modify<F: FnOnce(&mut HostsPart<'a>) -> bool>(&mut self, idx: usize, f: F) -> bool {
+        let before = self.parts[idx].clone();
+        if !f(&mut self.parts[idx]) {
+            return false;
+        }
+        self.changes.push(HostsChange::Modified {
+            before: before.clone().into_owned(),
+            after: self.parts[idx].clone().into_owned(),
+        });
+        self.undo.push(Undo::Modify(idx, before));
+        true
+    }
+}
+
+/// Applies a single action, returns whether any modification was made. Entries pinned by
+/// `IGNORE_MARKER` are skipped with a warning.
+fn apply_action(
+    journal: &mut Journal,
+    action: &Action,
+    policy: &Policy,
+    host_policy: &dyn HostPolicy,
+    warnings: &mut Vec<String>,
+) -> Result<bool, ApplyError> {
+    match action {
+        Action::Define(ip, host) | Action::DefineAt(_, ip, host) => {
+            if !host_policy.allows(host) {
+                return Err(ApplyError::NotWhitelisted(host.clone()));
+            }
+            // eprintln!("defining additionally...: {:?} += {:?}", ip, host);
+            let end = content_end(journal.parts);
+            let mut opt_insert = Some(end);
+            let mut anchored = false;
+            let mut host_found_v4 = false;
+            let mut host_found_v6 = false;
+            for (i, part) in journal
+                .parts
+                .iter()
+                .enumerate()
+                .filter(|(_i, p)| p.matches_ip(ip) || p.matches_hostname(host))
+            {
+                // eprintln!("matching entry: {:?}", part);
+                let matches_hostname = part.matches_hostname(host);
+                if part.matches_ip(ip) && matches_hostname {
+                    // eprintln!("already defined, NOP");
+                    return Ok(false);
+                }
+                if let (true, Some(family)) = (matches_hostname, part.get_family()) {
+                    let host_found = match family {
+                        HostsPartFamily::IPv4 => &mut host_found_v4,
+                        HostsPartFamily::IPv6 => &mut host_found_v6,
+                    };
+                    if *host_found || ip_family(ip) == family {
+                        return Err(ApplyError::Duplicate(host.clone(), family));
+                    }
+                    *host_found = true;
+                }
+                if opt_insert.is_some() {
+                    opt_insert = Some(i + 1);
+                    anchored = true;
+                }
+            }
+
+            let len = journal.parts.len();
+            let entry_new = HostsPart::Entry(*ip, vec![Cow::Owned(host.clone())], None);
+            if let Action::DefineAt(index, ..) = action {
+                journal.insert(min(*index, len), entry_new);
+                return Ok(true);
+            }
+
+            if policy.merge_same_ip {
+                if let Some(idx) = journal.parts.iter().position(|part| {
+                    matches!(part, HostsPart::Entry(..)) && part.matches_ip(ip) && !is_pinned(part)
+                }) {
+                    return Ok(
+                        journal.modify(idx, |part| part.add_hostname(Cow::Owned(host.clone())))
+                    );
+                }
+            }
+
+            let mut grouped = false;
+            if policy.group_by_ip {
+                if let Some(last) = journal
+                    .parts
+                    .iter()
+                    .rposition(|part| matches!(part, HostsPart::Entry(..)) && part.matches_ip(ip))
+                {
+                    opt_insert = Some(last + 1);
+                    grouped = true;
+                }
+            }
+
+            match opt_insert {
+                Some(insert) => {
+                    let insert = match policy.insert_position {
+                        InsertPosition::Start if !grouped && !anchored => 0,
+                        _ => min(insert, len),
+                    };
+                    journal.insert(insert, entry_new);
+                    Ok(true)
+                }
+                None => Ok(false),
+            }
+        }
+        Action::DefineExclusive(ip, host) => {
+            if !host_policy.allows(host) {
+                return Err(ApplyError::NotWhitelisted(host.clone()));
+            }
+            // eprintln!("defining exclusively...: {:?} += {:?}", ip, host);
+            let mut vec_remove = vec![];
+            for (i, part) in journal.parts.iter().enumerate().filter(|(_i, p)| {
+                p.matches_hostname(host) && !(policy.preserve_comments && p.is_commented())
+            }) {
+                if !skip_pinned(part, warnings) {
+                    vec_remove.push(i);
+                }
+            }
+            let entry_new = HostsPart::Entry(*ip, vec![Cow::Owned(host.clone())], None);
+            if let [remove] = vec_remove.as_slice() {
+                if journal.parts[*remove] == entry_new {
+                    // exactly the entry that would be reinserted at the same position
+                    return Ok(false);
+                }
+            }
+            let insert = vec_remove
+                .first()
+                .copied()
+                .unwrap_or_else(|| content_end(journal.parts));
+            // comments attached to later matches move along to the new entry, collected bottom-up
+            let mut moved = vec![];
+            for remove in vec_remove.iter().skip(1).rev() {
+                let begin = if policy.preserve_comments {
+                    *remove
+                } else {
+                    attached_comments_start(journal.parts, *remove)
+                };
+                journal.remove(*remove);
+                for idx in (begin..*remove).rev() {
+                    moved.push(journal.remove(idx));
+                }
+            }
+            if !vec_remove.is_empty() {
+                journal.remove(insert);
+            }
+            for (offset, part) in moved.into_iter().rev().chain(Some(entry_new)).enumerate() {
+                journal.insert(insert + offset, part);
+            }
+            Ok(true)
+        }
+        Action::Remove(pattern) | Action::RemoveFamily(pattern, _) => {
+            let in_family = |part: &HostsPart| match action {
+                Action::RemoveFamily(_, family) => part.get_family() == Some(*family),
+                _ => true,
+            };
+            let hosts_remove = if is_glob(pattern) {
+                let mut hosts_remove = vec![];
+                for part in journal.parts.iter().filter(|part| in_family(part)) {
+                    let aliases = match part {
+                        HostsPart::Entry(_, aliases, _) => aliases,
+                        HostsPart::CommentedEntry(_, aliases, ..) if policy.include_commented => {
+                            aliases
+                        }
+                        _ => continue,
+                    };
+                    for alias in aliases {
+                        if glob_matches(pattern, alias) && !hosts_remove.contains(alias) {
+                            hosts_remove.push(alias.clone());
+                        }
+                    }
+                }
+                // either the pattern itself or every single match must be whitelisted
+                if !host_policy.allows(pattern) {
+                    if let Some(host) = hosts_remove
+                        .iter()
+                        .find(|host| !host_policy.allows(host.as_ref()))
+                    {
+                        return Err(ApplyError::NotWhitelistedMatch(
+                            host.to_string(),
+                            pattern.clone(),
+                        ));
+                    }
+                }
+                hosts_remove
+            } else {
+                if !host_policy.allows(pattern) {
+                    return Err(ApplyError::NotWhitelisted(pattern.clone()));
+                }
+                vec![Cow::Borrowed(pattern.as_str())]
+            };
+            if let Some(host) = hosts_remove
+                .iter()
+                .map(|host| host.as_ref())
+                .chain(std::iter::once(pattern.as_str()))
+                .find(|host| host_policy.is_add_only(host))
+            {
+                return Err(ApplyError::AddOnly(host.to_string()));
+            }
+            if policy.include_commented
+                && policy.preserve_comments
+                && journal.parts.iter().any(|part| {
+                    part.is_commented()
+                        && in_family(part)
+                        && !is_pinned(part)
+                        && hosts_remove.iter().any(|host| part.matches_hostname(host))
+                })
+            {
+                return Err(ApplyError::CommentsPreserved(pattern.clone()));
+            }
+            let mut changed = false;
+            for host in hosts_remove.iter() {
+                let mut vec_match = vec![];
+                for (i, part) in journal
+                    .parts
+                    .iter()
+                    .enumerate()
+                    .filter(|(_i, p)| p.matches_hostname(host) && in_family(p))
+                {
+                    match part {
+                        HostsPart::Entry(..) => {}
+                        HostsPart::CommentedEntry(..) if policy.include_commented => {}
+                        _ => continue,
+                    };
+                    if !skip_pinned(part, warnings) {
+                        vec_match.push(i);
+                    }
+                }
+                // back to front, so removing an entry does not shift the remaining ones
+                for i in vec_match.into_iter().rev() {
+                    match &journal.parts[i] {
+                        HostsPart::Entry(_, aliases, _)
+                        | HostsPart::CommentedEntry(_, aliases, ..)
+                            if aliases.len() > 1 =>
+                        {
+                            journal.modify(i, |part| part.remove_hostname(host));
+                        }
+                        HostsPart::Entry(ip, aliases, opt_comment) if policy.comment_on_remove => {
+                            let part_commented = HostsPart::CommentedEntry(
+                                *ip,
+                                aliases.clone(),
+                                opt_comment.clone(),
+                                CommentMarker::Hash,
+                                Cow::Borrowed(""),
+                            );
+                            journal.modify(i, |part| {
+                                *part = part_commented;
+                                true
+                            });
+                        }
+                        _ => {
+                            journal.remove(i);
+                        }
+                    }
+                    changed = true;
+                }
+            }
+            Ok(changed)
+        }
+        Action::SetComment(host, comment) => {
+            if !host_policy.allows(host) {
+                return Err(ApplyError::NotWhitelisted(host.clone()));
+            }
+            if host_policy.is_add_only(host) {
+                return Err(ApplyError::AddOnly(host.clone()));
+            }
+            let comment_new = comment.as_ref().map(|comment| Cow::Owned(comment.clone()));
+            let vec_match: Vec<usize> = journal
+                .parts
+                .iter()
+                .enumerate()
+                .filter(|(_i, part)| {
+                    matches!(part, HostsPart::Entry(..))
+                        && part.matches_hostname(host)
+                        && !skip_pinned(part, warnings)
+                })
+                .map(|(i, _part)| i)
+                .collect();
+            let mut changed = false;
+            for i in vec_match {
+                changed |= journal.modify(i, |part| match part {
+                    HostsPart::Entry(_, _, comment_old) if *comment_old != comment_new => {
+                        *comment_old = comment_new.clone();
+                        true
+                    }
+                    _ => false,
+                });
+            }
+            Ok(changed)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::action::{
+        comb_action, glob_matches, Action, ApplyError, ApplyReport, HostPolicy, InsertPosition,
+        Policy,
+    };
+    use crate::file::{HostsChange, HostsFile};
+    use crate::parse::{ip_family, HostsPart, HostsPartFamily};
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+    use std::str::FromStr;
+
+    fn policy(whitelist: &[&str]) -> Policy {
+        Policy {
+            whitelist: whitelist.iter().map(|host| host.to_string()).collect(),
+            ..Policy::default()
+        }
+    }
+
+    #[test]
+    fn test_action_family() {
+        let ip4 = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let ip6 = IpAddr::V6("2001:db8::1".parse().unwrap());
+        assert_eq!(HostsPartFamily::IPv4, ip_family(&ip4));
+        assert_eq!(HostsPartFamily::IPv6, ip_family(&ip6));
+
+        let v4 = [
+            Action::Define(ip4, "somehost".into()),
+            Action::DefineAt(0, ip4, "somehost".into()),
+            Action::DefineExclusive(ip4, "somehost".into()),
+            Action::RemoveFamily("somehost".into(), HostsPartFamily::IPv4),
+        ];
+        for action in &v4 {
+            assert_eq!(Some(HostsPartFamily::IPv4), action.family(), "{:?}", action);
+        }
+        let v6 = [
+            Action::Define(ip6, "somehost".into()),
+            Action::DefineAt(0, ip6, "somehost".into()),
+            Action::DefineExclusive(ip6, "somehost".into()),
+            Action::RemoveFamily("somehost".into(), HostsPartFamily::IPv6),
+        ];
+        for action in &v6 {
+            assert_eq!(Some(HostsPartFamily::IPv6), action.family(), "{:?}", action);
+        }
+        assert_eq!(None, Action::Remove("somehost".into()).family());
+        assert_eq!(None, Action::SetComment("somehost".into(), None).family());
+    }
+
+    #[test]
+    fn test_parse_actions() {
+        {
+            let (remainder, parsed) = comb_action("-somehost").unwrap();
+            assert_eq!("", remainder);
+            assert_eq!(Action::Remove("somehost".into()), parsed);
+        }
+        {
+            let (remainder, parsed) = comb_action("127.1.65.77+=somehost").unwrap();
+            assert_eq!("", remainder);
+            assert_eq!(
+                Action::Define(IpAddr::V4(Ipv4Addr::new(127, 1, 65, 77)), "somehost".into()),
+                parsed
+            );
+        }
+        {
+            let (remainder, parsed) = comb_action("2003::f+=somehost").unwrap();
+            assert_eq!("", remainder);
+            assert_eq!(
+                Action::Define(
+                    IpAddr::V6(Ipv6Addr::from_str("2003::f").unwrap()),
+                    "somehost".into()
+                ),
+                parsed
+            );
+        }
+        {
+            let (remainder, parsed) = comb_action("::1=somehost").unwrap();
+            assert_eq!("", remainder);
+            assert_eq!(
+                Action::DefineExclusive(
+                    IpAddr::V6(Ipv6Addr::from_str("::1").unwrap()),
+                    "somehost".into()
+                ),
+                parsed
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_remove_glob() {
+        let (remainder, parsed) = comb_action("-*.dev.example").unwrap();
+        assert_eq!("", remainder);
+        assert_eq!(Action::Remove("*.dev.example".into()), parsed);
+    }
+
+    #[test]
+    fn test_parse_remove_family() {
+        assert_eq!(
+            Ok((
+                "",
+                Action::RemoveFamily("somehost".into(), HostsPartFamily::IPv6)
+            )),
+            comb_action("-somehost/v6")
+        );
+        assert_eq!(
+            Ok((
+                "",
+                Action::RemoveFamily("*.example".into(), HostsPartFamily::IPv4)
+            )),
+            comb_action("-*.example/v4")
+        );
+        assert!(comb_action("-somehost/v5").is_err());
+        assert!(comb_action("-somehost/").is_err());
+    }
+
+    #[test]
+    fn test_define_remove_all() {
+        let mut file = HostsFile::parse("127.0.0.1\tlocalhost\n").unwrap();
+        let mappings: Vec<(IpAddr, String)> = (1..=10)
+            .map(|i| {
+                let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, i));
+                (ip, format!("host{}.example", i))
+            })
+            .collect();
+        let hosts: Vec<String> = mappings.iter().map(|(_, host)| host.clone()).collect();
+        let hosts_ref: Vec<&str> = hosts.iter().map(String::as_str).collect();
+        let policy = policy(&hosts_ref);
+
+        let report = file.define_all(mappings.clone(), &policy).unwrap();
+        assert!(report.changed);
+        assert_eq!(10, report.changes.len());
+        for (ip, host) in &mappings {
+            assert!(file
+                .parts()
+                .iter()
+                .any(|part| part.matches_ip(ip) && part.matches_hostname(host)));
+        }
+        assert_eq!(
+            (0..10).collect::<Vec<_>>(),
+            file.define_all(mappings, &policy).unwrap().unchanged
+        );
+
+        // nothing is removed if one of the hostnames is not whitelisted
+        let before = file.clone();
+        let mut not_allowed = hosts.clone();
+        not_allowed.push("localhost".into());
+        assert!(file.remove_all(not_allowed, &policy).is_err());
+        assert_eq!(before, file);
+        assert!(file.remove_all(hosts, &policy).unwrap().changed);
+        assert_eq!(HostsFile::parse("127.0.0.1\tlocalhost\n").unwrap(), file);
+    }
+
+    #[test]
+    fn test_apply_define_at() {
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        assert_eq!(
+            Ok(("", Action::DefineAt(5, ip, "somehost".into()))),
+            comb_action("@5:10.0.0.1+=somehost")
+        );
+        assert!(comb_action("@:10.0.0.1+=somehost").is_err());
+        assert!(comb_action("@5:10.0.0.1=somehost").is_err());
+
+        let data = "127.0.0.1	localhost
+# comment
+10.0.0.1	other.example
+::1	localhost";
+        let mut file = HostsFile::parse(data).unwrap();
+        let policy = policy(&["somehost", "otherhost"]);
+        // placed at the index despite the entry with the same IP address
+        let actions = [Action::DefineAt(1, ip, "somehost".into())];
+        assert!(file.apply(&actions, &policy).unwrap().changed);
+        let entry = |host: &str| HostsPart::Entry(ip, vec![host.to_string().into()], None);
+        assert_eq!(entry("somehost"), file.parts()[1]);
+        assert!(!file.apply(&actions, &policy).unwrap().changed);
+
+        let actions = [Action::DefineAt(99, ip, "otherhost".into())];
+        assert!(file.apply(&actions, &policy).unwrap().changed);
+        assert_eq!(Some(&entry("otherhost")), file.parts().last());
+    }
+
+    #[test]
+    fn test_apply_pinned() {
+        let data = "10.0.0.1\tsomehost # hostsmod:ignore\n10.0.0.2\tsomehost otherhost\n";
+        let mut file = HostsFile::parse(data).unwrap();
+        let policy = policy(&["somehost", "otherhost"]);
+        let report = file
+            .apply(&[Action::Remove("somehost".into())], &policy)
+            .unwrap();
+        assert!(report.changed);
+        assert_eq!(
+            vec!["entry 10.0.0.1 somehost is marked hostsmod:ignore, not modified".to_string()],
+            report.warnings
+        );
+        assert_eq!(
+            HostsFile::parse("10.0.0.1\tsomehost # hostsmod:ignore\n10.0.0.2\totherhost\n")
+                .unwrap(),
+            file
+        );
+
+        // nothing else to remove, so the action is skipped entirely
+        let report = file
+            .apply(&[Action::Remove("somehost".into())], &policy)
+            .unwrap();
+        assert!(!report.changed);
+        assert_eq!(1, report.warnings.len());
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 3));
+        let actions = [
+            Action::DefineExclusive(ip, "somehost".into()),
+            Action::SetComment("somehost".into(), None),
+        ];
+        file.apply(&actions, &policy).unwrap();
+        assert!(file.parts().contains(&HostsPart::Entry(
+            IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+            vec!["somehost".into()],
+            Some(" hostsmod:ignore".into())
+        )));
+    }
+
+    #[test]
+    fn test_apply_remove_family() {
+        let data = "10.0.0.1\tsomehost\n2001:db8::1\tsomehost otherhost\n# ::2\tsomehost\n";
+        let mut file = HostsFile::parse(data).unwrap();
+        let remove_v6 = [Action::RemoveFamily(
+            "somehost".into(),
+            HostsPartFamily::IPv6,
+        )];
+        assert_eq!(
+            Err(ApplyError::NotWhitelisted("somehost".into())),
+            file.apply(&remove_v6, &policy(&["otherhost"]))
+        );
+        let mut allowing = policy(&["somehost"]);
+        allowing.include_commented = true;
+        assert!(file.apply(&remove_v6, &allowing).unwrap().changed);
+        assert_eq!(
+            HostsFile::parse("10.0.0.1\tsomehost\n2001:db8::1\totherhost\n").unwrap(),
+            file
+        );
+        assert!(!file.apply(&remove_v6, &allowing).unwrap().changed);
+
+        // removing the v4 mapping is no conflict with defining a v6 one just before
+        let actions = [
+            Action::Define("2001:db8::2".parse().unwrap(), "somehost".into()),
+            Action::RemoveFamily("some*".into(), HostsPartFamily::IPv4),
+        ];
+        assert!(
+            file.apply(&actions, &policy(&["somehost", "some*"]))
+                .unwrap()
+                .changed
+        );
+        let families: Vec<_> = file
+            .parts()
+            .iter()
+            .filter(|part| part.matches_hostname("somehost"))
+            .map(HostsPart::get_family)
+            .collect();
+        assert_eq!(vec![Some(HostsPartFamily::IPv6)], families);
+    }
+
+    #[test]
+    fn test_parse_set_comment() {
+        assert_eq!(
+            Ok((
+                "",
+                Action::SetComment("somehost".into(), Some(" a # note".into()))
+            )),
+            comb_action("somehost# a # note")
+        );
+        assert_eq!(
+            Ok(("", Action::SetComment("somehost".into(), None))),
+            comb_action("somehost#")
+        );
+        assert!(comb_action("somehost#multi\nline").is_err());
+    }
+
+    #[test]
+    fn test_apply_set_comment() {
+        let data = "10.0.0.1\tsomehost\n# 10.0.0.2\tsomehost\n::1\tsomehost # old\n";
+        let mut file = HostsFile::parse(data).unwrap();
+        let policy = policy(&["somehost"]);
+        let set = [Action::SetComment("somehost".into(), Some(" note".into()))];
+        assert!(file.apply(&set, &policy).unwrap().changed);
+        assert_eq!(
+            HostsFile::parse(
+                "10.0.0.1\tsomehost # note\n# 10.0.0.2\tsomehost\n::1\tsomehost # note\n"
+            )
+            .unwrap(),
+            file
+        );
+        assert!(!file.apply(&set, &policy).unwrap().changed);
+
+        let clear = [Action::SetComment("somehost".into(), None)];
+        assert!(file.apply(&clear, &policy).unwrap().changed);
+        assert_eq!(
+            HostsFile::parse("10.0.0.1\tsomehost\n# 10.0.0.2\tsomehost\n::1\tsomehost\n").unwrap(),
+            file
+        );
+
+        let policy = Policy {
+            add_only: policy.whitelist,
+            ..Policy::default()
+        };
+        assert_eq!(
+            Err(ApplyError::AddOnly("somehost".into())),
+            file.apply(&set, &policy)
+        );
+        let set_other = [Action::SetComment("otherhost".into(), None)];
+        assert_eq!(
+            Err(ApplyError::NotWhitelisted("otherhost".into())),
+            file.apply(&set_other, &policy)
+        );
+    }
+
+    #[test]
+    fn test_apply_define() {
+        let mut file = HostsFile::parse("127.0.0.1\tlocalhost\n10.0.0.1\tsomehost\n").unwrap();
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let actions = [
+            Action::Define(ip, "otherhost".into()),
+            Action::Define(ip, "otherhost".into()),
+        ];
+        let report = file
+            .apply(&actions, &policy(&["otherhost"]))
+            .expect("unable to apply actions");
+        assert_eq!(
+            ApplyReport {
+                changed: true,
+                unchanged: vec![1],
+                warnings: vec![],
+                changes: vec![(
+                    0,
+                    HostsChange::Added(HostsPart::Entry(ip, vec!["otherhost".into()], None))
+                )],
+            },
+            report
+        );
+        assert_eq!(
+            HostsFile::parse("127.0.0.1\tlocalhost\n10.0.0.1\tsomehost\n10.0.0.1\totherhost\n")
+                .unwrap(),
+            file
+        );
+        assert_eq!(
+            Err(ApplyError::NotWhitelisted("somehost".into())),
+            file.apply(&[Action::Define(ip, "somehost".into())], &Policy::default())
+        );
+    }
+
+    #[test]
+    fn test_apply_trailing_line_break() {
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let expected = HostsFile::parse("127.0.0.1\tlocalhost\n10.0.0.1\tsomehost\n").unwrap();
+        for actions in [
+            [Action::Define(ip, "somehost".into())],
+            [Action::DefineExclusive(ip, "somehost".into())],
+        ] {
+            for insert_position in [InsertPosition::End, InsertPosition::NearMatch] {
+                // parsed including the empty part following the final line break
+                let mut file = HostsFile::parse("127.0.0.1\tlocalhost\n").unwrap();
+                let policy = Policy {
+                    insert_position,
+                    ..policy(&["somehost"])
+                };
+                file.apply(&actions, &policy)
+                    .expect("unable to apply actions");
+                assert_eq!(expected, file, "{:?} {:?}", actions, insert_position);
+            }
+        }
+    }
+
+    #[test]
+    fn test_apply_whitelist_case_insensitive() {
+        let mut file = HostsFile::parse("127.0.0.1\tlocalhost\n10.0.0.1\tsomehost\n").unwrap();
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let report = file
+            .apply(
+                &[Action::Define(ip, "API.dev.example".into())],
+                &policy(&["api.dev.example"]),
+            )
+            .expect("unable to apply actions");
+        assert!(report.changed);
+        assert_eq!(
+            HostsFile::parse(
+                "127.0.0.1\tlocalhost\n10.0.0.1\tsomehost\n10.0.0.1\tAPI.dev.example\n"
+            )
+            .unwrap(),
+            file
+        );
+    }
+
+    #[test]
+    fn test_apply_conflicting_actions() {
+        let mut file = HostsFile::parse("127.0.0.1\tlocalhost\n").unwrap();
+        let ip_a = IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4));
+        let ip_b = IpAddr::V4(Ipv4Addr::new(5, 6, 7, 8));
+        let conflicting = [
+            Action::DefineExclusive(ip_a, "foo".into()),
+            Action::DefineExclusive(ip_b, "foo".into()),
+        ];
+        assert_eq!(
+            Err(ApplyError::ConflictingActions(1, 0, "foo".into())),
+            file.apply(&conflicting, &policy(&["foo"]))
+        );
+        assert_eq!(HostsFile::parse("127.0.0.1\tlocalhost\n").unwrap(), file);
+        let undone = [
+            Action::Define(ip_a, "foo".into()),
+            Action::Remove("f*".into()),
+        ];
+        assert_eq!(
+            Err(ApplyError::ConflictingActions(1, 0, "foo".into())),
+            file.apply(&undone, &policy(&["foo", "f*"]))
+        );
+
+        // the documented way to replace all mappings
+        let intended = [
+            Action::DefineExclusive(ip_a, "foo".into()),
+            Action::Define("::1".parse().unwrap(), "foo".into()),
+        ];
+        assert!(file.apply(&intended, &policy(&["foo"])).is_ok());
+        let mut allowing = policy(&["foo"]);
+        allowing.allow_conflicting_actions = true;
+        assert!(file.apply(&conflicting, &allowing).is_ok());
+    }
+
+    #[test]
+    fn test_apply_group_by_ip() {
+        let data = "fd00::1\tsomehost\n10.0.0.2\tapi.example\n\n10.0.0.9\tother.example\n";
+        let ip = IpAddr::V6(Ipv6Addr::from_str("fd00::1").unwrap());
+        let actions = [Action::Define(ip, "api.example".into())];
+        let mut file = HostsFile::parse(data).unwrap();
+        file.apply(&actions, &policy(&["api.example"])).unwrap();
+        assert_eq!(
+            HostsFile::parse(
+                "fd00::1\tsomehost\n10.0.0.2\tapi.example\nfd00::1\tapi.example\n\n10.0.0.9\tother.example\n"
+            )
+            .unwrap(),
+            file
+        );
+
+        let mut file = HostsFile::parse(data).unwrap();
+        let mut grouping = policy(&["api.example"]);
+        grouping.group_by_ip = true;
+        file.apply(&actions, &grouping).unwrap();
+        assert_eq!(
+            HostsFile::parse(
+                "fd00::1\tsomehost\nfd00::1\tapi.example\n10.0.0.2\tapi.example\n\n10.0.0.9\tother.example\n"
+            )
+            .unwrap(),
+            file
+        );
+    }
+
+    #[test]
+    fn test_apply_define_exclusive() {
+        let mut file = HostsFile::parse("10.0.0.1\tsomehost\n::1\tsomehost\n").unwrap();
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+        let actions = [Action::DefineExclusive(ip, "somehost".into())];
+        let report = file.apply(&actions, &policy(&["somehost"])).unwrap();
+        assert!(report.changed);
+        assert_eq!(HostsFile::parse("10.0.0.2\tsomehost\n").unwrap(), file);
+        let report = file.apply(&actions, &policy(&["somehost"])).unwrap();
+        assert!(!report.changed);
+
+        // attached comments of removed entries are kept above the new one
+        let data = "# a\n10.0.0.1\tsomehost\n\n# b1\n# b2\n::1\tsomehost\n# c\n10.0.0.9\tother\n";
+        let mut file = HostsFile::parse(data).unwrap();
+        assert!(
+            file.apply(&actions, &policy(&["somehost"]))
+                .unwrap()
+                .changed
+        );
+        assert_eq!(
+            HostsFile::parse("# a\n# b1\n# b2\n10.0.0.2\tsomehost\n\n# c\n10.0.0.9\tother\n")
+                .unwrap(),
+            file
+        );
+    }
+
+    #[test]
+    fn test_apply_remove() {
+        let data = "10.0.0.1\tsomehost otherhost\n# 10.0.0.2\tsomehost\n";
+        let mut file = HostsFile::parse(data).unwrap();
+        let actions = [Action::Remove("somehost".into())];
+        assert!(
+            file.apply(&actions, &policy(&["somehost"]))
+                .unwrap()
+                .changed
+        );
+        assert_eq!(
+            HostsFile::parse("10.0.0.1\totherhost\n# 10.0.0.2\tsomehost\n").unwrap(),
+            file
+        );
+
+        let mut policy = policy(&["some*"]);
+        policy.include_commented = true;
+        let mut file = HostsFile::parse(data).unwrap();
+        let actions = [Action::Remove("some*".into())];
+        assert!(file.apply(&actions, &policy).unwrap().changed);
+        assert_eq!(HostsFile::parse("10.0.0.1\totherhost\n").unwrap(), file);
+    }
+
+    #[test]
+    fn test_apply_reserved() {
+        let data = "127.0.0.1\tlocalhost\n";
+        let mut file = HostsFile::parse(data).unwrap();
+        let mut policy = policy(&["localhost"]);
+        policy.reserved = vec![(IpAddr::V4(Ipv4Addr::LOCALHOST), "localhost".into())];
+        assert_eq!(
+            Err(ApplyError::Reserved(
+                IpAddr::V4(Ipv4Addr::LOCALHOST),
+                "localhost".into()
+            )),
+            file.apply(&[Action::Remove("localhost".into())], &policy)
+        );
+        assert_eq!(HostsFile::parse(data).unwrap(), file);
+    }
+
+    #[test]
+    fn test_apply_rollback() {
+        let data = "# a\n10.0.0.1\tsomehost\n# b\n::1\tsomehost\n10.0.0.3\totherhost third\n";
+        let mut file = HostsFile::parse(data).unwrap();
+        let mut policy = policy(&["somehost", "otherhost", "newhost"]);
+        policy.comment_on_remove = true;
+        policy.merge_same_ip = true;
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 3));
+        let actions = [
+            Action::DefineExclusive(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)), "somehost".into()),
+            Action::Define(ip, "newhost".into()),
+            Action::Remove("otherhost".into()),
+            Action::SetComment("newhost".into(), Some("note".into())),
+            Action::Remove("third".into()),
+        ];
+        assert_eq!(
+            Err(ApplyError::NotWhitelisted("third".into())),
+            file.apply(&actions, &policy)
+        );
+        assert_eq!(HostsFile::parse(data).unwrap(), file);
+
+        // the same without the failing action
+        let report = file.apply(&actions[..4], &policy).unwrap();
+        assert!(report.unchanged.is_empty());
+        assert_eq!(
+            HostsFile::parse("# a\n# b\n10.0.0.2\tsomehost\n10.0.0.3\tthird newhost #note\n")
+                .unwrap(),
+            file
+        );
+    }
+
+    #[test]
+    fn test_apply_provenance() {
+        let mut file = HostsFile::parse("10.0.0.1\tsomehost otherhost\n").unwrap();
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+        let actions = [
+            Action::Remove("otherhost".into()),
+            Action::Define(ip, "newhost".into()),
+        ];
+        let report = file
+            .apply(&actions, &policy(&["otherhost", "newhost"]))
+            .unwrap();
+        assert_eq!(
+            vec![
+                (
+                    0,
+                    HostsChange::Modified {
+                        before: HostsPart::Entry(
+                            IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+                            vec!["somehost".into(), "otherhost".into()],
+                            None
+                        ),
+                        after: HostsPart::Entry(
+                            IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+                            vec!["somehost".into()],
+                            None
+                        ),
+                    }
+                ),
+                (
+                    1,
+                    HostsChange::Added(HostsPart::Entry(ip, vec!["newhost".into()], None))
+                ),
+            ],
+            report.changes
+        );
+    }
+
+    struct AllowAll {
+        dangerous: bool,
+    }
+
+    impl HostPolicy for AllowAll {
+        fn allows(&self, _host: &str) -> bool {
+            true
+        }
+
+        fn is_dangerous_enabled(&self) -> bool {
+            self.dangerous
+        }
+    }
+
+    #[test]
+    fn test_apply_custom_host_policy() {
+        let data = "127.0.0.1\tlocalhost\n10.0.0.1\tsomehost\n";
+        let mut file = HostsFile::parse(data).unwrap();
+        let actions = [
+            Action::Define(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)), "any.example".into()),
+            Action::Remove("somehost".into()),
+            Action::Remove("localhost".into()),
+        ];
+        let policy = Policy {
+            reserved: vec![(IpAddr::V4(Ipv4Addr::LOCALHOST), "localhost".into())],
+            ..Policy::default()
+        };
+        assert_eq!(
+            Err(ApplyError::NotWhitelisted("any.example".into())),
+            file.apply(&actions, &policy)
+        );
+        assert_eq!(
+            Err(ApplyError::Reserved(
+                IpAddr::V4(Ipv4Addr::LOCALHOST),
+                "localhost".into()
+            )),
+            file.apply_with(&actions, &policy, &AllowAll { dangerous: false })
+        );
+        assert_eq!(HostsFile::parse(data).unwrap(), file);
+        assert!(
+            file.apply_with(&actions, &policy, &AllowAll { dangerous: true })
+                .unwrap()
+                .changed
+        );
+        let entries: Vec<_> = file
+            .parts()
+            .iter()
+            .filter(|part| !part.is_empty())
+            .collect();
+        assert_eq!(1, entries.len());
+        assert!(entries[0].matches_hostname("any.example"));
+    }
+
+    #[test]
+    fn test_apply_raw_untouched() {
+        let raw = HostsPart::Raw("10.0.0.1 somehost = odd".into());
+        assert!(!raw.matches_hostname("somehost"));
+        assert!(!raw.matches_ip(&IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))));
+        assert_eq!(None, raw.primary_hostname());
+        assert_eq!(None, raw.get_family());
+
+        let mut file = HostsFile::from(vec![
+            raw.clone(),
+            HostsPart::Entry(
+                IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+                vec!["somehost".into()],
+                None,
+            ),
+        ]);
+        let actions = [
+            Action::Remove("some*".into()),
+            Action::DefineExclusive(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)), "somehost".into()),
+            Action::SetComment("somehost".into(), Some("note".into())),
+        ];
+        let mut allowing = policy(&["some*", "somehost"]);
+        allowing.allow_conflicting_actions = true;
+        allowing.include_commented = true;
+        assert!(file.apply(&actions, &allowing).unwrap().changed);
+        assert_eq!(raw, file.parts()[0]);
+    }
+
+    #[test]
+    fn test_apply_invalid_hostname() {
+        let data = "10.0.0.1\tsomehost\n";
+        let mut file = HostsFile::parse(data).unwrap();
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+        for host in ["bad#host", "bad host", "bad\u{2003}host", ""] {
+            let err = file
+                .apply(&[Action::Define(ip, host.into())], &policy(&[host]))
+                .unwrap_err();
+            assert_eq!(ApplyError::InvalidHostname(host.into()), err);
+            assert!(err.to_string().contains("read back"));
+        }
+        let err = file
+            .apply(
+                &[Action::DefineExclusive(ip, "bad#host".into())],
+                &policy(&["bad#host"]),
+            )
+            .unwrap_err();
+        assert_eq!(
+            "HOST \"bad#host\" would not be read back correctly from the hosts file",
+            err.to_string()
+        );
+        assert_eq!(HostsFile::parse(data).unwrap(), file);
+    }
+
+    #[test]
+    fn test_apply_special_ip() {
+        let mut file = HostsFile::parse("127.0.0.1\tlocalhost\n").unwrap();
+        let multicast = IpAddr::V4(Ipv4Addr::new(224, 0, 0, 251));
+        let actions = [Action::Define(multicast, "somehost".into())];
+        let mut policy = policy(&["somehost"]);
+        policy.reject_special_ips = true;
+        assert_eq!(
+            Err(ApplyError::SpecialIp(multicast, "multicast")),
+            file.apply(&actions, &policy)
+        );
+        assert_eq!(HostsFile::parse("127.0.0.1\tlocalhost\n").unwrap(), file);
+
+        policy.reject_special_ips = false;
+        let report = file.apply(&actions, &policy).unwrap();
+        assert!(report.changed);
+        assert_eq!(1, report.warnings.len());
+        assert!(report.warnings[0].contains("multicast"));
+
+        let unspecified = IpAddr::V6(Ipv6Addr::UNSPECIFIED);
+        let report = file
+            .apply(&[Action::Define(unspecified, "somehost".into())], &policy)
+            .unwrap();
+        assert!(report.warnings[0].contains("unspecified"));
+    }
+
+    #[test]
+    fn test_glob_matches() {
+        assert!(glob_matches("*.dev.example", "api.dev.example"));
+        assert!(glob_matches("*.dev.example", "a.b.dev.example"));
+        assert!(!glob_matches("*.dev.example", "dev.example"));
+        assert!(glob_matches("web?.example", "web1.example"));
+        assert!(!glob_matches("web?.example", "web12.example"));
+        assert!(glob_matches("*", "anything"));
+        assert!(glob_matches("a*b*c", "aXbYbZc"));
+        assert!(!glob_matches("a*b*c", "aXbYbZ"));
+        assert!(glob_matches("*.DEV.example", "api.dev.example"));
+        assert!(glob_matches("*.dev.example", "API.Dev.Example"));
+        assert!(glob_matches("WEB?.example", "web1.EXAMPLE"));
+    }
+}
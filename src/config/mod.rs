@@ -1,75 +1,358 @@
+use crate::action::{HostPolicy, InsertPosition};
+use crate::file::BlankLines;
+use crate::parse::is_valid_hostname;
+use crate::persist::write_atomically;
+use crate::reserved::{HostsEntry, RESERVED_FQDN, RESERVED_HOSTNAME};
 use serde::{Deserialize, Serialize};
-use std::borrow::Cow;
 use std::collections::BTreeSet;
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
-
-pub const RESERVED_HOSTNAME: &str = "%HOSTNAME%";
-pub const RESERVED_LOCALHOST: &str = "localhost";
-pub const RESERVED_IP6_LOCALHOST: &str = "ip6-localhost";
-pub const RESERVED_IP6_LOOPBACK: &str = "ip6-loopback";
-pub const RESERVED_IP6_ALLNODES: &str = "ip6-allnodes";
-pub const RESERVED_IP6_ALLROUTERS: &str = "ip6-allrouters";
-
-const IP4_LOCAL: Ipv4Addr = Ipv4Addr::new(127, 0, 0, 1);
-const IP4_LOCAL_ALT: Ipv4Addr = Ipv4Addr::new(127, 0, 1, 1);
-const IP6_LOCAL: Ipv6Addr = Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1);
-const IP6_ALL_NODES: Ipv6Addr = Ipv6Addr::new(65282, 0, 0, 0, 0, 0, 0, 1);
-const IP6_ALL_ROUTERS: Ipv6Addr = Ipv6Addr::new(65282, 0, 0, 0, 0, 0, 0, 2);
-
-#[allow(dead_code)]
-pub const DONT_TOUCH: &[HostsEntry] = &[
-    HostsEntry {
-        ip: IpAddr::V4(IP4_LOCAL),
-        hostname: Cow::Borrowed(RESERVED_LOCALHOST),
-    },
-    HostsEntry {
-        ip: IpAddr::V4(IP4_LOCAL_ALT),
-        hostname: Cow::Borrowed(RESERVED_HOSTNAME),
-    },
-    HostsEntry {
-        ip: IpAddr::V6(IP6_LOCAL),
-        hostname: Cow::Borrowed(RESERVED_LOCALHOST),
-    },
-    HostsEntry {
-        ip: IpAddr::V6(IP6_LOCAL),
-        hostname: Cow::Borrowed(RESERVED_IP6_LOCALHOST),
-    },
-    HostsEntry {
-        ip: IpAddr::V6(IP6_LOCAL),
-        hostname: Cow::Borrowed(RESERVED_IP6_LOOPBACK),
-    },
-    HostsEntry {
-        ip: IpAddr::V6(IP6_ALL_NODES),
-        hostname: Cow::Borrowed(RESERVED_IP6_ALLNODES),
-    },
-    HostsEntry {
-        ip: IpAddr::V6(IP6_ALL_ROUTERS),
-        hostname: Cow::Borrowed(RESERVED_IP6_ALLROUTERS),
-    },
-];
-
-#[derive(Debug, Serialize, Deserialize)]
-pub struct HostsEntry<'a> {
-    pub ip: IpAddr,
-    pub hostname: Cow<'a, str>,
-}
+use std::fs::read_to_string;
+use std::path::Path;
 
-#[derive(Default, Serialize, Deserialize)]
+#[derive(Clone, Default, Serialize, Deserialize)]
 pub struct HostsmodConfig {
     pub whitelist: BTreeSet<String>,
+    #[serde(default)]
+    pub add_only: BTreeSet<String>,
     #[serde(skip_serializing)]
     #[serde(default = "safely_false")]
     pub enable_dangerous_operations: bool,
+    #[serde(default)]
+    pub blank_whitespace_lines: bool,
+    #[serde(default)]
+    pub insert_position: InsertPosition,
+    #[serde(default)]
+    pub merge_same_ip: bool,
+    #[serde(default)]
+    pub group_by_ip: bool,
+    #[serde(default)]
+    pub backup: bool,
+    #[serde(default)]
+    pub backup_keep: Option<usize>,
+    #[serde(default)]
+    pub ensure_present: Vec<HostsEntry<'static>>,
+    #[serde(default)]
+    pub ensure_absent: Vec<HostsEntry<'static>>,
+    #[serde(default)]
+    pub pre_change_hook: Option<String>,
+    #[serde(default)]
+    pub post_change_hook: Option<String>,
+    #[serde(default)]
+    pub sort_on_write: bool,
+    #[serde(default)]
+    pub attach_comments: bool,
+    #[serde(default)]
+    pub dedup_on_write: bool,
+    #[serde(default)]
+    pub preserve_comments: bool,
+    #[serde(default)]
+    pub max_entries: Option<usize>,
+    #[serde(default)]
+    pub temp_suffix: Option<String>,
+    #[serde(default)]
+    pub file_mode: Option<u32>,
+    #[serde(default)]
+    pub semicolon_comments: bool,
+    #[serde(default)]
+    pub blank_lines: BlankLines,
+    #[serde(default)]
+    pub reject_special_ips: bool,
+    #[serde(default)]
+    pub protected: Vec<HostsEntry<'static>>,
+    #[serde(default)]
+    pub allow_runtime_whitelist: bool,
 }
 
 impl std::fmt::Debug for HostsmodConfig {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         f.debug_struct("HostsmodConfig")
             .field("whitelist", &self.whitelist)
+            .field("add_only", &self.add_only)
+            .field("blank_whitespace_lines", &self.blank_whitespace_lines)
+            .field("insert_position", &self.insert_position)
+            .field("merge_same_ip", &self.merge_same_ip)
+            .field("group_by_ip", &self.group_by_ip)
+            .field("backup", &self.backup)
+            .field("backup_keep", &self.backup_keep)
+            .field("ensure_present", &self.ensure_present)
+            .field("ensure_absent", &self.ensure_absent)
+            .field("pre_change_hook", &self.pre_change_hook)
+            .field("post_change_hook", &self.post_change_hook)
+            .field("sort_on_write", &self.sort_on_write)
+            .field("attach_comments", &self.attach_comments)
+            .field("dedup_on_write", &self.dedup_on_write)
+            .field("preserve_comments", &self.preserve_comments)
+            .field("max_entries", &self.max_entries)
+            .field("temp_suffix", &self.temp_suffix)
+            .field(
+                "file_mode",
+                &self.file_mode.map(|mode| format!("{:#o}", mode)),
+            )
+            .field("semicolon_comments", &self.semicolon_comments)
+            .field("blank_lines", &self.blank_lines)
+            .field("reject_special_ips", &self.reject_special_ips)
+            .field("protected", &self.protected)
+            .field("allow_runtime_whitelist", &self.allow_runtime_whitelist)
             .finish()
     }
 }
 
+impl HostsmodConfig {
+    /// Returns a description of every problem found, eg. a whitelist entry which can never match
+    /// a valid hostname.
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems: Vec<String> = self
+            .whitelist
+            .iter()
+            .chain(self.add_only.iter())
+            // wildcards are allowed in patterns, see `Action::Remove`
+            .filter(|host| !is_valid_hostname(&host.replace(['*', '?'], "x")))
+            .map(|host| format!("whitelisted {:?} is not a valid hostname", host))
+            .collect();
+        if let Some(mode) = self.file_mode.filter(|mode| *mode > 0o7777) {
+            problems.push(format!(
+                "file_mode {:#o} is no valid mode, write it in octal like 0o644",
+                mode
+            ));
+        }
+        if let Some(suffix) = &self.temp_suffix {
+            if suffix.is_empty() || suffix.contains('/') {
+                problems.push(format!(
+                    "temp_suffix {:?} must be a non-empty file name suffix",
+                    suffix
+                ));
+            }
+        }
+        for entry in &self.protected {
+            let placeholder =
+                entry.hostname == RESERVED_HOSTNAME || entry.hostname == RESERVED_FQDN;
+            if placeholder && !entry.ip.is_loopback() {
+                problems.push(format!(
+                    "protected {} {} maps the machine's own name to a non-loopback address",
+                    entry.ip, entry.hostname
+                ));
+            }
+            if entry.ip.is_unspecified() {
+                problems.push(format!(
+                    "protected {} {} uses the unspecified address",
+                    entry.ip, entry.hostname
+                ));
+            }
+        }
+        problems
+    }
+
+    /// Verifies every protected entry names a valid hostname or one of the placeholders
+    /// `%HOSTNAME%` and `%FQDN%`. Unlike the problems found by `validate`, such an entry would
+    /// make the safety check protect something other than intended, so the config is rejected.
+    pub fn check_protected(&self) -> Result<(), String> {
+        match self.protected.iter().find(|entry| {
+            entry.hostname != RESERVED_HOSTNAME
+                && entry.hostname != RESERVED_FQDN
+                && !is_valid_hostname(&entry.hostname)
+        }) {
+            Some(entry) => Err(format!(
+                "protected entry {} {:?} has no valid hostname",
+                entry.ip, entry.hostname
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
 fn safely_false() -> bool {
     false
 }
+
+impl HostPolicy for HostsmodConfig {
+    fn allows(&self, host: &str) -> bool {
+        self.whitelist
+            .iter()
+            .chain(self.add_only.iter())
+            .any(|listed| listed.eq_ignore_ascii_case(host))
+    }
+
+    fn is_add_only(&self, host: &str) -> bool {
+        self.add_only
+            .iter()
+            .any(|listed| listed.eq_ignore_ascii_case(host))
+    }
+
+    fn is_dangerous_enabled(&self) -> bool {
+        self.enable_dangerous_operations
+    }
+}
+
+/// Adds `host` to or removes it from the `whitelist` of the config at `path`, using `path_new`
+/// for the atomic write. Returns whether the config was modified.
+pub fn update_whitelist(
+    path: &Path,
+    path_new: &Path,
+    host: &str,
+    add: bool,
+) -> Result<bool, String> {
+    let content =
+        read_to_string(path).map_err(|err| format!("unable to read {:?}: {}", path, err))?;
+    match edit_whitelist(&content, host, add)? {
+        Some(content_new) => {
+            write_atomically(path, path_new, &content_new, None)?;
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+/// Edits the `whitelist` in the YAML `content` line by line, so comments and formatting of
+/// everything else survive. Returns `None` if there is nothing to change.
+fn edit_whitelist(content: &str, host: &str, add: bool) -> Result<Option<String>, String> {
+    let cfg: HostsmodConfig = serde_yaml::from_str(content)
+        .map_err(|err| format!("unable to parse configuration: {}", err))?;
+    let mut expected = cfg.whitelist.clone();
+    if add {
+        if expected
+            .iter()
+            .any(|listed| listed.eq_ignore_ascii_case(host))
+        {
+            return Ok(None);
+        }
+        expected.insert(host.to_string());
+    } else {
+        let len_before = expected.len();
+        expected.retain(|listed| !listed.eq_ignore_ascii_case(host));
+        if expected.len() == len_before {
+            return Ok(None);
+        }
+    }
+
+    let mut lines: Vec<String> = content.lines().map(String::from).collect();
+    match lines.iter().position(|line| line.starts_with("whitelist:")) {
+        None => {
+            lines.push("whitelist:".into());
+            lines.push(format!("  - {}", host));
+        }
+        Some(idx_key) if !lines[idx_key]["whitelist:".len()..].trim().is_empty() => {
+            // flow style like `[a, b]`, rewrite as block
+            let mut block = vec!["whitelist:".to_string()];
+            block.extend(expected.iter().map(|listed| format!("  - {}", listed)));
+            if expected.is_empty() {
+                block = vec!["whitelist: []".to_string()];
+            }
+            lines.splice(idx_key..=idx_key, block);
+        }
+        Some(idx_key) => {
+            let items: Vec<usize> = lines
+                .iter()
+                .enumerate()
+                .skip(idx_key + 1)
+                .take_while(|(_, line)| {
+                    line.is_empty()
+                        || line.starts_with(char::is_whitespace)
+                        || line.starts_with('-')
+                })
+                .filter(|(_, line)| line.trim_start().starts_with('-'))
+                .map(|(idx, _)| idx)
+                .collect();
+            if add {
+                let (idx_insert, indent) = match items.last() {
+                    Some(idx) => (
+                        idx + 1,
+                        lines[*idx][..lines[*idx].find('-').unwrap()].to_string(),
+                    ),
+                    None => (idx_key + 1, "  ".to_string()),
+                };
+                lines.insert(idx_insert, format!("{}- {}", indent, host));
+            } else {
+                for idx in items.into_iter().rev() {
+                    let value = lines[idx].trim_start()[1..]
+                        .trim()
+                        .trim_matches(|c| c == '"' || c == '\'');
+                    if value.eq_ignore_ascii_case(host) {
+                        lines.remove(idx);
+                    }
+                }
+            }
+        }
+    }
+    let mut content_new = lines.join("\n");
+    content_new.push('\n');
+
+    let cfg_new: HostsmodConfig = serde_yaml::from_str(&content_new)
+        .map_err(|err| format!("unable to edit whitelist, edit config manually: {}", err))?;
+    if cfg_new.whitelist != expected {
+        return Err("unable to edit whitelist, edit config manually".into());
+    }
+    Ok(Some(content_new))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{update_whitelist, HostsmodConfig};
+    use crate::persist::tests::temp_dir;
+    use std::fs;
+
+    #[test]
+    fn test_update_whitelist() {
+        let dir = temp_dir("update_whitelist");
+        let path = dir.join("hostsmod.yaml");
+        let path_new = dir.join("hostsmod.yaml.new");
+        let original = "# managed by ops\nwhitelist:\n  - a.example # first\nbackup: true\n";
+        fs::write(&path, original).unwrap();
+
+        assert!(update_whitelist(&path, &path_new, "b.example", true).unwrap());
+        assert_eq!(
+            "# managed by ops\nwhitelist:\n  - a.example # first\n  - b.example\nbackup: true\n",
+            fs::read_to_string(&path).unwrap()
+        );
+        assert!(!update_whitelist(&path, &path_new, "B.example", true).unwrap());
+
+        assert!(update_whitelist(&path, &path_new, "b.example", false).unwrap());
+        assert_eq!(original, fs::read_to_string(&path).unwrap());
+        assert!(!update_whitelist(&path, &path_new, "c.example", false).unwrap());
+
+        fs::write(&path, "whitelist: [a.example]\n").unwrap();
+        assert!(update_whitelist(&path, &path_new, "a.example", false).unwrap());
+        assert_eq!("whitelist: []\n", fs::read_to_string(&path).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_validate() {
+        let cfg: HostsmodConfig = serde_yaml::from_str(
+            "whitelist:\n  - good.example\n  - \"*.dev.example\"\n  - bad host\n  - a/b\ntemp_suffix: /tmp\n",
+        )
+        .unwrap();
+        assert_eq!(
+            vec![
+                "whitelisted \"a/b\" is not a valid hostname".to_string(),
+                "whitelisted \"bad host\" is not a valid hostname".to_string(),
+                "temp_suffix \"/tmp\" must be a non-empty file name suffix".to_string(),
+            ],
+            cfg.validate()
+        );
+    }
+
+    #[test]
+    fn test_validate_protected() {
+        let cfg: HostsmodConfig = serde_yaml::from_str(
+            "whitelist: []\nprotected:\n  - ip: 10.0.0.1\n    hostname: \"%HOSTNAME%\"\n  - ip: 0.0.0.0\n    hostname: blocked.example\n  - ip: 127.0.1.1\n    hostname: \"%FQDN%\"\n",
+        )
+        .unwrap();
+        assert_eq!(Ok(()), cfg.check_protected());
+        assert_eq!(
+            vec![
+                "protected 10.0.0.1 %HOSTNAME% maps the machine's own name to a non-loopback address"
+                    .to_string(),
+                "protected 0.0.0.0 blocked.example uses the unspecified address".to_string(),
+            ],
+            cfg.validate()
+        );
+
+        let cfg: HostsmodConfig = serde_yaml::from_str(
+            "whitelist: []\nprotected:\n  - ip: 10.0.0.1\n    hostname: \"\"\n",
+        )
+        .unwrap();
+        assert_eq!(
+            Err("protected entry 10.0.0.1 \"\" has no valid hostname".to_string()),
+            cfg.check_protected()
+        );
+    }
+}
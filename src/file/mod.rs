@@ -0,0 +1,1203 @@
+use crate::action::special_ip_kind;
+use crate::parse::{
+    ip_family, is_valid_hostname, parse_hosts_reader, try_parse_hosts, CommentMarker,
+    HostsParseError, HostsPart, HostsPartFamily,
+};
+use crate::reserved::DONT_TOUCH;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt::Write;
+use std::io::{BufReader, Read};
+use std::net::IpAddr;
+use std::ops::Range;
+
+/// Comment opening a block of entries managed by this tool, written as `# hostsmod:begin`.
+pub const MANAGED_BEGIN: &str = "hostsmod:begin";
+/// Comment closing a block of entries managed by this tool, written as `# hostsmod:end`.
+pub const MANAGED_END: &str = "hostsmod:end";
+/// Trailing comment pinning an entry, which actions then leave alone, written as
+/// `# hostsmod:ignore`.
+pub const IGNORE_MARKER: &str = "hostsmod:ignore";
+/// Prefix of all annotation comments of this tool, including `MANAGED_BEGIN`, `MANAGED_END` and
+/// `IGNORE_MARKER`.
+const ANNOTATION_PREFIX: &str = "hostsmod:";
+
+/// Policy for runs of consecutive empty lines, see `HostsFile::normalize_blank_lines`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BlankLines {
+    /// Reduce every run of empty lines to a single one.
+    #[default]
+    Collapse,
+    /// Keep the original spacing.
+    Preserve,
+    /// Allow at most the given number of consecutive empty lines.
+    Max(usize),
+}
+
+/// A single difference between two hosts files, see `HostsFile::diff`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HostsChange<'a> {
+    /// The part is only present in the new file.
+    Added(HostsPart<'a>),
+    /// The part is only present in the old file.
+    Removed(HostsPart<'a>),
+    /// An entry with the same IP address and primary hostname differs otherwise, eg. in its
+    /// aliases, its comment or by being commented out.
+    Modified {
+        #[allow(missing_docs)]
+        before: HostsPart<'a>,
+        #[allow(missing_docs)]
+        after: HostsPart<'a>,
+    },
+}
+
+impl HostsChange<'_> {
+    /// Converts a change into one owning all of its data, see `HostsPart::into_owned`.
+    pub fn into_owned(self) -> HostsChange<'static> {
+        match self {
+            HostsChange::Added(part) => HostsChange::Added(part.into_owned()),
+            HostsChange::Removed(part) => HostsChange::Removed(part.into_owned()),
+            HostsChange::Modified { before, after } => HostsChange::Modified {
+                before: before.into_owned(),
+                after: after.into_owned(),
+            },
+        }
+    }
+}
+
+/// A structural problem of a hosts file, see `HostsFile::validate`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidationIssue {
+    /// Line of the offending entry, counting from 1.
+    pub line: usize,
+    #[allow(missing_docs)]
+    pub kind: IssueKind,
+}
+
+/// What is wrong about an entry, see `ValidationIssue`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IssueKind {
+    /// The hostname is not valid, eg. it contains an empty label.
+    InvalidHostname(String),
+    /// The address is multicast, unspecified, broadcast or reserved for documentation, as
+    /// described by the second field.
+    SpecialAddress(IpAddr, &'static str),
+    /// The mapping of the address to the hostname was already made on an earlier line.
+    DuplicateMapping(IpAddr, String),
+    /// The hostname was already mapped to another address of the same family on the given
+    /// earlier line, so resolvers will likely ignore this mapping.
+    ConflictingMapping(String, usize),
+    /// The entry shares a hostname with the entry of the other address family on the given line,
+    /// but not all of its aliases.
+    AliasMismatch(usize),
+}
+
+impl std::fmt::Display for ValidationIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: ", self.line)?;
+        match &self.kind {
+            IssueKind::InvalidHostname(host) => write!(f, "{:?} is not a valid hostname", host),
+            IssueKind::SpecialAddress(ip, kind) => write!(f, "{} is a {} address", ip, kind),
+            IssueKind::DuplicateMapping(ip, host) => {
+                write!(f, "{} is already mapped to {:?}", ip, host)
+            }
+            IssueKind::ConflictingMapping(host, line) => write!(
+                f,
+                "{:?} is already mapped to another address on line {}",
+                host, line
+            ),
+            IssueKind::AliasMismatch(line) => {
+                write!(f, "aliases differ from the entry on line {}", line)
+            }
+        }
+    }
+}
+
+/// A complete hosts file, holding all of its parts in the order they appear in the file. Files
+/// are equal if their parts are.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct HostsFile<'a> {
+    parts: Vec<HostsPart<'a>>,
+    #[serde(skip)]
+    trailing_newline: bool,
+}
+
+impl<'a> PartialEq for HostsFile<'a> {
+    fn eq(&self, other: &Self) -> bool {
+        self.parts == other.parts
+    }
+}
+
+impl<'a> Eq for HostsFile<'a> {}
+
+/// Reads a complete hosts file from `read` and parses it into a file owning all of its data, see
+/// `parse_hosts_reader`.
+pub fn try_parse_hosts_reader<R: Read>(read: R) -> Result<HostsFile<'static>, HostsParseError> {
+    let mut read = TailReader { read, tail: [0; 2] };
+    let parts = parse_hosts_reader(BufReader::new(&mut read)).collect::<Result<Vec<_>, _>>()?;
+    Ok(HostsFile {
+        parts,
+        trailing_newline: ends_with_line_break(&read.tail),
+    })
+}
+
+/// Passes through everything read, remembering the last two bytes.
+struct TailReader<R> {
+    read: R,
+    tail: [u8; 2],
+}
+
+impl<R: Read> Read for TailReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = self.read.read(buf)?;
+        match len {
+            0 => {}
+            1 => self.tail = [self.tail[1], buf[0]],
+            _ => self.tail = [buf[len - 2], buf[len - 1]],
+        }
+        Ok(len)
+    }
+}
+
+/// Checks whether `content` ends with any line break the parser accepts.
+fn ends_with_line_break(content: &[u8]) -> bool {
+    content.ends_with(b"\n") || content.ends_with(b"\n\r")
+}
+
+impl<'a> From<Vec<HostsPart<'a>>> for HostsFile<'a> {
+    fn from(parts: Vec<HostsPart<'a>>) -> Self {
+        HostsFile {
+            parts,
+            trailing_newline: false,
+        }
+    }
+}
+
+impl<'a> HostsFile<'a> {
+    /// Parses a complete hosts file, see `try_parse_hosts`.
+    pub fn parse(read: &'a str) -> Result<Self, String> {
+        Ok(HostsFile {
+            parts: try_parse_hosts(read)?,
+            trailing_newline: ends_with_line_break(read.as_bytes()),
+        })
+    }
+
+    /// Returns all parts of the hosts file.
+    pub fn parts(&self) -> &[HostsPart<'a>] {
+        &self.parts
+    }
+
+    /// Returns the wrapped `Vec` of parts for arbitrary modifications.
+    pub fn parts_mut(&mut self) -> &mut Vec<HostsPart<'a>> {
+        &mut self.parts
+    }
+
+    /// Unwraps the parts of the hosts file.
+    pub fn into_parts(self) -> Vec<HostsPart<'a>> {
+        self.parts
+    }
+
+    /// Deep-clones all parts, so the copy no longer borrows from the parsed text. The original is
+    /// left untouched, see `HostsPart::into_owned` for a consuming conversion of a single part.
+    pub fn to_owned_static(&self) -> HostsFile<'static> {
+        HostsFile {
+            parts: self
+                .parts
+                .iter()
+                .cloned()
+                .map(HostsPart::into_owned)
+                .collect(),
+            trailing_newline: self.trailing_newline,
+        }
+    }
+
+    /// Serializes the structured representation of the hosts file to JSON. Parts are tagged with
+    /// their variant, IP addresses are written as strings.
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string(self)
+    }
+
+    /// Deserializes a hosts file from the JSON produced by `to_json`.
+    pub fn from_json(json: &str) -> serde_json::Result<HostsFile<'static>> {
+        serde_json::from_str(json)
+    }
+
+    /// Retains only the parts for which `f` returns `true`, eg. to drop all comments or all
+    /// commented-out entries in one call. Empty parts are filtered like any other, no cleanup of
+    /// trailing or consecutive empty lines is done, so they may be left behind by removing the
+    /// parts in between.
+    pub fn retain<F: FnMut(&HostsPart) -> bool>(&mut self, f: F) {
+        self.parts.retain(f);
+    }
+
+    /// Checks whether the file contains markers or annotation comments of this tool, eg.
+    /// `# hostsmod:begin`. Comments are recognized with leading whitespace, trailing comments of
+    /// entries are considered.
+    pub fn is_managed(&self) -> bool {
+        self.parts.iter().any(|part| {
+            let comment = match part {
+                HostsPart::Comment(comment, ..) => comment,
+                HostsPart::Entry(_, _, Some(comment))
+                | HostsPart::CommentedEntry(_, _, Some(comment), ..) => comment,
+                _ => return false,
+            };
+            comment.trim_start().starts_with(ANNOTATION_PREFIX)
+        })
+    }
+
+    /// Checks whether the parsed file ended with a line break, as recorded while parsing. Always
+    /// `false` for files not parsed from text, eg. built from parts or deserialized from JSON.
+    pub fn had_trailing_newline(&self) -> bool {
+        self.trailing_newline
+    }
+
+    /// Removes all empty lines at the end of the file, including the empty part following a
+    /// trailing line break.
+    pub fn trim_trailing_empty(&mut self) {
+        let trim = self
+            .parts
+            .iter()
+            .rev()
+            .take_while(|part| part.is_empty())
+            .count();
+        self.parts.truncate(self.parts.len() - trim);
+    }
+
+    /// Reduces every run of consecutive empty lines to a single one, same as
+    /// `normalize_blank_lines(BlankLines::Collapse)`.
+    pub fn collapse_blank_runs(&mut self) {
+        self.normalize_blank_lines(BlankLines::Collapse);
+    }
+
+    /// Lists the changes turning `self` into `other`. Entries (commented out or not) are matched
+    /// by IP address and primary hostname, all other parts by equality, so moving parts around is
+    /// not reported as a change. Removals are listed first in the order of `self`, followed by
+    /// additions and modifications in the order of `other`.
+    pub fn diff(&self, other: &HostsFile<'a>) -> Vec<HostsChange<'a>> {
+        diff_parts(self.parts.iter(), other.parts.iter())
+    }
+
+    /// Shortens runs of consecutive empty lines according to `policy`. Whitespace-only lines count
+    /// as empty, the first lines of a run are kept.
+    pub fn normalize_blank_lines(&mut self, policy: BlankLines) {
+        let max = match policy {
+            BlankLines::Collapse => 1,
+            BlankLines::Preserve => return,
+            BlankLines::Max(max) => max,
+        };
+        let mut run = 0;
+        self.parts.retain(|part| {
+            if part.is_empty() {
+                run += 1;
+                run <= max
+            } else {
+                run = 0;
+                true
+            }
+        });
+    }
+
+    /// Renders all entries as `A`/`AAAA` records of a DNS zone file, suitable for pasting into a
+    /// BIND zone for `origin`. Hostnames within `origin` are written relative to it, all others as
+    /// absolute names. Commented-out entries and comments are kept as zone comments, empty and raw
+    /// lines are skipped.
+    pub fn to_zone_fragment(&self, origin: &str) -> String {
+        let origin = origin.trim_end_matches('.');
+        let mut buf = String::new();
+        for part in &self.parts {
+            let (ip, hosts, opt_comment, prefix) = match part {
+                HostsPart::Entry(ip, hosts, opt_comment) => (ip, hosts, opt_comment, ""),
+                HostsPart::CommentedEntry(ip, hosts, opt_comment, ..) => {
+                    (ip, hosts, opt_comment, "; ")
+                }
+                HostsPart::Comment(comment, ..) => {
+                    writeln!(buf, ";{}", comment).expect("unable to format zone comment");
+                    continue;
+                }
+                HostsPart::Empty(..) | HostsPart::Raw(..) => continue,
+            };
+            let rtype = match part.get_family() {
+                Some(HostsPartFamily::IPv4) => "A",
+                Some(HostsPartFamily::IPv6) => "AAAA",
+                None => continue,
+            };
+            for host in hosts {
+                write!(
+                    buf,
+                    "{}{}\tIN\t{}\t{}",
+                    prefix,
+                    zone_name(host, origin),
+                    rtype,
+                    ip
+                )
+                .expect("unable to format zone record");
+                if let Some(comment) = opt_comment {
+                    buf.push_str(" ;");
+                    buf.push_str(comment);
+                }
+                buf.push('\n');
+            }
+        }
+        buf
+    }
+
+    /// Adds an entry for each `A` and `AAAA` record of a DNS zone file fragment, the inverse of
+    /// `to_zone_fragment`. Records are expected as `name [ttl] [IN] A|AAAA ip`, `$ORIGIN`
+    /// directives are honoured for relative names. Mappings already present are not added again.
+    ///
+    /// Returns a warning for every line that was ignored, eg. because of an unsupported record
+    /// type or a name which is no valid hostname, like a wildcard.
+    pub fn add_from_zone(&mut self, text: &str) -> Vec<String> {
+        let mut warnings = vec![];
+        let mut origin = String::new();
+        for (idx, line) in text.lines().enumerate() {
+            let line = line.split(';').next().unwrap_or_default();
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+            match tokens.as_slice() {
+                [] => continue,
+                ["$ORIGIN", name] => {
+                    origin = name.trim_end_matches('.').to_string();
+                    continue;
+                }
+                _ => {}
+            }
+            let name = tokens[0];
+            let rest = tokens[1..]
+                .iter()
+                .skip_while(|token| token.chars().all(|c| c.is_ascii_digit()))
+                .skip_while(|token| token.eq_ignore_ascii_case("IN"))
+                .collect::<Vec<_>>();
+            let (rtype, rdata) = match rest.as_slice() {
+                [rtype, rdata] => (rtype.to_ascii_uppercase(), rdata),
+                _ => {
+                    warnings.push(format!("line {}: ignoring unsupported record", idx + 1));
+                    continue;
+                }
+            };
+            let ip = match (rtype.as_str(), rdata.parse::<IpAddr>()) {
+                ("A", Ok(ip @ IpAddr::V4(_))) | ("AAAA", Ok(ip @ IpAddr::V6(_))) => ip,
+                ("A", _) | ("AAAA", _) => {
+                    warnings.push(format!("line {}: invalid address {:?}", idx + 1, rdata));
+                    continue;
+                }
+                (rtype, _) => {
+                    warnings.push(format!(
+                        "line {}: ignoring unsupported record type {}",
+                        idx + 1,
+                        rtype
+                    ));
+                    continue;
+                }
+            };
+            let host = if name == "@" {
+                origin.clone()
+            } else if name.ends_with('.') || origin.is_empty() {
+                name.trim_end_matches('.').to_string()
+            } else {
+                format!("{}.{}", name, origin)
+            };
+            if !is_valid_hostname(&host) {
+                warnings.push(format!(
+                    "line {}: ignoring invalid name {:?}",
+                    idx + 1,
+                    host
+                ));
+                continue;
+            }
+            if !self
+                .parts
+                .iter()
+                .any(|part| part.matches_ip(&ip) && part.matches_hostname(&host))
+            {
+                let insert = content_end(&self.parts);
+                self.parts
+                    .insert(insert, HostsPart::Entry(ip, vec![Cow::Owned(host)], None));
+            }
+        }
+        warnings
+    }
+
+    /// Returns pairs of an IPv4 and an IPv6 entry sharing a hostname, but not all of their
+    /// aliases, eg. after an alias was only added to one of them. Aliases are compared ignoring
+    /// order and ASCII case. Commented-out entries are not considered.
+    pub fn family_alias_mismatches(&self) -> Vec<(&HostsPart<'a>, &HostsPart<'a>)> {
+        self.family_alias_mismatch_indices()
+            .into_iter()
+            .map(|(v4, v6)| (&self.parts[v4], &self.parts[v6]))
+            .collect()
+    }
+
+    /// Like `family_alias_mismatches`, returning the indices of both entries.
+    fn family_alias_mismatch_indices(&self) -> Vec<(usize, usize)> {
+        let mut aliases = vec![BTreeSet::new(); self.parts.len()];
+        // indices of the IPv4 and IPv6 entries of each hostname
+        let mut groups: HashMap<String, (Vec<usize>, Vec<usize>)> = HashMap::new();
+        for (idx, part) in self.parts.iter().enumerate() {
+            let (ip, hosts) = match part {
+                HostsPart::Entry(ip, hosts, _) => (ip, hosts),
+                _ => continue,
+            };
+            let hosts: BTreeSet<String> =
+                hosts.iter().map(|host| host.to_ascii_lowercase()).collect();
+            for host in &hosts {
+                let (v4, v6) = groups.entry(host.clone()).or_default();
+                match ip_family(ip) {
+                    HostsPartFamily::IPv4 => v4.push(idx),
+                    HostsPartFamily::IPv6 => v6.push(idx),
+                }
+            }
+            aliases[idx] = hosts;
+        }
+        // sharing a hostname, the aliases are not disjoint
+        let mut mismatches = BTreeSet::new();
+        for (v4, v6) in groups.values() {
+            for &idx_v4 in v4 {
+                for &idx_v6 in v6 {
+                    if aliases[idx_v4] != aliases[idx_v6] {
+                        mismatches.insert((idx_v4, idx_v6));
+                    }
+                }
+            }
+        }
+        mismatches.into_iter().collect()
+    }
+
+    /// Sorts all parts by `HostsPart::cmp_canonical`. With `attach_comments`, comments directly
+    /// above an entry (commented out or not) are considered part of it and move along, staying
+    /// right above it.
+    pub fn sort_canonical(&mut self, attach_comments: bool) {
+        if !attach_comments {
+            self.parts.sort_by(HostsPart::cmp_canonical);
+            return;
+        }
+        // every group ends in the part it is sorted by, preceded by any attached comments
+        let mut groups: Vec<Range<usize>> = vec![];
+        let mut start = 0;
+        for (idx, part) in self.parts.iter().enumerate() {
+            match part {
+                HostsPart::Comment(..) => continue,
+                HostsPart::Entry(..) | HostsPart::CommentedEntry(..) => {
+                    let begin = attached_comments_start(&self.parts, idx);
+                    groups.extend((start..begin).map(|i| i..i + 1));
+                    groups.push(begin..idx + 1);
+                }
+                _ => groups.extend((start..idx + 1).map(|i| i..i + 1)),
+            }
+            start = idx + 1;
+        }
+        groups.extend((start..self.parts.len()).map(|i| i..i + 1));
+        groups.sort_by(|a, b| self.parts[a.end - 1].cmp_canonical(&self.parts[b.end - 1]));
+        let mut parts: Vec<_> = self.parts.drain(..).map(Some).collect();
+        self.parts = groups
+            .into_iter()
+            .flatten()
+            .filter_map(|idx| parts[idx].take())
+            .collect();
+    }
+
+    /// Removes every IP-hostname mapping of an active entry already present in an earlier active
+    /// entry, dropping entries left without a hostname. Returns whether anything was removed.
+    pub fn dedup_aliases(&mut self) -> bool {
+        let mut seen = HashSet::new();
+        let mut changed = false;
+        for part in self.parts.iter_mut() {
+            if let HostsPart::Entry(ip, aliases, _) = part {
+                let len = aliases.len();
+                aliases.retain(|host| seen.insert((*ip, host.to_string())));
+                changed |= aliases.len() != len;
+            }
+        }
+        self.parts
+            .retain(|part| !matches!(part, HostsPart::Entry(_, hosts, _) if hosts.is_empty()));
+        changed
+    }
+
+    /// Removes commented-out entries with the same IP address and hostnames as an active entry,
+    /// eg. `# 10.0.0.1 host` left over next to `10.0.0.1 host`. Trailing comments are not
+    /// compared. Returns whether anything was removed.
+    pub fn coalesce_commented_duplicates(&mut self) -> bool {
+        let active: HashSet<(IpAddr, Vec<Cow<str>>)> = self
+            .parts
+            .iter()
+            .filter_map(|part| match part {
+                HostsPart::Entry(ip, hosts, _) => Some((*ip, hosts.clone())),
+                _ => None,
+            })
+            .collect();
+        let len = self.parts.len();
+        self.parts.retain(|part| match part {
+            HostsPart::CommentedEntry(ip, hosts, ..) => !active.contains(&(*ip, hosts.clone())),
+            _ => true,
+        });
+        self.parts.len() != len
+    }
+
+    /// Tidies up the file in one go, the opposite of preserving its layout: comments lose their
+    /// indentation and trailing whitespace and use `#`, whitespace-only lines (and comments) become
+    /// blank, aliases are deduplicated with `dedup_aliases`, all parts sorted with
+    /// `sort_canonical` keeping comments attached, runs of blank lines collapsed and trailing ones
+    /// removed. Raw lines are left as they are.
+    ///
+    /// Rendering the result from scratch additionally yields canonical IP addresses, a single tab
+    /// after the IP address and `\n` line breaks.
+    pub fn normalize(&mut self) {
+        fn trimmed<'a>(text: &Cow<'a, str>) -> Option<Cow<'a, str>> {
+            let text = text.trim_end();
+            if text.is_empty() {
+                None
+            } else {
+                Some(Cow::Owned(text.to_string()))
+            }
+        }
+        for part in self.parts.iter_mut() {
+            match part {
+                HostsPart::Entry(_, _, comment) => *comment = comment.as_ref().and_then(trimmed),
+                HostsPart::CommentedEntry(_, _, comment, marker, indent) => {
+                    *comment = comment.as_ref().and_then(trimmed);
+                    *marker = CommentMarker::Hash;
+                    *indent = Cow::Borrowed("");
+                }
+                HostsPart::Comment(text, ..) => {
+                    *part = match trimmed(text) {
+                        Some(text) => HostsPart::Comment(text, CommentMarker::Hash, "".into()),
+                        None => HostsPart::Empty(Cow::Borrowed("")),
+                    }
+                }
+                HostsPart::Empty(_) => *part = HostsPart::Empty(Cow::Borrowed("")),
+                HostsPart::Raw(_) => {}
+            }
+        }
+        self.dedup_aliases();
+        self.sort_canonical(true);
+        self.collapse_blank_runs();
+        self.trim_trailing_empty();
+    }
+
+    /// Checks all active entries for structural problems: invalid hostnames, special addresses,
+    /// repeated mappings, hostnames mapped to several addresses of one family and dual-stack
+    /// entries with differing aliases. Entries of `DONT_TOUCH` are exempt from the latter, they
+    /// differ in a typical hosts file. Issues are ordered by line, counting the parts of the file
+    /// like the lines they were parsed from.
+    pub fn validate(&self) -> Vec<ValidationIssue> {
+        let reserved = |ip: &IpAddr, host: &str| {
+            DONT_TOUCH
+                .iter()
+                .any(|entry| entry.ip == *ip && entry.hostname == host)
+        };
+        let all_reserved = |part: &HostsPart| match part {
+            HostsPart::Entry(ip, hosts, _) => hosts.iter().all(|host| reserved(ip, host)),
+            _ => false,
+        };
+        let mut issues = vec![];
+        // line of the first mapping of each hostname per family, and of each exact mapping
+        let mut first_host: HashMap<(String, HostsPartFamily), (usize, IpAddr)> = HashMap::new();
+        let mut first_mapping: HashSet<(IpAddr, String)> = HashSet::new();
+        for (idx, part) in self.parts.iter().enumerate() {
+            let line = idx + 1;
+            let (ip, hosts) = match part {
+                HostsPart::Entry(ip, hosts, _) => (ip, hosts),
+                _ => continue,
+            };
+            let any_reserved = hosts.iter().any(|host| reserved(ip, host));
+            if let (Some(kind), false) = (special_ip_kind(ip), any_reserved) {
+                issues.push(ValidationIssue {
+                    line,
+                    kind: IssueKind::SpecialAddress(*ip, kind),
+                });
+            }
+            for host in hosts {
+                let kind = if !is_valid_hostname(host) {
+                    IssueKind::InvalidHostname(host.to_string())
+                } else if !first_mapping.insert((*ip, host.to_ascii_lowercase())) {
+                    IssueKind::DuplicateMapping(*ip, host.to_string())
+                } else {
+                    let key = (host.to_ascii_lowercase(), ip_family(ip));
+                    match first_host.get(&key) {
+                        Some((line_first, ip_first)) if ip_first != ip => {
+                            IssueKind::ConflictingMapping(host.to_string(), *line_first)
+                        }
+                        Some(_) => continue,
+                        None => {
+                            first_host.insert(key, (line, *ip));
+                            continue;
+                        }
+                    }
+                };
+                issues.push(ValidationIssue { line, kind });
+            }
+        }
+        for (idx_v4, idx_v6) in self.family_alias_mismatch_indices() {
+            if all_reserved(&self.parts[idx_v4]) || all_reserved(&self.parts[idx_v6]) {
+                continue;
+            }
+            let (line_v4, line_v6) = (idx_v4 + 1, idx_v6 + 1);
+            issues.push(ValidationIssue {
+                line: line_v4.max(line_v6),
+                kind: IssueKind::AliasMismatch(line_v4.min(line_v6)),
+            });
+        }
+        issues.sort_by_key(|issue| issue.line);
+        issues
+    }
+
+    /// Returns every distinct hostname of all entries, commented-out ones only if
+    /// `include_commented` is set.
+    pub fn all_hostnames(&self, include_commented: bool) -> BTreeSet<&str> {
+        self.entries(include_commented)
+            .flat_map(|(_, hosts)| hosts.iter().map(|host| host.as_ref()))
+            .collect()
+    }
+
+    /// Returns every distinct IP address of all entries, commented-out ones only if
+    /// `include_commented` is set.
+    pub fn all_ips(&self, include_commented: bool) -> BTreeSet<IpAddr> {
+        self.entries(include_commented).map(|(ip, _)| *ip).collect()
+    }
+
+    fn entries(
+        &self,
+        include_commented: bool,
+    ) -> impl Iterator<Item = (&IpAddr, &Vec<Cow<'a, str>>)> {
+        self.parts.iter().filter_map(move |part| match part {
+            HostsPart::Entry(ip, hosts, _) => Some((ip, hosts)),
+            HostsPart::CommentedEntry(ip, hosts, ..) if include_commented => Some((ip, hosts)),
+            _ => None,
+        })
+    }
+}
+
+/// Lists the changes turning the parts `old` into the parts `new`, see `HostsFile::diff`.
+pub(crate) fn diff_parts<'p, 'a: 'p>(
+    old: impl Iterator<Item = &'p HostsPart<'a>> + Clone,
+    new: impl Iterator<Item = &'p HostsPart<'a>>,
+) -> Vec<HostsChange<'a>> {
+    fn key<'p>(part: &'p HostsPart) -> Option<(&'p IpAddr, Option<&'p str>)> {
+        match part {
+            HostsPart::Entry(ip, ..) | HostsPart::CommentedEntry(ip, ..) => {
+                Some((ip, part.primary_hostname()))
+            }
+            _ => None,
+        }
+    }
+
+    let mut entries_old: HashMap<_, Vec<&HostsPart>> = HashMap::new();
+    let mut others_old: HashMap<&HostsPart, usize> = HashMap::new();
+    for part in old.clone() {
+        match key(part) {
+            Some(key) => entries_old.entry(key).or_default().push(part),
+            None => *others_old.entry(part).or_default() += 1,
+        }
+    }
+
+    let mut changes = vec![];
+    let mut entries_new: HashMap<_, usize> = HashMap::new();
+    let mut others_new: HashMap<&HostsPart, usize> = HashMap::new();
+    for part in new {
+        match key(part) {
+            Some(key) => {
+                let seen = entries_new.entry(key).or_default();
+                match entries_old.get(&key).and_then(|old| old.get(*seen)) {
+                    Some(before) if *before == part => {}
+                    Some(before) => changes.push(HostsChange::Modified {
+                        before: (*before).clone(),
+                        after: part.clone(),
+                    }),
+                    None => changes.push(HostsChange::Added(part.clone())),
+                }
+                *seen += 1;
+            }
+            None => {
+                let seen = others_new.entry(part).or_default();
+                if *seen >= others_old.get(part).copied().unwrap_or_default() {
+                    changes.push(HostsChange::Added(part.clone()));
+                }
+                *seen += 1;
+            }
+        }
+    }
+
+    let mut removed = vec![];
+    let mut seen_old: HashMap<_, usize> = HashMap::new();
+    let mut others_seen_old: HashMap<&HostsPart, usize> = HashMap::new();
+    for part in old {
+        let (seen, count_new) = match key(part) {
+            Some(key) => (
+                seen_old.entry(key).or_default(),
+                entries_new.get(&key).copied().unwrap_or_default(),
+            ),
+            None => (
+                others_seen_old.entry(part).or_default(),
+                others_new.get(part).copied().unwrap_or_default(),
+            ),
+        };
+        if *seen >= count_new {
+            removed.push(HostsChange::Removed(part.clone()));
+        }
+        *seen += 1;
+    }
+    removed.append(&mut changes);
+    removed
+}
+
+/// Returns the index past the last part which is not empty, where parts are added at the end of
+/// the file. Trailing empty lines, eg. the one following the final line break, stay at the end.
+pub(crate) fn content_end(parts: &[HostsPart]) -> usize {
+    parts.len()
+        - parts
+            .iter()
+            .rev()
+            .take_while(|part| part.is_empty())
+            .count()
+}
+
+/// Returns the index of the first comment directly above the part at `idx`, without an empty
+/// line in between. These comments are considered attached to an entry at `idx` and move along
+/// with it. Returns `idx` if there are none.
+pub(crate) fn attached_comments_start(parts: &[HostsPart], idx: usize) -> usize {
+    parts[..idx]
+        .iter()
+        .rposition(|part| !matches!(part, HostsPart::Comment(..)))
+        .map_or(0, |before| before + 1)
+}
+
+fn zone_name(host: &str, origin: &str) -> String {
+    if origin.is_empty() {
+        return format!("{}.", host);
+    }
+    if host.eq_ignore_ascii_case(origin) {
+        return "@".to_string();
+    }
+    if host.len() > origin.len() + 1 {
+        let idx = host.len() - origin.len() - 1;
+        if host.is_char_boundary(idx)
+            && host[idx..].starts_with('.')
+            && host[idx + 1..].eq_ignore_ascii_case(origin)
+        {
+            return host[..idx].to_string();
+        }
+    }
+    format!("{}.", host)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::file::{
+        try_parse_hosts_reader, BlankLines, HostsChange, HostsFile, IssueKind, ValidationIssue,
+    };
+    use crate::parse::{try_parse_hosts_with, HostsPart, ParseOptions};
+    use std::fmt::Write;
+    use std::io::Cursor;
+    use std::net::IpAddr;
+
+    #[test]
+    fn test_sort_attached_comments() {
+        let data = "# header\n\n# web server\n# second line\n10.0.0.9\tweb.example\n# database\n10.0.0.1\tdb.example\n# trailing\n";
+        let mut file = HostsFile::parse(data).unwrap();
+        file.sort_canonical(true);
+        assert_eq!(
+            HostsFile::parse("# database\n10.0.0.1\tdb.example\n# web server\n# second line\n10.0.0.9\tweb.example\n# header\n# trailing\n\n").unwrap(),
+            file
+        );
+
+        let mut file = HostsFile::parse(data).unwrap();
+        file.sort_canonical(false);
+        assert_eq!(
+            HostsPart::Entry(
+                "10.0.0.9".parse().unwrap(),
+                vec!["web.example".into()],
+                None
+            ),
+            file.parts()[1]
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        let data = r##"127.0.0.1	localhost
+::1	localhost ip6-localhost ip6-loopback
+ff02::1	ip6-allnodes
+# broken entries below
+10.0.0.1	web.example www.example
+10.0.0.2	web.example
+2001:db8::1	web.example
+10.0.0.1	www.example
+224.0.0.1	multicast.example
+10.0.0.3	bad..example
+#10.0.0.4	web.example
+"##;
+        let issues = HostsFile::parse(data).unwrap().validate();
+        let issue = |line, kind| ValidationIssue { line, kind };
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+        assert_eq!(
+            vec![
+                issue(6, IssueKind::ConflictingMapping("web.example".into(), 5)),
+                issue(
+                    7,
+                    IssueKind::SpecialAddress(ip("2001:db8::1"), "documentation")
+                ),
+                issue(7, IssueKind::AliasMismatch(5)),
+                issue(
+                    8,
+                    IssueKind::DuplicateMapping(ip("10.0.0.1"), "www.example".into())
+                ),
+                issue(9, IssueKind::SpecialAddress(ip("224.0.0.1"), "multicast")),
+                issue(10, IssueKind::InvalidHostname("bad..example".into())),
+            ],
+            issues
+        );
+        assert_eq!(
+            "line 6: \"web.example\" is already mapped to another address on line 5",
+            issues[0].to_string()
+        );
+        assert!(HostsFile::parse("127.0.0.1\tlocalhost\n::1\tlocalhost\n")
+            .unwrap()
+            .validate()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_coalesce_commented_duplicates() {
+        let data = "# 10.0.0.1\tsomehost alias\n10.0.0.1\tsomehost alias # in use\n#10.0.0.1\tsomehost\n;10.0.0.2\tsomehost alias\n";
+        let mut file = HostsFile::from(
+            try_parse_hosts_with(
+                data,
+                ParseOptions {
+                    semicolon_comments: true,
+                    ..ParseOptions::default()
+                },
+            )
+            .unwrap(),
+        );
+        assert!(file.coalesce_commented_duplicates());
+        assert_eq!(
+            HostsFile::parse("10.0.0.1\tsomehost alias # in use\n#10.0.0.1\tsomehost\n")
+                .unwrap()
+                .parts()[..2],
+            file.parts()[..2]
+        );
+        assert_eq!(4, file.parts().len());
+        assert!(!file.coalesce_commented_duplicates());
+    }
+
+    #[test]
+    fn test_normalize() {
+        let data = "# hosts\n   \n\n\n10.0.0.9 web.example web.example  # web  \n  ;10.0.0.5\told.example\n10.0.0.1\tdb.example\n#   \n10.0.0.9\tweb.example alias\n\n";
+        let options = ParseOptions {
+            semicolon_comments: true,
+            ..ParseOptions::default()
+        };
+        let mut file = HostsFile::from(try_parse_hosts_with(data, options).unwrap());
+        file.normalize();
+        let mut expected = HostsFile::parse("10.0.0.1\tdb.example\n10.0.0.9\talias\n10.0.0.9\tweb.example # web\n#10.0.0.5\told.example\n# hosts\n").unwrap();
+        expected.trim_trailing_empty();
+        assert_eq!(expected, file);
+        let normalized = file.clone();
+        file.normalize();
+        assert_eq!(normalized, file);
+    }
+
+    #[test]
+    fn test_all_hostnames_ips() {
+        let data = r##"127.0.0.1	localhost
+127.0.1.1	thismachine
+::1	localhost ip6-localhost ip6-loopback
+ff02::1 ip6-allnodes
+ff02::2 ip6-allrouters
+# comment
+
+198.51.100.11	www.employer.example
+10.0.20.4	intranet.someclub.example #  with trailing comment!
+# 10.4.79.99	deactivated.host deactivated.host.1
+    
+"##;
+        let file = HostsFile::parse(data).unwrap();
+        let hostnames = vec![
+            "intranet.someclub.example",
+            "ip6-allnodes",
+            "ip6-allrouters",
+            "ip6-localhost",
+            "ip6-loopback",
+            "localhost",
+            "thismachine",
+            "www.employer.example",
+        ];
+        assert_eq!(
+            hostnames,
+            file.all_hostnames(false).into_iter().collect::<Vec<_>>()
+        );
+        let ips: Vec<IpAddr> = [
+            "10.0.20.4",
+            "127.0.0.1",
+            "127.0.1.1",
+            "198.51.100.11",
+            "::1",
+            "ff02::1",
+            "ff02::2",
+        ]
+        .iter()
+        .map(|ip| ip.parse().unwrap())
+        .collect();
+        assert_eq!(ips, file.all_ips(false).into_iter().collect::<Vec<_>>());
+
+        let hostnames = file.all_hostnames(true);
+        assert_eq!(10, hostnames.len());
+        assert!(hostnames.contains("deactivated.host.1"));
+        assert!(file
+            .all_ips(true)
+            .contains(&"10.4.79.99".parse::<IpAddr>().unwrap()));
+    }
+
+    #[test]
+    fn test_to_zone_fragment() {
+        let data = r##"10.0.0.1	example.test www.example.test
+2001:db8::1	example.test # dual stack
+# 10.0.0.2	old.example.test
+198.51.100.7	other.example
+"##;
+        let file = HostsFile::parse(data).unwrap();
+        assert_eq!(
+            "@\tIN\tA\t10.0.0.1
+www\tIN\tA\t10.0.0.1
+@\tIN\tAAAA\t2001:db8::1 ; dual stack
+; old\tIN\tA\t10.0.0.2
+other.example.\tIN\tA\t198.51.100.7
+",
+            file.to_zone_fragment("example.test.")
+        );
+    }
+
+    #[test]
+    fn test_add_from_zone() {
+        let zone = r##"$ORIGIN example.test.
+www	3600	IN	A	10.0.0.1 ; web
+api.other.example.	IN	AAAA	2001:db8::2
+@	IN	MX	10 mail
+"##;
+        let mut file = HostsFile::parse("127.0.0.1\tlocalhost\n").unwrap();
+        let warnings = file.add_from_zone(zone);
+        assert_eq!(1, warnings.len(), "{:?}", warnings);
+        assert_eq!(
+            &[
+                HostsPart::Entry(
+                    "10.0.0.1".parse::<IpAddr>().unwrap(),
+                    vec!["www.example.test".into()],
+                    None
+                ),
+                HostsPart::Entry(
+                    "2001:db8::2".parse::<IpAddr>().unwrap(),
+                    vec!["api.other.example".into()],
+                    None
+                ),
+            ],
+            &file.parts()[1..3]
+        );
+
+        // names a hosts file cannot hold are reported instead of added
+        let zone = "*\tIN\tA\t10.0.0.3\nbad#host.\tIN\tA\t10.0.0.4\n@\tIN\tA\t10.0.0.5\n";
+        let mut file = HostsFile::parse("127.0.0.1\tlocalhost\n").unwrap();
+        let warnings = file.add_from_zone(zone);
+        assert_eq!(
+            vec![
+                "line 1: ignoring invalid name \"*\"",
+                "line 2: ignoring invalid name \"bad#host\"",
+                "line 3: ignoring invalid name \"\"",
+            ],
+            warnings
+        );
+        assert_eq!(HostsFile::parse("127.0.0.1\tlocalhost\n").unwrap(), file);
+    }
+
+    #[test]
+    fn test_retain() {
+        let data = r##"127.0.0.1	localhost
+# comment
+
+# 10.4.79.99	deactivated.host
+10.0.20.4	intranet.someclub.example
+"##;
+        let mut file = HostsFile::parse(data).unwrap();
+        file.retain(|part| matches!(part, HostsPart::Entry(..)));
+        assert_eq!(2, file.parts().len());
+        assert!(file.parts()[0].matches_hostname("localhost"));
+        assert!(file.parts()[1].matches_hostname("intranet.someclub.example"));
+    }
+
+    #[test]
+    fn test_json_round_trip() {
+        let data = r##"127.0.0.1	localhost
+::1	localhost ip6-localhost ip6-loopback
+# comment
+
+10.0.20.4	intranet.someclub.example #  with trailing comment!
+# 10.4.79.99	deactivated.host deactivated.host.1
+    
+"##;
+        let file = HostsFile::parse(data).unwrap();
+        let json = file.to_json().unwrap();
+        assert!(
+            json.starts_with(r#"[{"Entry":["127.0.0.1",["localhost"],null]}"#),
+            "{}",
+            json
+        );
+        assert_eq!(file, HostsFile::from_json(&json).unwrap());
+    }
+
+    #[test]
+    fn test_to_owned_static() {
+        let data = String::from("127.0.0.1\tlocalhost\n# comment\n\n10.0.0.1\ta.example # a\n");
+        let file = HostsFile::parse(&data).unwrap();
+        let cached = file.to_owned_static();
+        assert_eq!(file, cached);
+        drop(file);
+        drop(data);
+        assert_eq!(5, cached.parts().len());
+        assert!(cached.parts()[3].matches_hostname("a.example"));
+    }
+
+    #[test]
+    fn test_normalize_blank_lines() {
+        let data = "127.0.0.1\tlocalhost\n\n  \n\n10.0.0.1\ta.example\n";
+        let normalized = |policy| {
+            let mut file = HostsFile::parse(data).unwrap();
+            file.normalize_blank_lines(policy);
+            file.parts().iter().filter(|part| part.is_empty()).count()
+        };
+        assert_eq!(2, normalized(BlankLines::Collapse));
+        assert_eq!(4, normalized(BlankLines::Preserve));
+        assert_eq!(3, normalized(BlankLines::Max(2)));
+        assert_eq!(0, normalized(BlankLines::Max(0)));
+
+        let mut file = HostsFile::parse(data).unwrap();
+        file.normalize_blank_lines(BlankLines::Max(2));
+        assert_eq!(
+            HostsFile::parse("127.0.0.1\tlocalhost\n\n  \n10.0.0.1\ta.example\n").unwrap(),
+            file
+        );
+    }
+
+    #[test]
+    fn test_trim_and_collapse() {
+        let data = "\n\n127.0.0.1\tlocalhost\n\n \n\t\n10.0.0.1\ta.example\n\n\n";
+        let mut file = HostsFile::parse(data).unwrap();
+        file.trim_trailing_empty();
+        assert!(!file.parts().last().unwrap().is_empty());
+        assert_eq!(7, file.parts().len());
+        file.collapse_blank_runs();
+        assert_eq!(
+            HostsFile::parse("\n127.0.0.1\tlocalhost\n\n10.0.0.1\ta.example").unwrap(),
+            file
+        );
+        file.trim_trailing_empty();
+        assert_eq!(4, file.parts().len());
+    }
+
+    #[test]
+    fn test_diff() {
+        let before = HostsFile::parse(
+            "127.0.0.1\tlocalhost\n# header\n10.0.0.1\tapi.example\n10.0.0.2\tdb.example db\n",
+        )
+        .unwrap();
+        let reordered = HostsFile::parse(
+            "# header\n10.0.0.2\tdb.example db\n127.0.0.1\tlocalhost\n10.0.0.1\tapi.example\n",
+        )
+        .unwrap();
+        assert!(before.diff(&reordered).is_empty());
+        assert!(before.diff(&before).is_empty());
+
+        let after = HostsFile::parse(
+            "127.0.0.1\tlocalhost\n# header\n10.0.0.9\tapi.example\n10.0.0.2\tdb.example # db\n",
+        )
+        .unwrap();
+        let ip = |last| IpAddr::from([10, 0, 0, last]);
+        assert_eq!(
+            vec![
+                HostsChange::Removed(HostsPart::Entry(ip(1), vec!["api.example".into()], None)),
+                HostsChange::Added(HostsPart::Entry(ip(9), vec!["api.example".into()], None)),
+                HostsChange::Modified {
+                    before: HostsPart::Entry(ip(2), vec!["db.example".into(), "db".into()], None),
+                    after: HostsPart::Entry(ip(2), vec!["db.example".into()], Some(" db".into())),
+                },
+            ],
+            before.diff(&after)
+        );
+    }
+
+    #[test]
+    fn test_is_managed() {
+        let unmanaged = "127.0.0.1\tlocalhost\n# hostsmod is not in charge here\n";
+        assert!(!HostsFile::parse(unmanaged).unwrap().is_managed());
+        assert!(!HostsFile::default().is_managed());
+        let managed =
+            "127.0.0.1\tlocalhost\n# hostsmod:begin\n10.0.0.1\ta.example\n# hostsmod:end\n";
+        assert!(HostsFile::parse(managed).unwrap().is_managed());
+        let annotated = "10.0.0.1\ta.example # hostsmod:ignore\n";
+        assert!(HostsFile::parse(annotated).unwrap().is_managed());
+    }
+
+    #[test]
+    fn test_family_alias_mismatches() {
+        let file = HostsFile::parse(
+            "10.0.0.1\tapi.example api\nfd00::1\tapi.example\n10.0.0.2\tdb.example DB\nfd00::2\tdb\tdb.example\n# fd00::3 api.example\n",
+        )
+        .unwrap();
+        assert_eq!(
+            vec![(&file.parts()[0], &file.parts()[1])],
+            file.family_alias_mismatches()
+        );
+
+        // a large blocklist is no pairwise comparison of all entries
+        let mut data = String::new();
+        for i in 0..20_000u32 {
+            writeln!(data, "0.0.0.0\tblocked-{}.example", i).unwrap();
+            writeln!(data, "::\tblocked-{}.example", i).unwrap();
+        }
+        data.push_str("::\tblocked-0.example www.blocked-0.example\n");
+        let file = HostsFile::parse(&data).unwrap();
+        assert_eq!(
+            vec![(&file.parts()[0], &file.parts()[40_000])],
+            file.family_alias_mismatches()
+        );
+    }
+
+    #[test]
+    fn test_try_parse_hosts_reader() {
+        let data = "127.0.0.1\tlocalhost\r\n# comment\n10.0.0.1\tsomehost\n";
+        let file = try_parse_hosts_reader(Cursor::new(data)).unwrap();
+        assert_eq!(HostsFile::parse(data).unwrap(), file);
+        assert!(try_parse_hosts_reader(Cursor::new("127.0.0.1\n")).is_err());
+    }
+
+    #[test]
+    fn test_had_trailing_newline() {
+        for (data, expected) in [
+            ("127.0.0.1\tlocalhost\n", true),
+            ("127.0.0.1\tlocalhost\r\n\n", true),
+            ("\n", true),
+            ("127.0.0.1\tlocalhost", false),
+            ("127.0.0.1\tlocalhost\n  ", false),
+            ("", false),
+        ] {
+            assert_eq!(
+                expected,
+                HostsFile::parse(data).unwrap().had_trailing_newline(),
+                "{:?}",
+                data
+            );
+        }
+        let data = "127.0.0.1\tlocalhost\n\r";
+        let mut file = HostsFile::parse(data).unwrap();
+        assert!(file.had_trailing_newline());
+        file.trim_trailing_empty();
+        assert!(file.had_trailing_newline());
+        assert!(file.to_owned_static().had_trailing_newline());
+        assert!(try_parse_hosts_reader(Cursor::new(data))
+            .unwrap()
+            .had_trailing_newline());
+        assert!(!try_parse_hosts_reader(Cursor::new("127.0.0.1\tlocalhost"))
+            .unwrap()
+            .had_trailing_newline());
+        assert!(!HostsFile::from(file.into_parts()).had_trailing_newline());
+    }
+}
@@ -0,0 +1,70 @@
+use std::io::Write;
+use std::os::unix::process::CommandExt;
+use std::process::{Command, ExitStatus, Stdio};
+
+/// The only environment variable passed to a hook, the caller's environment is discarded.
+const HOOK_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+/// Runs a command line through `sh -c` and waits for it to finish. If given, `input` is written to
+/// the standard input of the command.
+///
+/// The command runs with a cleared environment except for a fixed `PATH`, so the invoking user
+/// cannot influence what it executes. With effective root, as when run setuid, it runs as root:
+/// the real user and group are set to root as well, as the shell would otherwise drop the
+/// privileges, eg. for `nscd -i hosts`.
+pub fn run_hook(command: &str, input: Option<&str>) -> Result<ExitStatus, String> {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c")
+        .arg(command)
+        .env_clear()
+        .env("PATH", HOOK_PATH)
+        .stdin(if input.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::inherit()
+        });
+    if users::get_effective_uid() == 0 {
+        // only async-signal-safe calls between fork and exec
+        unsafe {
+            cmd.pre_exec(|| {
+                if libc::setgid(0) != 0 || libc::setuid(0) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+    }
+    let mut child = cmd
+        .spawn()
+        .map_err(|err| format!("unable to run hook {:?}: {}", command, err))?;
+    if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+        // the hook may exit without consuming all of its input
+        if let Err(err) = stdin.write_all(input.as_bytes()) {
+            if err.kind() != std::io::ErrorKind::BrokenPipe {
+                return Err(format!(
+                    "unable to pass input to hook {:?}: {}",
+                    command, err
+                ));
+            }
+        }
+    }
+    child
+        .wait()
+        .map_err(|err| format!("unable to run hook {:?}: {}", command, err))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::hook::{run_hook, HOOK_PATH};
+
+    #[test]
+    fn test_hook_environment() {
+        // set for the test run by cargo
+        assert!(std::env::var_os("CARGO").is_some());
+        let status = run_hook(
+            r#"test -z "$CARGO" && test "$PATH" = "$(cat)""#,
+            Some(HOOK_PATH),
+        );
+        assert!(status.unwrap().success());
+    }
+}
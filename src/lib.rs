@@ -7,8 +7,81 @@
 //! Intended to be compatible to any hosts file outlined in `man 5 hosts`. Uses the nom parser
 //! combinator library.
 
+mod action;
+mod file;
 mod parse;
+mod reserved;
 
+pub use action::try_parse_action;
+pub use action::Action;
+pub use action::ApplyError;
+pub use action::ApplyReport;
+pub use action::HostPolicy;
+pub use action::InsertPosition;
+pub use action::Policy;
+
+pub use file::try_parse_hosts_reader;
+pub use file::BlankLines;
+pub use file::HostsChange;
+pub use file::HostsFile;
+pub use file::IssueKind;
+pub use file::ValidationIssue;
+pub use file::IGNORE_MARKER;
+pub use file::MANAGED_BEGIN;
+pub use file::MANAGED_END;
+
+pub use parse::canonical_ip;
+pub use parse::ip_family;
+pub use parse::parse_hosts_prefix;
+pub use parse::parse_hosts_reader;
+pub use parse::parse_lenient;
 pub use parse::try_parse_hosts;
+pub use parse::try_parse_hosts_bytes;
+pub use parse::try_parse_hosts_with;
+pub use parse::try_parse_hosts_with_lines;
+pub use parse::try_parse_hosts_with_spans;
+pub use parse::CommentMarker;
+pub use parse::HostsParseError;
 pub use parse::HostsPart;
 pub use parse::HostsPartFamily;
+pub use parse::ParseOptions;
+
+pub use reserved::reserved_present;
+pub use reserved::resolve_reserved;
+pub use reserved::verify_reserved;
+pub use reserved::HostsEntry;
+pub use reserved::ReservedViolation;
+pub use reserved::DONT_TOUCH;
+pub use reserved::RESERVED_FQDN;
+pub use reserved::RESERVED_HOSTNAME;
+pub use reserved::RESERVED_IP6_ALLNODES;
+pub use reserved::RESERVED_IP6_ALLROUTERS;
+pub use reserved::RESERVED_IP6_LOCALHOST;
+pub use reserved::RESERVED_IP6_LOOPBACK;
+pub use reserved::RESERVED_LOCALHOST;
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::process::Command;
+
+    #[test]
+    fn test_build_without_cli() {
+        let manifest_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let status = Command::new(env!("CARGO"))
+            .args(["build", "--lib", "--offline", "--no-default-features"])
+            .arg("--manifest-path")
+            .arg(manifest_dir.join("Cargo.toml"))
+            // separate directory, the outer cargo may still hold the lock on the default one
+            .env(
+                "CARGO_TARGET_DIR",
+                manifest_dir.join("target").join("no-default-features"),
+            )
+            .status()
+            .expect("unable to run cargo");
+        assert!(
+            status.success(),
+            "library does not build without feature cli"
+        );
+    }
+}
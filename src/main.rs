@@ -27,24 +27,108 @@ extern crate structopt;
 extern crate nom;
 
 mod config;
+mod hook;
 mod opts;
+mod persist;
+// shared with the library, not all of it is used by the binary
+#[allow(dead_code)]
+mod action;
+#[allow(dead_code)]
+mod file;
+#[allow(dead_code)]
 mod parse;
+#[allow(dead_code)]
+mod reserved;
 
-use crate::config::RESERVED_HOSTNAME;
-use crate::config::{HostsmodConfig, DONT_TOUCH};
-use crate::opts::Action;
-use crate::parse::{try_parse_hosts, HostsPart, HostsPartFamily};
+use crate::action::{ApplyReport, Policy};
+use crate::config::{update_whitelist, HostsmodConfig};
+use crate::file::{diff_parts, HostsChange, HostsFile};
+use crate::hook::run_hook;
+use crate::opts::{Command, ConfigCommand, WhitelistCommand};
+use crate::parse::{try_parse_hosts_with, try_parse_spans_with, HostsPart, ParseOptions};
+use crate::persist::{
+    create_backup, list_backups, prune_backups, resolve_symlink, rollback, sibling_with_suffix,
+    write_atomically,
+};
+use crate::reserved::{
+    reserved_present, resolve_reserved, verify_reserved, HostsEntry, DONT_TOUCH,
+};
 use std::borrow::Cow;
-use std::cmp::min;
-use std::fs::{rename, File, OpenOptions};
-use std::io::{stdout, BufReader, Read, Write};
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::fs::{File, OpenOptions};
+use std::io::{self, stdout, BufReader, ErrorKind, Read, Write};
 use std::net::IpAddr;
+use std::ops::Range;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
 use structopt::StructOpt;
 
 const PATH_HOSTSFILE: &str = "/etc/hosts";
-const PATH_HOSTSFILE_NEW: &str = "/etc/hosts.new";
+/// Appended to the name of the hosts file for the atomic write unless configured otherwise.
+const TEMP_SUFFIX: &str = ".new";
 
 const PATH_CONFIG: &str = "/etc/hostsmod.yaml";
+const PATH_CONFIG_NEW: &str = "/etc/hostsmod.yaml.new";
+
+/// Exit status if a modification would violate a reserved entry.
+const EXIT_RESERVED: i32 = 3;
+/// Exit status if a dry run left modifications unwritten.
+const EXIT_DRY_RUN: i32 = 4;
+/// Exit status if the hosts file would grow beyond `max_entries`.
+const EXIT_MAX_ENTRIES: i32 = 5;
+/// Exit status if the `pre_change_hook` rejected the modifications.
+const EXIT_HOOK_REJECTED: i32 = 6;
+/// Exit status if the generated hosts file would not parse back as intended.
+const EXIT_ROUND_TRIP: i32 = 7;
+/// Exit status if the hosts file is a symlink, which is not replaced without `--follow-symlinks`.
+const EXIT_SYMLINK: i32 = 8;
+
+/// How a run ended, unless it failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Outcome {
+    /// The hosts file was written.
+    Modified,
+    /// Nothing to do, the hosts file already is as intended. Also the result of read-only
+    /// commands.
+    Unchanged,
+    /// There were modifications, but `--dry-run` suppressed writing them.
+    DryRun,
+    /// The modifications were refused, they would grow the hosts file beyond `max_entries`.
+    TooManyEntries,
+    /// The modifications were refused by the `pre_change_hook`.
+    HookRejected,
+    /// The modifications were refused, the generated hosts file does not parse back as intended.
+    RoundTripFailed,
+    /// The modifications were refused, the hosts file is a symlink, see `--follow-symlinks`.
+    SymlinkRefused,
+}
+
+impl Outcome {
+    fn message(self) -> &'static str {
+        match self {
+            Outcome::Modified => "hosts file modified",
+            Outcome::Unchanged => "no changes needed, hosts file not modified",
+            Outcome::DryRun => "dry run, changes not written, hosts file not modified",
+            Outcome::TooManyEntries => "too many entries, hosts file not modified",
+            Outcome::HookRejected => "rejected by pre_change_hook, hosts file not modified",
+            Outcome::RoundTripFailed => "unparseable result, hosts file not modified",
+            Outcome::SymlinkRefused => "hosts file is a symlink, hosts file not modified",
+        }
+    }
+
+    fn exit_code(self) -> i32 {
+        match self {
+            Outcome::Modified | Outcome::Unchanged => 0,
+            Outcome::DryRun => EXIT_DRY_RUN,
+            Outcome::TooManyEntries => EXIT_MAX_ENTRIES,
+            Outcome::HookRejected => EXIT_HOOK_REJECTED,
+            Outcome::RoundTripFailed => EXIT_ROUND_TRIP,
+            Outcome::SymlinkRefused => EXIT_SYMLINK,
+        }
+    }
+}
 
 fn main() {
     let hostname_os_string = hostname::get().expect("unable to determine system hostname");
@@ -77,11 +161,11 @@ The only exception is if the config variable `enable_dangerous_operations` is se
 these reserved hostnames can be modified."##,
             PATH_HOSTSFILE,
             PATH_CONFIG,
-            config::RESERVED_LOCALHOST,
-            config::RESERVED_IP6_LOCALHOST,
-            config::RESERVED_IP6_LOOPBACK,
-            config::RESERVED_IP6_ALLNODES,
-            config::RESERVED_IP6_ALLROUTERS,
+            reserved::RESERVED_LOCALHOST,
+            reserved::RESERVED_IP6_LOCALHOST,
+            reserved::RESERVED_IP6_LOOPBACK,
+            reserved::RESERVED_IP6_ALLNODES,
+            reserved::RESERVED_IP6_ALLROUTERS,
             hostname
         );
         let app = app
@@ -91,6 +175,10 @@ these reserved hostnames can be modified."##,
         opts::HostsArgs::from_clap(&app.get_matches())
     };
 
+    if let Some(action) = opts.command.as_ref().and_then(Command::action) {
+        opts.actions.push(action);
+    }
+
     if opts.generate_sample_config {
         let mut out = stdout();
         let mut sample = HostsmodConfig::default();
@@ -99,14 +187,354 @@ these reserved hostnames can be modified."##,
         return;
     }
 
-    let euid = users::get_effective_uid();
+    let uid = users::get_current_uid();
+    let mut euid = users::get_effective_uid();
     // dbg!(uid);
-    if euid != 0 {
+    let path_hosts = opts
+        .file
+        .clone()
+        .unwrap_or_else(|| PathBuf::from(PATH_HOSTSFILE));
+    // `--file /etc/hosts` is no different from leaving it out
+    let user_file = opts.file.clone().filter(|path| !is_system_hosts_file(path));
+    let writable = user_file.as_deref().is_some_and(writable_by_real_user);
+    match setuid_user_file(user_file.as_deref(), uid, euid) {
+        Ok(false) => {}
+        Ok(true) => {
+            if let Err(err) = drop_privileges(uid) {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            }
+            euid = uid;
+        }
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    }
+    let forced_dry_run = lacks_privilege(euid, writable) && !opts.dry_run;
+    if lacks_privilege(euid, writable) {
         eprintln!("not effectively root, forced dry-run mode");
         opts.dry_run = true;
     }
+    if opts.verbose {
+        eprintln!("{}", privilege_summary(uid, euid, forced_dry_run));
+    }
     // dbg!(opts);
 
+    if opts.init {
+        // the effective user is root when run setuid
+        if uid != 0 {
+            eprintln!("--init requires running as root");
+            std::process::exit(1);
+        }
+        let created = init(Path::new(PATH_CONFIG), Path::new(PATH_HOSTSFILE), hostname)
+            .expect("unable to initialize");
+        for path in &created {
+            println!("created {:?}", path);
+        }
+        if created.is_empty() {
+            println!("{:?} and {:?} already exist", PATH_CONFIG, PATH_HOSTSFILE);
+        }
+        return;
+    }
+
+    if let Some(Command::Config {
+        command: Some(ConfigCommand::Whitelist(whitelist)),
+    }) = &opts.command
+    {
+        // the effective user is root when run setuid
+        if uid != 0 {
+            eprintln!("modifying the config requires running as root");
+            std::process::exit(1);
+        }
+        let (host, add) = match whitelist {
+            WhitelistCommand::Add { host } => (host, true),
+            WhitelistCommand::Remove { host } => (host, false),
+        };
+        let changed = update_whitelist(
+            Path::new(PATH_CONFIG),
+            Path::new(PATH_CONFIG_NEW),
+            host,
+            add,
+        )
+        .expect("unable to modify config");
+        if !changed {
+            println!("whitelist already up to date, config not modified");
+        }
+        return;
+    }
+
+    let cfg = match load_config(Path::new(PATH_CONFIG)) {
+        Ok(cfg) => cfg,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    };
+    if opts.validate_config {
+        let problems = cfg.validate();
+        if !problems.is_empty() {
+            eprintln!("config {:?} is invalid", PATH_CONFIG);
+            std::process::exit(1);
+        }
+        println!("config {:?} is valid", PATH_CONFIG);
+        return;
+    }
+    if let Err(err) = check_allow(&opts, &cfg, uid) {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    }
+    if opts.show_config {
+        print!("{}", effective_config(&opts, &cfg));
+        return;
+    }
+    let fqdn = system_fqdn(hostname);
+
+    let outcome = run(&mut opts, &cfg, hostname, fqdn.as_deref(), &path_hosts);
+    std::process::exit(outcome.exit_code());
+}
+
+/// Looks up the fully-qualified domain name of the machine like `hostname -f`, `None` if there is
+/// none.
+fn system_fqdn(hostname: &str) -> Option<String> {
+    let c_hostname = CString::new(hostname).ok()?;
+    // SAFETY: hints is a valid zeroed addrinfo, the result list is freed exactly once
+    unsafe {
+        let mut hints: libc::addrinfo = std::mem::zeroed();
+        hints.ai_flags = libc::AI_CANONNAME;
+        let mut info = std::ptr::null_mut();
+        if libc::getaddrinfo(c_hostname.as_ptr(), std::ptr::null(), &hints, &mut info) != 0 {
+            return None;
+        }
+        let fqdn = if (*info).ai_canonname.is_null() {
+            None
+        } else {
+            CStr::from_ptr((*info).ai_canonname)
+                .to_str()
+                .ok()
+                .map(String::from)
+        };
+        libc::freeaddrinfo(info);
+        fqdn.filter(|fqdn| fqdn.contains('.'))
+    }
+}
+
+fn load_config(path: &Path) -> Result<HostsmodConfig, String> {
+    // TODO: check config file access rights
+    let file_cfg = File::open(path).map_err(|err| match err.kind() {
+        ErrorKind::NotFound => format!(
+            "config file {:?} not found, create one with `hostsmod --sample-config > {}`",
+            path,
+            path.display()
+        ),
+        _ => format!("unable to open config file {:?}: {}", path, err),
+    })?;
+    let owned_by_root = file_cfg
+        .metadata()
+        .map(|meta| meta.uid() == 0)
+        .unwrap_or(false);
+    let mut cfg: HostsmodConfig = serde_yaml::from_reader(BufReader::new(file_cfg))
+        .map_err(|err| format!("unable to parse configuration {:?}: {}", path, err))?;
+    cfg.check_protected()
+        .map_err(|err| format!("configuration {:?} is invalid: {}", path, err))?;
+    // commands run as root with a clean environment, only an admin may define them
+    if !owned_by_root && (cfg.pre_change_hook.is_some() || cfg.post_change_hook.is_some()) {
+        eprintln!(
+            "config {:?} is not owned by root, ignoring pre_change_hook and post_change_hook",
+            path
+        );
+        cfg.pre_change_hook = None;
+        cfg.post_change_hook = None;
+    }
+    for problem in cfg.validate() {
+        eprintln!("warning: {}", problem);
+    }
+    Ok(cfg)
+}
+
+/// Describes whether the tool acts as root, eg. when run setuid by the real user `uid`.
+fn privilege_summary(uid: u32, euid: u32, forced_dry_run: bool) -> String {
+    format!(
+        "real uid {}, effective uid {}: {}{}",
+        uid,
+        euid,
+        match (uid, euid) {
+            (0, 0) => "running as root",
+            (_, 0) => "running setuid root",
+            _ => "running unprivileged",
+        },
+        if forced_dry_run {
+            ", dry-run forced due to lack of privilege"
+        } else {
+            ""
+        }
+    )
+}
+
+/// Decides whether writing the hosts file must be left to a dry run. Without effective root, only
+/// a file given by `--file` which the invoking user may write is modified.
+fn lacks_privilege(euid: u32, writable_user_file: bool) -> bool {
+    euid != 0 && !writable_user_file
+}
+
+/// Checks whether `path` given by `--file` is the system hosts file, which is handled as if
+/// `--file` was left out.
+fn is_system_hosts_file(path: &Path) -> bool {
+    let system = Path::new(PATH_HOSTSFILE);
+    path == system
+        || matches!((path.canonicalize(), system.canonicalize()), (Ok(a), Ok(b)) if a == b)
+}
+
+/// Decides whether root privileges must be dropped before touching `user_file`, given by `--file`.
+/// Run setuid root, only the system hosts file is modified on behalf of the user: a file the
+/// invoking user may write is handled with their privileges, any other file is refused.
+fn setuid_user_file(user_file: Option<&Path>, uid: u32, euid: u32) -> Result<bool, String> {
+    match user_file {
+        Some(path) if uid != 0 && euid == 0 => {
+            if writable_by_real_user(path) {
+                Ok(true)
+            } else {
+                Err(format!(
+                    "--file {:?} is not writable by the invoking user",
+                    path
+                ))
+            }
+        }
+        _ => Ok(false),
+    }
+}
+
+/// Checks whether the real user may write `path` and create files next to it, as required by the
+/// atomic write. Unlike the effective user, which is root when run setuid.
+fn writable_by_real_user(path: &Path) -> bool {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    [(path, libc::W_OK), (dir, libc::W_OK | libc::X_OK)]
+        .iter()
+        .all(
+            |(path, mode)| match CString::new(path.as_os_str().as_bytes()) {
+                // access(2) checks against the real user and group
+                Ok(c_path) => unsafe { libc::access(c_path.as_ptr(), *mode) == 0 },
+                Err(_) => false,
+            },
+        )
+}
+
+/// Permanently gives up effective root in favour of the real user and group.
+fn drop_privileges(uid: u32) -> Result<(), String> {
+    unsafe {
+        if libc::setgid(libc::getgid()) != 0 || libc::setuid(uid) != 0 {
+            return Err(format!(
+                "unable to drop privileges: {}",
+                io::Error::last_os_error()
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Creates a default config at `path_config` and a hosts file containing the reserved entries at
+/// `path_hosts`, skipping any which already exist. Returns the paths of the files created.
+fn init(path_config: &Path, path_hosts: &Path, hostname: &str) -> Result<Vec<PathBuf>, String> {
+    let config =
+        serde_yaml::to_string(&HostsmodConfig::default()).expect("unable to serialize config");
+    let mut hosts = String::new();
+    let reserved = resolve_reserved(DONT_TOUCH, hostname, None);
+    for (idx, entry) in reserved.iter().enumerate() {
+        if reserved[..idx].iter().any(|prev| prev.ip == entry.ip) {
+            continue;
+        }
+        let hostnames: Vec<&str> = reserved
+            .iter()
+            .filter(|other| other.ip == entry.ip)
+            .map(|other| other.hostname.as_ref())
+            .collect();
+        hosts.push_str(&format!("{}\t{}\n", entry.ip, hostnames.join(" ")));
+    }
+
+    let mut created = vec![];
+    for (path, content) in [(path_config, config), (path_hosts, hosts)] {
+        let mut file = match OpenOptions::new().write(true).create_new(true).open(path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == ErrorKind::AlreadyExists => continue,
+            Err(err) => return Err(format!("unable to create {:?}: {}", path, err)),
+        };
+        file.write_all(content.as_bytes())
+            .and_then(|_| file.sync_all())
+            .map_err(|err| format!("unable to write {:?}: {}", path, err))?;
+        created.push(path.to_path_buf());
+    }
+    Ok(created)
+}
+
+/// Verifies `--allow` is permitted for the invoking user `uid`. The effective user is root when run
+/// setuid, so only the real user counts.
+fn check_allow(opts: &opts::HostsArgs, config: &HostsmodConfig, uid: u32) -> Result<(), String> {
+    if opts.allow.is_empty() || uid == 0 || config.allow_runtime_whitelist {
+        return Ok(());
+    }
+    Err(format!(
+        "--allow {:?} requires running as root or config variable allow_runtime_whitelist",
+        opts.allow
+    ))
+}
+
+/// Renders the configuration in effect as YAML: `config` with the runtime flags of `opts` applied.
+/// Unlike a sample config, it includes `enable_dangerous_operations`.
+fn effective_config(opts: &opts::HostsArgs, config: &HostsmodConfig) -> String {
+    let mut config = config.clone();
+    config.whitelist.extend(opts.allow.iter().cloned());
+    let mut value = serde_yaml::to_value(&config).expect("unable to serialize config");
+    if let serde_yaml::Value::Mapping(mapping) = &mut value {
+        mapping.insert(
+            "enable_dangerous_operations".into(),
+            config.enable_dangerous_operations.into(),
+        );
+    }
+    serde_yaml::to_string(&value).expect("unable to serialize config")
+}
+
+/// Modifies the hosts file at `path` according to `opts`. The atomic write goes through a sibling
+/// named by appending the config variable `temp_suffix`.
+fn run(
+    opts: &mut opts::HostsArgs,
+    config: &HostsmodConfig,
+    hostname: &str,
+    fqdn: Option<&str>,
+    path: &Path,
+) -> Outcome {
+    let path_link = path;
+    let resolved = resolve_symlink(path).expect("unable to open hosts");
+    let symlink_refused = resolved.is_some() && !opts.follow_symlinks;
+    let path = match &resolved {
+        Some(path_real) if opts.follow_symlinks => path_real.as_path(),
+        _ => path,
+    };
+    let path_new = &sibling_with_suffix(path, config.temp_suffix.as_deref().unwrap_or(TEMP_SUFFIX));
+    let refusal = "is a symlink, replacing it would break the link (see --follow-symlinks), hosts \
+                   file not modified";
+
+    if opts.rollback {
+        if opts.dry_run {
+            let backups = list_backups(path).expect("unable to list backups");
+            match backups.last() {
+                Some(backup) => println!("would restore hosts file from {:?}", backup),
+                None => println!("no backup of hosts file found"),
+            }
+            println!("hosts file not modified");
+            return Outcome::DryRun;
+        }
+        if symlink_refused {
+            eprintln!("{:?} {}", path_link, refusal);
+            return Outcome::SymlinkRefused;
+        }
+        let backup = rollback(path, path_new).expect("unable to roll back hosts file");
+        println!("restored hosts file from {:?}", backup);
+        return Outcome::Modified;
+    }
+
     // open file
     let mut file_hosts_orig = OpenOptions::new()
         .read(true)
@@ -114,7 +542,7 @@ these reserved hostnames can be modified."##,
         .write(false)
         .truncate(false)
         .create(false)
-        .open(PATH_HOSTSFILE)
+        .open(path)
         .expect("unable to open hosts");
 
     // let opt_file_hosts_new = if opts.dry_run {
@@ -135,324 +563,522 @@ these reserved hostnames can be modified."##,
         .read_to_string(&mut str_content)
         .expect("unable to read hosts file as UTF-8 string");
 
-    let mut hosts_parts =
-        try_parse_hosts(&str_content).expect("unable to parse contents of hosts file");
-    trim_hosts_parts(&mut hosts_parts);
-
-    let hosts_parts_orig = hosts_parts.clone();
+    let options = ParseOptions {
+        semicolon_comments: config.semicolon_comments,
+        lenient: opts.lenient,
+    };
+    let spans = try_parse_spans_with(&str_content, options)
+        .expect("unable to parse contents of hosts file");
+    for (idx, (_, part)) in spans.iter().enumerate() {
+        if let HostsPart::Raw(raw) = part {
+            eprintln!(
+                "warning: line {} not understood, kept as is: {:?}",
+                idx + 1,
+                raw
+            );
+        }
+    }
+    let mut hosts_file = HostsFile::from(
+        spans
+            .iter()
+            .map(|(_, part)| part.clone())
+            .collect::<Vec<_>>(),
+    );
+    hosts_file.trim_trailing_empty();
+    if opts.verbose {
+        for (v4, v6) in hosts_file.family_alias_mismatches() {
+            eprintln!("warning: aliases differ between {:?} and {:?}", v4, v6);
+        }
+    }
+    let mut hosts_parts = hosts_file.into_parts();
 
     // eprintln!("PRE-actions: {:#?}", &hosts_parts);
 
-    let cfg: HostsmodConfig = {
-        // TODO: check config file ownership & access rights
-        let file_cfg = BufReader::new(File::open(PATH_CONFIG).expect("unable to open config file"));
-        serde_yaml::from_reader(file_cfg).expect("unable to parse configuration")
-    };
+    match &opts.command {
+        Some(Command::List) => {
+            print!("{}", list_entries(&hosts_parts));
+            return Outcome::Unchanged;
+        }
+        Some(Command::Query { host }) => {
+            let mut found = false;
+            for part in hosts_parts
+                .iter()
+                .filter(|part| matches!(part, HostsPart::Entry(..)) && part.matches_hostname(host))
+            {
+                if let HostsPart::Entry(ip, ..) = part {
+                    println!("{}", ip);
+                    found = true;
+                }
+            }
+            if !found {
+                std::process::exit(1);
+            }
+            return Outcome::Unchanged;
+        }
+        Some(Command::Config { .. }) => {
+            print!("{}", effective_config(opts, config));
+            return Outcome::Unchanged;
+        }
+        _ => {}
+    }
 
-    if opts.dry_run || opts.verbose {
+    if opts.check {
+        let violations = check_ensure_rules(&hosts_parts, config);
+        for violation in &violations {
+            println!("{}", violation);
+        }
+        if !violations.is_empty() {
+            std::process::exit(1);
+        }
         if opts.verbose {
-            eprintln!("config: {:#?}", cfg);
+            println!("all ensure rules satisfied");
         }
-        println!("original contents:\n>>>\n{}<<<", str_content);
+        return Outcome::Unchanged;
+    }
+    if opts.validate {
+        let issues = HostsFile::from(hosts_parts).validate();
+        for issue in &issues {
+            println!("{}", issue);
+        }
+        if !issues.is_empty() {
+            std::process::exit(1);
+        }
+        if opts.verbose {
+            println!("no issues found");
+        }
+        return Outcome::Unchanged;
     }
 
-    let mut found_pre = vec![false; DONT_TOUCH.len()];
-
-    if !cfg.enable_dangerous_operations {
-        for (dt, found) in DONT_TOUCH.iter().zip(found_pre.iter_mut()) {
-            let dt_host = if dt.hostname == RESERVED_HOSTNAME {
-                Cow::Borrowed(hostname)
-            } else {
-                Cow::Borrowed(dt.hostname.as_ref())
-            };
-            for part in &hosts_parts {
-                if part.matches_hostname(&dt_host) && part.matches_ip(&dt.ip) {
-                    *found = true;
-                }
-            }
+    if opts.dry_run || opts.verbose {
+        if opts.verbose {
+            eprintln!("config: {:#?}", config);
+        }
+        if !opts.changed_only {
+            println!("original contents:\n>>>\n{}<<<", str_content);
         }
     }
-    let found_pre = found_pre;
+
+    // the parts as parsed remain in `spans`, record what the checks and the summary need of them
+    let mut protected = resolve_reserved(DONT_TOUCH, hostname, fqdn);
+    protected.extend(resolve_reserved(&config.protected, hostname, fqdn));
+    let reserved_pre = reserved_present(&hosts_parts, &protected, hostname);
+    let count_pre = count_entries(&hosts_parts);
+    let spans_pre = &spans[..hosts_parts.len()];
 
     // execute actions
-    perform_actions(&mut opts, &mut hosts_parts, &cfg).expect("unable to modify hosts file");
+    let report =
+        perform_actions(opts, &mut hosts_parts, config).expect("unable to modify hosts file");
+    let mut changed = report.changed;
+    changed |= ensure_present(&mut hosts_parts, &config.ensure_present);
+    changed |= ensure_absent(&mut hosts_parts, &config.ensure_absent);
+    if opts.tidy {
+        let mut hosts_file = HostsFile::from(hosts_parts);
+        changed |= hosts_file.coalesce_commented_duplicates();
+        hosts_parts = hosts_file.into_parts();
+    }
+    if opts.normalize {
+        let mut hosts_file = HostsFile::from(hosts_parts);
+        hosts_file.normalize();
+        hosts_parts = hosts_file.into_parts();
+        // rendered without the original, so nothing of its layout is kept
+        changed |= generate_hosts_file(len_content, &hosts_parts, "", &[]) != str_content;
+    }
+    let print_summary = |hosts_parts: &[HostsPart]| {
+        if opts.summary {
+            eprintln!(
+                "{}",
+                summary(
+                    spans_pre,
+                    hosts_parts,
+                    report.unchanged.len(),
+                    path_link,
+                    opts.dry_run
+                )
+            );
+        }
+    };
 
-    if !opts.dry_run && hosts_parts == hosts_parts_orig {
-        if opts.verbose {
-            println!("no changes, not modifying hosts file");
+    if !changed {
+        if opts.dry_run || opts.verbose {
+            println!("{}", Outcome::Unchanged.message());
+        }
+        print_summary(&hosts_parts);
+        return Outcome::Unchanged;
+    }
+
+    if config.dedup_on_write {
+        dedup_entries(&mut hosts_parts);
+    }
+    if config.sort_on_write {
+        let mut hosts_file = HostsFile::from(hosts_parts);
+        hosts_file.sort_canonical(config.attach_comments);
+        hosts_parts = hosts_file.into_parts();
+    }
+    if let Some(max_entries) = config.max_entries {
+        let count_post = count_entries(&hosts_parts);
+        if count_post > max_entries && count_post > count_pre {
+            eprintln!(
+                "refusing to grow hosts file to {} entries, maximum is {}, hosts file not modified",
+                count_post, max_entries
+            );
+            return Outcome::TooManyEntries;
         }
-        return;
     }
 
     // remove redundant Empty elements
-    trim_hosts_parts(&mut hosts_parts);
-    {
-        let mut remove = false;
-        hosts_parts.retain(|item| match (item.is_empty(), remove) {
-            (true, true) => false,
-            (true, false) => {
-                remove = true;
-                true
-            }
-            (false, _) => {
-                remove = false;
-                true
-            }
-        });
+    let mut hosts_file = HostsFile::from(hosts_parts);
+    hosts_file.trim_trailing_empty();
+    hosts_file.normalize_blank_lines(config.blank_lines);
+    let mut hosts_parts = hosts_file.into_parts();
+    if config.blank_whitespace_lines {
+        blank_whitespace_lines(&mut hosts_parts);
     }
 
     // eprintln!("POST-actions: {:#?}", &hosts_parts);
 
     // compare against DONT_TOUCH
-    let buf_generate = generate_hosts_file(len_content, &hosts_parts);
+    let buf_generate = if opts.normalize {
+        generate_hosts_file(len_content, &hosts_parts, "", &[])
+    } else {
+        generate_hosts_file(len_content, &hosts_parts, &str_content, &spans)
+    };
     // eprintln!(">\n{}<", &buf_generate);
 
     // safety checks
-    if !cfg.enable_dangerous_operations {
-        let mut found_post = vec![false; DONT_TOUCH.len()];
-        for (dt, found) in DONT_TOUCH.iter().zip(found_post.iter_mut()) {
-            let dt_host = if dt.hostname == RESERVED_HOSTNAME {
-                Cow::Borrowed(hostname)
-            } else {
-                Cow::Borrowed(dt.hostname.as_ref())
-            };
-            for part in &hosts_parts {
-                match (part.matches_hostname(&dt_host), part.matches_ip(&dt.ip)) {
-                    (true, true) => {
-                        *found = true;
-                    }
-                    (true, false) => {
-                        if DONT_TOUCH
-                            .iter()
-                            .find(|dt_lookup| {
-                                // eprint!("conflict: {:?} == {:?} ", part, dt_lookup);
-                                let res = part.matches_hostname(&dt_lookup.hostname)
-                                    && part.matches_ip(&dt_lookup.ip);
-                                // eprintln!("{}", res);
-                                res
-                            })
-                            .is_none()
-                        {
-                            panic!(
-                                "untouchable entry {:?} {:?} was changed! {:?}",
-                                dt.ip, dt_host, part
-                            );
-                        }
-                        // *found = true;
-                    }
-                    (false, _) => {}
-                }
-            }
-        }
-        if found_post != found_pre {
-            dbg!(&found_pre);
-            dbg!(&found_post);
-            for (i, (pre, post)) in found_pre.iter().zip(found_post.iter()).enumerate() {
-                if pre != post {
-                    eprintln!("Difference: {:?}", DONT_TOUCH[i])
-                }
-            }
-            panic!("found_post != found_pre");
+    if !config.enable_dangerous_operations {
+        if let Err(violation) = verify_reserved(&reserved_pre, &hosts_parts, &protected, hostname) {
+            eprintln!("{}, hosts file not modified", violation);
+            std::process::exit(EXIT_RESERVED);
         }
     }
+    if let Err(err) = verify_round_trip(&hosts_parts, &buf_generate, options) {
+        eprintln!(
+            "generated hosts file does not parse as intended ({}), hosts file not modified",
+            err
+        );
+        return Outcome::RoundTripFailed;
+    }
 
     if opts.dry_run || opts.verbose {
-        println!("generated:\n>>>\n{}<<<", &buf_generate);
+        if opts.changed_only {
+            print!(
+                "{}",
+                changed_lines(spans_pre, &hosts_parts, &str_content, &spans)
+            );
+        } else {
+            println!("generated:\n>>>\n{}<<<", &buf_generate);
+        }
     }
     if opts.dry_run {
         println!("DRY-RUN DRY-RUN DRY-RUN DRY-RUN DRY-RUN DRY-RUN DRY-RUN DRY-RUN DRY-RUN DRY-RUN DRY-RUN DRY-RUN");
-        println!("hosts file not modified");
-        return;
+        println!("{}", Outcome::DryRun.message());
+        print_summary(&hosts_parts);
+        return Outcome::DryRun;
     }
 
-    let mut file_hosts_new = OpenOptions::new()
-        .write(true)
-        .create_new(true)
-        .open(PATH_HOSTSFILE_NEW)
-        .expect("unable to open new hosts file for writing! Stale file from previous run?");
-
-    file_hosts_new
-        .write_all(buf_generate.as_bytes())
-        .expect("unable to write generated hosts file");
-    file_hosts_new
-        .set_len(buf_generate.as_bytes().len() as u64)
-        .expect("unable to truncate hosts file to right len");
-    file_hosts_new.flush().expect("unable to flush hosts file");
-    // close file handles
-    drop(file_hosts_new);
+    if symlink_refused {
+        eprintln!("{:?} {}", path_link, refusal);
+        return Outcome::SymlinkRefused;
+    }
+
+    // close file handle
     drop(file_hosts_orig);
-    rename(PATH_HOSTSFILE_NEW, PATH_HOSTSFILE).expect("unable to move new hosts file into place!");
+    if let Some(hook) = &config.pre_change_hook {
+        let status = run_hook(hook, Some(&buf_generate)).expect("unable to run pre_change_hook");
+        if !status.success() {
+            eprintln!(
+                "pre_change_hook rejected changes ({}), hosts file not modified",
+                status
+            );
+            return Outcome::HookRejected;
+        }
+    }
+    if config.backup {
+        let backup = create_backup(path, &str_content).expect("unable to back up hosts file");
+        if opts.verbose {
+            println!("backed up hosts file to {:?}", backup);
+        }
+    }
+    write_atomically(path, path_new, &buf_generate, config.file_mode)
+        .expect("unable to write hosts file");
+    if let Some(hook) = &config.post_change_hook {
+        match run_hook(hook, None) {
+            Ok(status) if status.success() => {}
+            Ok(status) => eprintln!("post_change_hook failed: {}", status),
+            Err(err) => eprintln!("{}", err),
+        }
+    }
+    if let (true, Some(keep)) = (config.backup, config.backup_keep) {
+        for backup in prune_backups(path, keep).expect("unable to remove old backups") {
+            if opts.verbose {
+                println!("removed old backup {:?}", backup);
+            }
+        }
+    }
+    if opts.verbose {
+        println!("{}", Outcome::Modified.message());
+    }
+    print_summary(&hosts_parts);
+    Outcome::Modified
 }
 
-fn trim_hosts_parts(hosts_parts: &mut Vec<HostsPart>) {
-    let trim = hosts_parts
+/// Sums up the changes from the parts of the `before` spans to `after` in a single line of
+/// `key=value` pairs, `unchanged` being the number of actions without effect.
+fn summary(
+    before: &[(Range<usize>, HostsPart)],
+    after: &[HostsPart],
+    unchanged: usize,
+    path: &Path,
+    dry_run: bool,
+) -> String {
+    let (mut added, mut removed, mut modified) = (0, 0, 0);
+    for change in diff_parts(before.iter().map(|(_, part)| part), after.iter()) {
+        match change {
+            HostsChange::Added(_) => added += 1,
+            HostsChange::Removed(_) => removed += 1,
+            HostsChange::Modified { .. } => modified += 1,
+        }
+    }
+    format!(
+        "hostsmod: added={} removed={} modified={} unchanged={} file={} dry_run={}",
+        added,
+        removed,
+        modified,
+        unchanged,
+        path.display(),
+        dry_run
+    )
+}
+
+/// Verifies the `generated` hosts file parses back into `parts`, catching anything the parser
+/// reads differently than it was rendered, eg. a hostname containing `#`.
+fn verify_round_trip(
+    parts: &[HostsPart],
+    generated: &str,
+    options: ParseOptions,
+) -> Result<(), String> {
+    let mut file_reparsed = HostsFile::from(try_parse_hosts_with(generated, options)?);
+    file_reparsed.trim_trailing_empty();
+    let mut file_intended = HostsFile::from(parts.to_vec());
+    file_intended.trim_trailing_empty();
+    let (reparsed, intended) = (file_reparsed.parts(), file_intended.parts());
+    if reparsed == intended {
+        return Ok(());
+    }
+    let idx = reparsed
         .iter()
-        .rev()
-        .take_while(|part| part.is_empty())
+        .zip(intended)
+        .take_while(|(part_reparsed, part_intended)| part_reparsed == part_intended)
         .count();
-    hosts_parts.truncate(hosts_parts.len() - trim);
+    Err(format!(
+        "line {} is {:?} instead of {:?}",
+        idx + 1,
+        reparsed.get(idx),
+        intended.get(idx)
+    ))
+}
+
+/// Replaces empty lines consisting of whitespace by truly blank ones.
+fn blank_whitespace_lines(hosts_parts: &mut [HostsPart]) {
+    for part in hosts_parts
+        .iter_mut()
+        .filter(|part| part.is_whitespace_only())
+    {
+        *part = HostsPart::Empty(Cow::Borrowed(""));
+    }
+}
+
+/// Removes every IP-hostname mapping of an active entry already present in an earlier active
+/// entry, dropping entries left without a hostname. Returns whether anything was removed.
+fn dedup_entries(hosts: &mut Vec<HostsPart>) -> bool {
+    let mut hosts_file = HostsFile::from(std::mem::take(hosts));
+    let changed = hosts_file.dedup_aliases();
+    *hosts = hosts_file.into_parts();
+    changed
+}
+
+/// Counts the active entries.
+fn count_entries(hosts: &[HostsPart]) -> usize {
+    hosts
+        .iter()
+        .filter(|part| matches!(part, HostsPart::Entry(..)))
+        .count()
+}
+
+/// Lists all active entries, one per line.
+fn list_entries(hosts: &[HostsPart]) -> String {
+    let mut buf = String::new();
+    for part in hosts {
+        if let HostsPart::Entry(ip, hosts, _) = part {
+            buf.push_str(&format!("{}\t{}\n", ip, hosts.join(" ")));
+        }
+    }
+    buf
+}
+
+/// Checks whether a mapping is present as an active entry.
+fn has_active_mapping(hosts: &[HostsPart], entry: &HostsEntry) -> bool {
+    hosts.iter().any(|part| {
+        matches!(part, HostsPart::Entry(..))
+            && part.matches_ip(&entry.ip)
+            && part.matches_hostname(&entry.hostname)
+    })
+}
+
+/// Reports every violated `ensure_present` and `ensure_absent` rule, without modifying anything.
+fn check_ensure_rules(hosts: &[HostsPart], config: &HostsmodConfig) -> Vec<String> {
+    let missing = config
+        .ensure_present
+        .iter()
+        .filter(|entry| !has_active_mapping(hosts, entry))
+        .map(|entry| format!("missing: {} {}", entry.ip, entry.hostname));
+    let present = config
+        .ensure_absent
+        .iter()
+        .filter(|entry| has_active_mapping(hosts, entry))
+        .map(|entry| format!("present: {} {}", entry.ip, entry.hostname));
+    missing.chain(present).collect()
+}
+
+/// Adds an entry for every mapping not present as an active entry, returns whether any was added.
+/// Admin-declared, so no whitelist applies.
+fn ensure_present(hosts: &mut Vec<HostsPart>, entries: &[HostsEntry]) -> bool {
+    let mut changed = false;
+    for entry in entries {
+        if !has_active_mapping(hosts, entry) {
+            hosts.push(HostsPart::Entry(
+                entry.ip,
+                vec![Cow::Owned(entry.hostname.to_string())],
+                None,
+            ));
+            changed = true;
+        }
+    }
+    changed
+}
+
+/// Removes every mapping from the active entries, dropping entries left without a hostname.
+/// Returns whether anything was removed. Admin-declared, so no whitelist applies.
+fn ensure_absent(hosts: &mut Vec<HostsPart>, entries: &[HostsEntry]) -> bool {
+    let mut changed = false;
+    for entry in entries {
+        for part in hosts
+            .iter_mut()
+            .filter(|part| matches!(part, HostsPart::Entry(..)) && part.matches_ip(&entry.ip))
+        {
+            changed |= part.remove_hostname(&entry.hostname);
+        }
+    }
+    hosts.retain(|part| !matches!(part, HostsPart::Entry(_, hosts, _) if hosts.is_empty()));
+    changed
 }
 
+/// Applies all actions to the parsed hosts file, returns the report of what each action did.
 fn perform_actions(
     opts: &mut opts::HostsArgs,
     hosts: &mut Vec<HostsPart>,
     config: &HostsmodConfig,
-) -> Result<(), String> {
-    'loop_actions: for action in &opts.actions {
-        match action {
-            Action::Define(ip, host) => {
-                if !config.whitelist.contains(host) {
-                    return Err(format!("HOST {:?} not whitelisted!", host));
-                }
-                // eprintln!("defining additionally...: {:?} += {:?}", ip, host);
-                let mut opt_insert = Some(hosts.len());
-                let mut host_found_v4 = false;
-                let mut host_found_v6 = false;
-                for (i, part) in hosts
-                    .iter_mut()
-                    .enumerate()
-                    .filter(|(_i, p)| p.matches_ip(ip) || p.matches_hostname(host))
-                {
-                    // eprintln!("matching entry: {:?}", part);
-                    let matches_hostname = part.matches_hostname(host);
-                    if part.matches_ip(ip) && matches_hostname {
-                        // eprintln!("already defined, NOP");
-                        //opt_insert = None;
-                        continue 'loop_actions;
-                    }
-                    if matches_hostname {
-                        match part.get_family() {
-                            Some(HostsPartFamily::IPv4) => {
-                                if host_found_v4 || ip.is_ipv4() {
-                                    return Err(format!(
-                                        "duplicate entry for host {:?} {:?}",
-                                        host,
-                                        HostsPartFamily::IPv4
-                                    ));
-                                }
-                                host_found_v4 = true;
-                            }
-                            Some(HostsPartFamily::IPv6) => {
-                                if host_found_v6 || ip.is_ipv6() {
-                                    return Err(format!(
-                                        "duplicate entry for host {:?} {:?}",
-                                        host,
-                                        HostsPartFamily::IPv6
-                                    ));
-                                }
-                                host_found_v6 = true;
-                            }
-                            None => {}
-                        };
-                    }
-                    if opt_insert.is_some() {
-                        opt_insert = Some(i + 1);
-                    }
-                }
-
-                if let Some(insert) = opt_insert {
-                    let insert = min(insert, hosts.len());
-                    hosts.insert(
-                        insert,
-                        HostsPart::Entry(ip.clone(), vec![Cow::Owned(host.clone())], None),
-                    );
-                }
-            }
-            Action::DefineExclusive(ip, host) => {
-                if !config.whitelist.contains(host) {
-                    return Err(format!("HOST {:?} not whitelisted!", host));
-                }
-                // eprintln!("defining exclusively...: {:?} += {:?}", ip, host);
-                let mut vec_remove = vec![];
-                for (i, _part) in hosts
-                    .iter()
-                    .enumerate()
-                    .filter(|(_i, p)| p.matches_hostname(host))
-                {
-                    // eprintln!("matching entry: {:?}", part);
-                    // if part.matches_ip(ip) && part.matches_hostname(host) {
-                    //     eprintln!("already defined, NOP");
-                    //     return;
-                    // }
-                    // insert = i + 1;
-                    vec_remove.push(i);
-                }
-                for remove in vec_remove.iter().rev() {
-                    hosts.remove(*remove);
-                }
-                let insert = vec_remove.into_iter().min().unwrap_or(hosts.len());
-                hosts.insert(
-                    insert,
-                    HostsPart::Entry(ip.clone(), vec![Cow::Owned(host.clone())], None),
-                );
-            }
-            Action::Remove(host) => {
-                if !config.whitelist.contains(host) {
-                    return Err(format!("HOST {:?} not whitelisted!", host));
-                }
-                let mut vec_remove = vec![];
-                let mut vec_insert = vec![];
-                let mut offset_remove = 0;
-                for (i, part) in hosts
-                    .iter()
-                    .enumerate()
-                    .filter(|(_i, p)| p.matches_hostname(host))
-                {
-                    match part {
-                        HostsPart::Entry(ip, hosts, opt_comment) => {
-                            // eprintln!("matching entry: {:?}", (&ip, &hosts, &opt_comment));
-                            if hosts.len() > 1 {
-                                let mut hosts_filtered = hosts.clone();
-                                hosts_filtered.retain(|ent| ent != host);
-                                vec_insert.push((
-                                    i,
-                                    HostsPart::Entry(
-                                        ip.clone(),
-                                        hosts_filtered,
-                                        opt_comment.clone(),
-                                    ),
-                                ));
-                                offset_remove += 1;
-                            }
-                            vec_remove.push(offset_remove + i);
-                            // for h in hosts {
-                            //     if h == host {
-                            //     }
-                            // }
-                        }
-                        _ => {}
-                    }
-                }
-                // dbg!(&vec_insert);
-                for (idx, part) in vec_insert {
-                    hosts.insert(idx, part);
-                }
-                // dbg!(&vec_remove);
-                // unimplemented!();
-                for remove in vec_remove.iter().rev() {
-                    hosts.remove(*remove);
-                }
-            }
+) -> Result<ApplyReport, String> {
+    let mut host_policy = config.clone();
+    // permission for `--allow` is verified by `check_allow` up front
+    host_policy.whitelist.extend(opts.allow.iter().cloned());
+    let policy = Policy {
+        // hostnames are checked by `host_policy`
+        whitelist: Default::default(),
+        add_only: Default::default(),
+        // reserved entries are verified by `run` after all modifications
+        reserved: vec![],
+        include_commented: opts.include_commented,
+        comment_on_remove: opts.comment_on_remove,
+        merge_same_ip: config.merge_same_ip,
+        insert_position: config.insert_position,
+        group_by_ip: config.group_by_ip,
+        preserve_comments: config.preserve_comments,
+        reject_special_ips: config.reject_special_ips,
+        allow_conflicting_actions: opts.allow_conflicting_actions,
+    };
+    let mut hosts_file = HostsFile::from(std::mem::take(hosts));
+    let result = hosts_file.apply_with(&opts.actions, &policy, &host_policy);
+    *hosts = hosts_file.into_parts();
+    let report = result.map_err(|err| err.to_string())?;
+    for warning in &report.warnings {
+        eprintln!("warning: {}", warning);
+    }
+    if opts.verbose {
+        for (idx, change) in &report.changes {
+            eprintln!("action {}: {:?}", idx + 1, change);
+        }
+        for idx in &report.unchanged {
+            eprintln!("action {}: already in effect, nothing to do", idx + 1);
         }
     }
-    Ok(())
+    Ok(report)
 }
 
-fn generate_hosts_file(len_content: usize, parsed: &Vec<HostsPart>) -> String {
+/// Renders the hosts file. Parts also present in the original file are copied from their `spans`
+/// in `original` instead, so untouched lines keep their exact formatting. Modified entries keep
+/// the original spelling of their IP address (eg. `2001:0db8::1`) if the file mapped it to one of
+/// their hostnames, anything else is written in canonical form. Likewise they keep separating the
+/// IP address from the hostnames with spaces if the original entry did, otherwise with a tab. Lines
+/// copied from the original keep their line break (`\n`, `\r\n` or `\n\r`), all others get the
+/// first line break of the original, `\n` if there is none.
+fn generate_hosts_file(
+    len_content: usize,
+    parsed: &Vec<HostsPart>,
+    original: &str,
+    spans: &[(Range<usize>, HostsPart)],
+) -> String {
     let mut buf_generate = String::with_capacity(len_content);
 
+    // the line break following each span, if any
+    let breaks: Vec<Option<&str>> = spans
+        .iter()
+        .zip(spans.iter().skip(1).map(Some).chain(std::iter::once(None)))
+        .map(|((span, _), next)| next.map(|(next, _)| &original[span.end..next.start]))
+        .collect();
+    let line_break = breaks.iter().flatten().next().copied().unwrap_or("\n");
+    // reversed, so popping takes equal parts in order of the original file
+    let mut originals: HashMap<&HostsPart, Vec<(&str, &str)>> = HashMap::new();
+    for ((span, part), opt_break) in spans.iter().zip(&breaks).rev() {
+        originals
+            .entry(part)
+            .or_default()
+            .push((&original[span.clone()], opt_break.unwrap_or(line_break)));
+    }
+    // original IP spelling and whether it was followed by spaces instead of a tab
+    let mut ip_texts: HashMap<(&IpAddr, &str), (&str, bool)> = HashMap::new();
+    for (span, part) in spans {
+        if let HostsPart::Entry(ip, hosts, _) | HostsPart::CommentedEntry(ip, hosts, ..) = part {
+            let line = original[span.clone()].trim_start_matches([' ', '\t', '#', ';']);
+            let text = line.split([' ', '\t', '#']).next().unwrap_or_default();
+            let spaces = line[text.len()..].starts_with(' ');
+            for host in hosts {
+                ip_texts.entry((ip, host)).or_insert((text, spaces));
+            }
+        }
+    }
+
     // eprintln!("rendering: {:?}", parsed);
 
     fn render_entry<'a>(
         buf_generate: &mut String,
+        ip_texts: &HashMap<(&IpAddr, &str), (&str, bool)>,
         ip: &IpAddr,
         hosts: &Vec<Cow<'a, str>>,
         opt_comment: &Option<Cow<'a, str>>,
     ) {
         use std::fmt::Write;
 
-        write!(buf_generate, "{:20}\t", ip).expect("unable to format entry IP address");
-        let max = hosts.iter().count() - 1;
+        match hosts
+            .iter()
+            .find_map(|host| ip_texts.get(&(ip, host.as_ref())))
+        {
+            Some((text, true)) => write!(buf_generate, "{:20} ", text),
+            Some((text, false)) => write!(buf_generate, "{:20}\t", text),
+            None => write!(buf_generate, "{:20}\t", ip),
+        }
+        .expect("unable to format entry IP address");
+        let max = hosts.len() - 1;
         for (i, host) in hosts.iter().enumerate() {
             write!(buf_generate, "{}{}", host, if i < max { " " } else { "" })
                 .expect("unable to format entry hostname");
@@ -465,24 +1091,1318 @@ fn generate_hosts_file(len_content: usize, parsed: &Vec<HostsPart>) -> String {
 
     for part in parsed {
         // eprintln!("rendering: {:?}", part);
+        if let Some((text, original_break)) = originals.get_mut(part).and_then(Vec::pop) {
+            buf_generate.push_str(text);
+            buf_generate.push_str(original_break);
+            continue;
+        }
         match part {
-            HostsPart::Empty(empty) => {
+            HostsPart::Empty(empty) | HostsPart::Raw(empty) => {
                 buf_generate.push_str(empty);
             }
-            HostsPart::Comment(comment) => {
-                buf_generate.push_str("#");
+            HostsPart::Comment(comment, marker, indent) => {
+                buf_generate.push_str(indent);
+                buf_generate.push(marker.as_char());
                 buf_generate.push_str(comment);
             }
-            HostsPart::CommentedEntry(ip, hosts, opt_comment) => {
-                buf_generate.push_str("# ");
-                render_entry(&mut buf_generate, ip, hosts, opt_comment)
+            HostsPart::CommentedEntry(ip, hosts, opt_comment, marker, indent) => {
+                buf_generate.push_str(indent);
+                buf_generate.push(marker.as_char());
+                buf_generate.push(' ');
+                render_entry(&mut buf_generate, &ip_texts, ip, hosts, opt_comment)
             }
             HostsPart::Entry(ip, hosts, opt_comment) => {
-                render_entry(&mut buf_generate, ip, hosts, opt_comment)
+                render_entry(&mut buf_generate, &ip_texts, ip, hosts, opt_comment)
             }
         }
-        buf_generate.push_str("\n");
+        buf_generate.push_str(line_break);
     }
     // buf_generate.pop();
     buf_generate
 }
+
+/// Renders the changes from the parts of the `before` spans to `after` as lines prefixed by `+` or
+/// `-`, a modified entry as removal and addition. Lines present in the `original` file are shown
+/// verbatim.
+fn changed_lines(
+    before: &[(Range<usize>, HostsPart)],
+    after: &[HostsPart],
+    original: &str,
+    spans: &[(Range<usize>, HostsPart)],
+) -> String {
+    let render = |part: &HostsPart| generate_hosts_file(0, &vec![part.clone()], original, spans);
+    let mut buf = String::new();
+    for change in diff_parts(before.iter().map(|(_, part)| part), after.iter()) {
+        match change {
+            HostsChange::Added(part) => buf.push_str(&format!("+{}", render(&part))),
+            HostsChange::Removed(part) => buf.push_str(&format!("-{}", render(&part))),
+            HostsChange::Modified { before, after } => {
+                buf.push_str(&format!("-{}", render(&before)));
+                buf.push_str(&format!("+{}", render(&after)));
+            }
+        }
+    }
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::action::{Action, InsertPosition};
+    use crate::config::HostsmodConfig;
+    use crate::file::HostsFile;
+    use crate::opts::HostsArgs;
+    use crate::parse::{
+        try_parse_hosts, try_parse_hosts_with_spans, try_parse_spans_with, CommentMarker,
+        HostsPart, ParseOptions,
+    };
+    use crate::persist::tests::temp_dir;
+    use crate::reserved::HostsEntry;
+    use crate::{
+        blank_whitespace_lines, changed_lines, check_allow, check_ensure_rules, dedup_entries,
+        effective_config, ensure_absent, ensure_present, generate_hosts_file, init,
+        is_system_hosts_file, lacks_privilege, list_entries, load_config, perform_actions,
+        privilege_summary, run, setuid_user_file, summary, verify_round_trip,
+        writable_by_real_user, Outcome, EXIT_DRY_RUN, EXIT_HOOK_REJECTED, EXIT_MAX_ENTRIES,
+        EXIT_ROUND_TRIP, EXIT_SYMLINK,
+    };
+    use std::fs;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+    use std::path::Path;
+
+    fn parse_trimmed(data: &str) -> Vec<HostsPart<'_>> {
+        let mut file = HostsFile::parse(data).unwrap();
+        file.trim_trailing_empty();
+        file.into_parts()
+    }
+
+    fn args(actions: Vec<Action>) -> HostsArgs {
+        HostsArgs {
+            dry_run: false,
+            verbose: false,
+            generate_sample_config: false,
+            include_commented: false,
+            comment_on_remove: false,
+            lenient: false,
+            normalize: false,
+            tidy: false,
+            follow_symlinks: false,
+            rollback: false,
+            check: false,
+            validate: false,
+            validate_config: false,
+            show_config: false,
+            init: false,
+            allow_conflicting_actions: false,
+            changed_only: false,
+            summary: false,
+            allow: vec![],
+            file: None,
+            actions,
+            command: None,
+        }
+    }
+
+    fn config(whitelist: &[&str]) -> HostsmodConfig {
+        let mut cfg = HostsmodConfig::default();
+        cfg.whitelist
+            .extend(whitelist.iter().map(|host| host.to_string()));
+        cfg
+    }
+
+    #[test]
+    fn test_noop_actions_unchanged() {
+        let data = "127.0.0.1\tlocalhost\n10.0.0.1\tsomehost\n";
+        let cfg = config(&["somehost", "otherhost"]);
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let mut hosts = try_parse_hosts(data).unwrap();
+        let orig = hosts.clone();
+        let mut opts = args(vec![
+            Action::Define(ip, "somehost".into()),
+            Action::DefineExclusive(ip, "somehost".into()),
+            Action::Remove("otherhost".into()),
+        ]);
+        assert!(
+            !perform_actions(&mut opts, &mut hosts, &cfg)
+                .unwrap()
+                .changed
+        );
+        assert_eq!(orig, hosts);
+
+        let mut opts = args(vec![Action::Define(ip, "otherhost".into())]);
+        assert!(
+            perform_actions(&mut opts, &mut hosts, &cfg)
+                .unwrap()
+                .changed
+        );
+        assert_ne!(orig, hosts);
+    }
+
+    #[test]
+    fn test_trim_and_blank_whitespace_lines() {
+        let data = "  \n127.0.0.1\tlocalhost\n\t\n\n \n";
+        let mut hosts = parse_trimmed(data);
+        assert_eq!(2, hosts.len());
+        assert!(hosts[0].is_whitespace_only());
+        blank_whitespace_lines(&mut hosts);
+        assert!(hosts[0].is_blank());
+    }
+
+    #[test]
+    fn test_insert_position() {
+        let data = "127.0.0.1\tlocalhost\n10.0.0.1\tsomehost\n";
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+        let mut cfg = config(&["otherhost"]);
+        for (position, idx) in &[(InsertPosition::Start, 0), (InsertPosition::End, 2)] {
+            cfg.insert_position = *position;
+            let mut hosts = parse_trimmed(data);
+            let mut opts = args(vec![Action::Define(ip, "otherhost".into())]);
+            assert!(
+                perform_actions(&mut opts, &mut hosts, &cfg)
+                    .unwrap()
+                    .changed
+            );
+            assert_eq!(3, hosts.len());
+            assert!(
+                hosts[*idx].matches_hostname("otherhost"),
+                "{:?}: {:?}",
+                position,
+                hosts
+            );
+        }
+
+        // a related entry anchors new ones regardless of the position
+        let ip6 = IpAddr::V6(Ipv6Addr::LOCALHOST);
+        let data = format!("{}10.0.0.9\tlast.example\n", data);
+        let mut cfg = config(&["somehost"]);
+        for position in &[
+            InsertPosition::NearMatch,
+            InsertPosition::End,
+            InsertPosition::Start,
+        ] {
+            cfg.insert_position = *position;
+            let mut hosts = parse_trimmed(&data);
+            let mut opts = args(vec![Action::Define(ip6, "somehost".into())]);
+            perform_actions(&mut opts, &mut hosts, &cfg).unwrap();
+            assert_eq!(
+                HostsPart::Entry(ip6, vec!["somehost".into()], None),
+                hosts[2],
+                "{:?}: {:?}",
+                position,
+                hosts
+            );
+        }
+    }
+
+    #[test]
+    fn test_merge_same_ip() {
+        let data = "127.0.0.1\tlocalhost\n10.0.0.1\tsomehost\n::1\tlocalhost\n";
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let mut cfg = config(&["somehost", "otherhost"]);
+        cfg.merge_same_ip = true;
+        let mut hosts = parse_trimmed(data);
+        let mut opts = args(vec![Action::Define(ip, "otherhost".into())]);
+        assert!(
+            perform_actions(&mut opts, &mut hosts, &cfg)
+                .unwrap()
+                .changed
+        );
+        assert_eq!(3, hosts.len());
+        assert_eq!(
+            HostsPart::Entry(ip, vec!["somehost".into(), "otherhost".into()], None),
+            hosts[1]
+        );
+
+        // duplicate family checks still apply
+        let mut opts = args(vec![Action::Define(
+            IpAddr::V4(Ipv4Addr::new(10, 0, 0, 9)),
+            "otherhost".into(),
+        )]);
+        assert!(perform_actions(&mut opts, &mut hosts, &cfg).is_err());
+    }
+
+    #[test]
+    fn test_remove_include_commented() {
+        let data = r##"10.0.20.4	intranet.someclub.example deactivated.host
+# 10.4.79.99	deactivated.host deactivated.host.1
+# 10.4.79.98	deactivated.host
+"##;
+        let cfg = config(&["deactivated.host"]);
+        let mut hosts = parse_trimmed(data);
+        let mut opts = args(vec![Action::Remove("deactivated.host".into())]);
+        assert!(
+            perform_actions(&mut opts, &mut hosts, &cfg)
+                .unwrap()
+                .changed
+        );
+        assert_eq!(3, hosts.len());
+        assert!(hosts[1].matches_hostname("deactivated.host"));
+
+        let mut hosts = parse_trimmed(data);
+        opts.include_commented = true;
+        assert!(
+            perform_actions(&mut opts, &mut hosts, &cfg)
+                .unwrap()
+                .changed
+        );
+        assert_eq!(
+            vec![
+                HostsPart::Entry(
+                    IpAddr::V4(Ipv4Addr::new(10, 0, 20, 4)),
+                    vec!["intranet.someclub.example".into()],
+                    None
+                ),
+                HostsPart::CommentedEntry(
+                    IpAddr::V4(Ipv4Addr::new(10, 4, 79, 99)),
+                    vec!["deactivated.host.1".into()],
+                    None,
+                    CommentMarker::Hash,
+                    "".into()
+                ),
+            ],
+            hosts
+        );
+    }
+
+    #[test]
+    fn test_add_only() {
+        let data = "10.0.0.1\tother.example\n";
+        let mut cfg = config(&["other.example"]);
+        cfg.add_only.insert("stable.example".into());
+        let mut hosts = parse_trimmed(data);
+
+        let mut opts = args(vec![Action::Define(
+            IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)),
+            "stable.example".into(),
+        )]);
+        assert!(perform_actions(&mut opts, &mut hosts, &cfg).is_ok());
+        assert!(hosts.iter().any(
+            |part| part.matches_ip(&IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)))
+                && part.matches_hostname("stable.example")
+        ));
+
+        let hosts_defined = hosts.clone();
+        let mut opts = args(vec![Action::Remove("stable.example".into())]);
+        assert!(perform_actions(&mut opts, &mut hosts, &cfg).is_err());
+        let mut opts = args(vec![Action::Remove("*.example".into())]);
+        cfg.whitelist.insert("*.example".into());
+        assert!(perform_actions(&mut opts, &mut hosts, &cfg).is_err());
+        assert_eq!(hosts_defined, hosts);
+    }
+
+    #[test]
+    fn test_allow() {
+        let data = "10.0.0.1\tother.example\n";
+        let mut cfg = config(&["other.example"]);
+        let mut hosts = parse_trimmed(data);
+        let define = Action::Define(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)), "new.example".into());
+
+        let mut opts = args(vec![define.clone()]);
+        assert!(perform_actions(&mut opts, &mut hosts, &cfg).is_err());
+
+        let mut opts = args(vec![define]);
+        opts.allow.push("new.example".into());
+        assert!(check_allow(&opts, &cfg, 1000).is_err());
+        assert!(check_allow(&opts, &cfg, 0).is_ok());
+        assert!(perform_actions(&mut opts, &mut hosts, &cfg).is_ok());
+        assert!(hosts
+            .iter()
+            .any(|part| part.matches_hostname("new.example")));
+        assert!(!cfg.whitelist.contains("new.example"));
+
+        cfg.allow_runtime_whitelist = true;
+        assert!(check_allow(&opts, &cfg, 1000).is_ok());
+    }
+
+    #[test]
+    fn test_preserve_comments() {
+        let data = r##"10.0.20.4	intranet.someclub.example deactivated.host
+# 10.4.79.99	deactivated.host deactivated.host.1
+"##;
+        let mut cfg = config(&["deactivated.host", "intranet.someclub.example"]);
+        cfg.preserve_comments = true;
+        let mut hosts = parse_trimmed(data);
+        let hosts_orig = hosts.clone();
+        let mut opts = args(vec![Action::Remove("deactivated.host".into())]);
+        opts.include_commented = true;
+        assert!(perform_actions(&mut opts, &mut hosts, &cfg).is_err());
+        assert_eq!(hosts_orig, hosts);
+
+        // no commented-out entry affected
+        let mut hosts_active = hosts.clone();
+        let mut opts = args(vec![Action::Remove("intranet.someclub.example".into())]);
+        opts.include_commented = true;
+        assert!(
+            perform_actions(&mut opts, &mut hosts_active, &cfg)
+                .unwrap()
+                .changed
+        );
+        assert_eq!(hosts_orig[1], hosts_active[1]);
+
+        let mut opts = args(vec![Action::DefineExclusive(
+            IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+            "deactivated.host".into(),
+        )]);
+        assert!(
+            perform_actions(&mut opts, &mut hosts, &cfg)
+                .unwrap()
+                .changed
+        );
+        assert!(hosts.contains(&hosts_orig[1]));
+    }
+
+    #[test]
+    fn test_ensure_present() {
+        let data = "127.0.0.1\tlocalhost\n# 10.0.0.5\trequired.example\n";
+        let entries = vec![
+            HostsEntry {
+                ip: IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+                hostname: "localhost".into(),
+            },
+            HostsEntry {
+                ip: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 5)),
+                hostname: "required.example".into(),
+            },
+        ];
+        let mut hosts = parse_trimmed(data);
+        assert!(ensure_present(&mut hosts, &entries));
+        assert_eq!(3, hosts.len());
+        assert_eq!(
+            HostsPart::Entry(entries[1].ip, vec!["required.example".into()], None),
+            hosts[2]
+        );
+        assert!(!ensure_present(&mut hosts, &entries));
+    }
+
+    #[test]
+    fn test_ensure_absent() {
+        let data =
+            "127.0.0.1\tlocalhost\n10.6.6.6\tevil.example good.example\n10.6.6.7\tevil.example\n";
+        let entries = vec![
+            HostsEntry {
+                ip: IpAddr::V4(Ipv4Addr::new(10, 6, 6, 6)),
+                hostname: "evil.example".into(),
+            },
+            HostsEntry {
+                ip: IpAddr::V4(Ipv4Addr::new(10, 6, 6, 7)),
+                hostname: "evil.example".into(),
+            },
+        ];
+        let mut hosts = parse_trimmed(data);
+        assert!(ensure_absent(&mut hosts, &entries));
+        assert_eq!(2, hosts.len());
+        assert_eq!(
+            HostsPart::Entry(entries[0].ip, vec!["good.example".into()], None),
+            hosts[1]
+        );
+
+        // already absent
+        let orig = hosts.clone();
+        assert!(!ensure_absent(&mut hosts, &entries));
+        assert_eq!(orig, hosts);
+    }
+
+    #[test]
+    fn test_check_ensure_rules() {
+        let data = "127.0.0.1\tlocalhost\n# 10.0.0.5\trequired.example\n10.6.6.6\tevil.example\n";
+        let mut cfg = config(&[]);
+        cfg.ensure_present.push(HostsEntry {
+            ip: IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+            hostname: "localhost".into(),
+        });
+        cfg.ensure_absent.push(HostsEntry {
+            ip: IpAddr::V4(Ipv4Addr::new(10, 6, 6, 7)),
+            hostname: "evil.example".into(),
+        });
+        let hosts = try_parse_hosts(data).unwrap();
+        assert!(check_ensure_rules(&hosts, &cfg).is_empty());
+
+        cfg.ensure_present.push(HostsEntry {
+            ip: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 5)),
+            hostname: "required.example".into(),
+        });
+        cfg.ensure_absent.push(HostsEntry {
+            ip: IpAddr::V4(Ipv4Addr::new(10, 6, 6, 6)),
+            hostname: "evil.example".into(),
+        });
+        assert_eq!(
+            vec![
+                "missing: 10.0.0.5 required.example".to_string(),
+                "present: 10.6.6.6 evil.example".to_string(),
+            ],
+            check_ensure_rules(&hosts, &cfg)
+        );
+    }
+
+    #[test]
+    fn test_list_entries() {
+        let data =
+            "127.0.0.1\tlocalhost\n# 10.0.0.5\tdisabled.example\n::1  localhost  ip6-localhost\n";
+        let hosts = try_parse_hosts(data).unwrap();
+        assert_eq!(
+            "127.0.0.1\tlocalhost\n::1\tlocalhost ip6-localhost\n",
+            list_entries(&hosts)
+        );
+    }
+
+    #[test]
+    fn test_post_change_hook() {
+        let dir = temp_dir("post_change_hook");
+        let path = dir.join("hosts");
+        let path_marker = dir.join("marker");
+        fs::write(&path, "127.0.0.1\tlocalhost\n").unwrap();
+        let mut cfg = config(&["somehost"]);
+        cfg.post_change_hook = Some(format!("echo run >> {:?}", path_marker));
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+
+        for _ in 0..2 {
+            let mut opts = args(vec![Action::Define(ip, "somehost".into())]);
+            run(&mut opts, &cfg, "testhost", None, &path);
+            assert_eq!("run\n", fs::read_to_string(&path_marker).unwrap());
+        }
+        assert!(fs::read_to_string(&path).unwrap().contains("somehost"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_pre_change_hook() {
+        let dir = temp_dir("pre_change_hook");
+        let path = dir.join("hosts");
+        let path_new = dir.join("hosts.new");
+        let path_marker = dir.join("marker");
+        let orig = "127.0.0.1\tlocalhost\n";
+        fs::write(&path, orig).unwrap();
+        let mut cfg = config(&["somehost"]);
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+
+        cfg.pre_change_hook = Some(format!("cat > {:?}; exit 3", path_marker));
+        let mut opts = args(vec![Action::Define(ip, "somehost".into())]);
+        let outcome = run(&mut opts, &cfg, "testhost", None, &path);
+        assert_eq!(Outcome::HookRejected, outcome);
+        assert_eq!(EXIT_HOOK_REJECTED, outcome.exit_code());
+        assert_eq!(orig, fs::read_to_string(&path).unwrap());
+        assert!(!path_new.exists());
+        assert!(fs::read_to_string(&path_marker)
+            .unwrap()
+            .contains("somehost"));
+
+        cfg.pre_change_hook = Some("grep -q somehost".into());
+        run(&mut opts, &cfg, "testhost", None, &path);
+        assert!(fs::read_to_string(&path).unwrap().contains("somehost"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_untouched_lines_byte_identical() {
+        let dir = temp_dir("untouched_lines");
+        let path = dir.join("hosts");
+        let orig = "127.0.0.1 localhost\n#   spaced   comment\n2001:0db8:0000::0001    v6.example   # note\n10.0.0.1\t\tsomehost   otherhost\n  \n10.0.0.9  last.example\n";
+        fs::write(&path, orig).unwrap();
+        let cfg = config(&["otherhost"]);
+        let mut opts = args(vec![Action::Remove("otherhost".into())]);
+        run(&mut opts, &cfg, "testhost", None, &path);
+        let generated = fs::read_to_string(&path).unwrap();
+        let lines_orig: Vec<&str> = orig.lines().collect();
+        let lines: Vec<&str> = generated.lines().collect();
+        assert_eq!(lines_orig.len(), lines.len());
+        for (idx, (line_orig, line)) in lines_orig.iter().zip(lines.iter()).enumerate() {
+            if idx == 3 {
+                assert!(line.starts_with("10.0.0.1") && line.ends_with("\tsomehost"));
+            } else {
+                assert_eq!(line_orig, line);
+            }
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_indented_comment_round_trip() {
+        let data = "    # note\n\t# 10.0.0.1\tsomehost\n";
+        let mut parts = parse_trimmed(data);
+        assert_eq!(
+            HostsPart::Comment(" note".into(), CommentMarker::Hash, "    ".into()),
+            parts[0]
+        );
+        parts.push(HostsPart::Comment(
+            " new".into(),
+            CommentMarker::Hash,
+            "".into(),
+        ));
+        // no spans, so every part is rendered anew
+        let generated = generate_hosts_file(data.len(), &parts, data, &[]);
+        let lines: Vec<&str> = generated.lines().collect();
+        assert_eq!("    # note", lines[0]);
+        assert!(lines[1].starts_with("\t# 10.0.0.1") && lines[1].ends_with("\tsomehost"));
+        assert_eq!("# new", lines[2]);
+    }
+
+    #[test]
+    fn test_multi_hash_comment_round_trip() {
+        let data = "### header\n## 10.0.0.1\tsomehost\n";
+        let parts = parse_trimmed(data);
+        assert_eq!(
+            HostsPart::Comment("## header".into(), CommentMarker::Hash, "".into()),
+            parts[0]
+        );
+        let generated = generate_hosts_file(data.len(), &parts, data, &[]);
+        assert_eq!(data, generated);
+    }
+
+    #[test]
+    fn test_load_config_missing() {
+        let dir = temp_dir("load_config_missing");
+        let path = dir.join("hostsmod.yaml");
+        let err = load_config(&path).unwrap_err();
+        assert!(err.contains("not found"), "{}", err);
+        assert!(err.contains("hostsmod --sample-config >"), "{}", err);
+
+        fs::write(&path, "whitelist: {{").unwrap();
+        let err = load_config(&path).unwrap_err();
+        assert!(err.starts_with("unable to parse configuration"), "{}", err);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_ip_text_round_trip() {
+        let data = "2001:0db8:0000::0001    v6.example other.example\n2001:0db8::2 moved.example\n";
+        let spans = try_parse_hosts_with_spans(data).unwrap();
+        let mut parts: Vec<HostsPart> = spans.iter().map(|(_, part)| part.clone()).collect();
+        parts[0].remove_hostname("other.example");
+        parts[1].set_ip("2001:db8::3".parse().unwrap());
+        parts.insert(
+            2,
+            HostsPart::Entry(
+                "2001:db8::1".parse().unwrap(),
+                vec!["new.example".into()],
+                None,
+            ),
+        );
+        let generated = generate_hosts_file(data.len(), &parts, data, &spans);
+        let lines: Vec<&str> = generated.lines().collect();
+        assert!(lines[0].starts_with("2001:0db8:0000::0001 "));
+        assert!(lines[0].ends_with(" v6.example"));
+        assert!(lines[1].starts_with("2001:db8::3 "));
+        assert!(lines[2].starts_with("2001:db8::1 "));
+    }
+
+    #[test]
+    fn test_effective_config() {
+        let mut cfg = config(&["somehost"]);
+        cfg.backup = true;
+        let mut opts = args(vec![]);
+        opts.allow.push("runtime.example".into());
+        let shown: HostsmodConfig = serde_yaml::from_str(&effective_config(&opts, &cfg)).unwrap();
+        assert!(shown.whitelist.contains("somehost"));
+        assert!(shown.whitelist.contains("runtime.example"));
+        assert!(shown.backup);
+        assert!(!cfg.whitelist.contains("runtime.example"));
+        assert!(effective_config(&opts, &cfg).contains("enable_dangerous_operations: false"));
+    }
+
+    #[test]
+    fn test_init() {
+        let dir = temp_dir("init");
+        let path_config = dir.join("hostsmod.yaml");
+        let path_hosts = dir.join("hosts");
+        assert_eq!(
+            vec![path_config.clone(), path_hosts.clone()],
+            init(&path_config, &path_hosts, "testhost").unwrap()
+        );
+        assert!(load_config(&path_config).unwrap().whitelist.is_empty());
+        assert_eq!(
+            "127.0.0.1\tlocalhost\n127.0.1.1\ttesthost\n::1\tlocalhost ip6-localhost ip6-loopback\nff02::1\tip6-allnodes\nff02::2\tip6-allrouters\n",
+            fs::read_to_string(&path_hosts).unwrap()
+        );
+
+        // never overwrites
+        fs::write(&path_hosts, "10.0.0.1\tsomehost\n").unwrap();
+        assert!(init(&path_config, &path_hosts, "testhost")
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            "10.0.0.1\tsomehost\n",
+            fs::read_to_string(&path_hosts).unwrap()
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_repeated_run_stable() {
+        let dir = temp_dir("repeated_run_stable");
+        let path = dir.join("hosts");
+        let orig = "127.0.0.1\tlocalhost\n\n\n# dev\n10.0.0.1\tsomehost otherhost\n\n";
+        let mut cfg = config(&["somehost", "otherhost", "new.example"]);
+        cfg.merge_same_ip = true;
+        cfg.insert_position = InsertPosition::NearMatch;
+        cfg.blank_whitespace_lines = true;
+        let actions = vec![
+            Action::Define(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)), "new.example".into()),
+            Action::DefineExclusive(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 3)), "somehost".into()),
+            Action::Remove("otherhost".into()),
+        ];
+        for sort_on_write in [false, true] {
+            fs::write(&path, orig).unwrap();
+            cfg.sort_on_write = sort_on_write;
+            run(&mut args(actions.clone()), &cfg, "testhost", None, &path);
+            let first = fs::read_to_string(&path).unwrap();
+            assert_ne!(orig, first);
+
+            let mut hosts = parse_trimmed(&first);
+            assert!(
+                !perform_actions(&mut args(actions.clone()), &mut hosts, &cfg)
+                    .unwrap()
+                    .changed
+            );
+            run(&mut args(actions.clone()), &cfg, "testhost", None, &path);
+            assert_eq!(first, fs::read_to_string(&path).unwrap());
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_privilege_summary() {
+        assert_eq!(
+            "real uid 1000, effective uid 0: running setuid root",
+            privilege_summary(1000, 0, false)
+        );
+        assert_eq!(
+            "real uid 1000, effective uid 1000: running unprivileged, dry-run forced due to lack of privilege",
+            privilege_summary(1000, 1000, true)
+        );
+        assert!(privilege_summary(0, 0, false).ends_with("running as root"));
+    }
+
+    #[test]
+    fn test_user_file() {
+        let dir = temp_dir("user_file");
+        let path = dir.join("hosts");
+        fs::write(&path, "127.0.0.1\tlocalhost\n").unwrap();
+        // created by the user running the tests
+        assert!(writable_by_real_user(&path));
+        assert!(!writable_by_real_user(&dir.join("missing").join("hosts")));
+        assert!(!is_system_hosts_file(&path));
+        assert!(is_system_hosts_file(Path::new("/etc/hosts")));
+        assert!(is_system_hosts_file(Path::new("/etc/../etc/hosts")));
+        // run setuid root, privileges are dropped for the user's file, anything else is refused
+        let unwritable = dir.join("missing").join("hosts");
+        assert_eq!(Ok(true), setuid_user_file(Some(&path), 1000, 0));
+        assert!(setuid_user_file(Some(&unwritable), 1000, 0)
+            .unwrap_err()
+            .contains("not writable"));
+        assert_eq!(Ok(false), setuid_user_file(None, 1000, 0));
+        assert_eq!(Ok(false), setuid_user_file(Some(&unwritable), 0, 0));
+        assert_eq!(Ok(false), setuid_user_file(Some(&unwritable), 1000, 1000));
+        assert!(!lacks_privilege(1000, true));
+        assert!(lacks_privilege(1000, false));
+        assert!(!lacks_privilege(0, false));
+
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let mut opts = args(vec![Action::Define(ip, "somehost".into())]);
+        opts.file = Some(path.clone());
+        opts.dry_run = lacks_privilege(1000, writable_by_real_user(&path));
+        let cfg = config(&["somehost"]);
+        assert_eq!(
+            Outcome::Modified,
+            run(&mut opts, &cfg, "testhost", None, &path)
+        );
+        assert!(fs::read_to_string(&path).unwrap().contains("somehost"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_changed_lines() {
+        let data =
+            "127.0.0.1\tlocalhost\n# keep\n10.0.0.1   somehost otherhost # note\n10.0.0.9\tlast\n";
+        let spans = try_parse_hosts_with_spans(data).unwrap();
+        let before: Vec<HostsPart> = spans.iter().map(|(_, part)| part.clone()).collect();
+        let mut after = before.clone();
+        after[2].remove_hostname("otherhost");
+        let changed = changed_lines(&spans, &after, data, &spans);
+        let lines: Vec<&str> = changed.lines().collect();
+        assert_eq!(2, lines.len(), "{}", changed);
+        assert_eq!("-10.0.0.1   somehost otherhost # note", lines[0]);
+        assert!(lines[1].starts_with("+10.0.0.1") && lines[1].ends_with(" somehost # note"));
+    }
+
+    #[test]
+    fn test_comment_on_remove() {
+        let data = "10.0.0.1\tsomehost # note\n10.0.0.2\tsomehost otherhost\n";
+        let spans = try_parse_hosts_with_spans(data).unwrap();
+        let cfg = config(&["somehost"]);
+        let mut opts = args(vec![Action::Remove("somehost".into())]);
+        opts.comment_on_remove = true;
+        let mut hosts: Vec<HostsPart> = spans.iter().map(|(_, part)| part.clone()).collect();
+        assert!(
+            perform_actions(&mut opts, &mut hosts, &cfg)
+                .unwrap()
+                .changed
+        );
+        assert_eq!(
+            format!(
+                "# {:20}\tsomehost # note\n{:20}\totherhost\n\n",
+                "10.0.0.1", "10.0.0.2"
+            ),
+            generate_hosts_file(data.len(), &hosts, data, &spans)
+        );
+        // already commented out, nothing left to remove
+        assert!(
+            !perform_actions(&mut opts, &mut hosts, &cfg)
+                .unwrap()
+                .changed
+        );
+    }
+
+    #[test]
+    fn test_separator_round_trip() {
+        let data = "10.0.0.1\tone.example alias1\n10.0.0.2 two.example alias2\n#10.0.0.3  three.example alias3\n";
+        let spans = try_parse_hosts_with_spans(data).unwrap();
+        let mut parts: Vec<HostsPart> = spans.iter().map(|(_, part)| part.clone()).collect();
+        parts[0].remove_hostname("alias1");
+        parts[1].remove_hostname("alias2");
+        parts[2].remove_hostname("alias3");
+        parts.insert(
+            3,
+            HostsPart::Entry(
+                "10.0.0.4".parse().unwrap(),
+                vec!["four.example".into()],
+                None,
+            ),
+        );
+        let generated = generate_hosts_file(data.len(), &parts, data, &spans);
+        let lines: Vec<&str> = generated.lines().collect();
+        assert_eq!(format!("{:20}\tone.example", "10.0.0.1"), lines[0]);
+        assert_eq!(format!("{:20} two.example", "10.0.0.2"), lines[1]);
+        assert!(lines[2].ends_with(&format!("{:20} three.example", "10.0.0.3")));
+        assert_eq!(format!("{:20}\tfour.example", "10.0.0.4"), lines[3]);
+    }
+
+    #[test]
+    fn test_line_break_round_trip() {
+        let data = "127.0.0.1\tlocalhost\n\r10.0.0.1\tsomehost otherhost\n\r# comment\n\r";
+        let spans = try_parse_hosts_with_spans(data).unwrap();
+        let mut parts: Vec<HostsPart> = spans.iter().map(|(_, part)| part.clone()).collect();
+        // the empty part following the last line break, trimmed by `run`
+        parts.pop();
+        let generated = generate_hosts_file(data.len(), &parts, data, &spans);
+        assert_eq!(data, generated);
+
+        parts[1].remove_hostname("otherhost");
+        parts.insert(
+            3,
+            HostsPart::Entry(
+                "10.0.0.2".parse().unwrap(),
+                vec!["new.example".into()],
+                None,
+            ),
+        );
+        let generated = generate_hosts_file(data.len(), &parts, data, &spans);
+        assert_eq!(
+            format!(
+                "127.0.0.1\tlocalhost\n\r{:20}\tsomehost\n\r# comment\n\r{:20}\tnew.example\n\r",
+                "10.0.0.1", "10.0.0.2"
+            ),
+            generated
+        );
+        // mixed line breaks are kept line by line
+        let data = "127.0.0.1\tlocalhost\r\n::1\tlocalhost\n";
+        let spans = try_parse_hosts_with_spans(data).unwrap();
+        let generated = generate_hosts_file(data.len(), &parse_trimmed(data), data, &spans);
+        assert_eq!(data, generated);
+    }
+
+    #[test]
+    fn test_verify_round_trip() {
+        let data = "127.0.0.1\tlocalhost\n# comment\n\n10.0.0.1\tsomehost # note\n";
+        let spans = try_parse_hosts_with_spans(data).unwrap();
+        let mut parts: Vec<HostsPart> = spans.iter().map(|(_, part)| part.clone()).collect();
+        let generated = generate_hosts_file(data.len(), &parts, data, &spans);
+        assert_eq!(
+            Ok(()),
+            verify_round_trip(&parts, &generated, ParseOptions::default())
+        );
+
+        parts[3].add_hostname("bad#host".into());
+        let generated = generate_hosts_file(data.len(), &parts, data, &spans);
+        assert!(
+            verify_round_trip(&parts, &generated, ParseOptions::default())
+                .unwrap_err()
+                .starts_with("line 4 ")
+        );
+    }
+
+    #[test]
+    fn test_refuse_unparseable() {
+        let dir = temp_dir("refuse_unparseable");
+        let path = dir.join("hosts");
+        let path_new = dir.join("hosts.new");
+        let orig = "127.0.0.1\tlocalhost\n";
+        fs::write(&path, orig).unwrap();
+        // actions reject such a hostname, the admin-declared ensure_present does not
+        let mut cfg = config(&[]);
+        cfg.ensure_present = vec![HostsEntry {
+            ip: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+            hostname: "bad#host".into(),
+        }];
+        let mut opts = args(vec![]);
+        let outcome = run(&mut opts, &cfg, "testhost", None, &path);
+        assert_eq!(Outcome::RoundTripFailed, outcome);
+        assert_eq!(EXIT_ROUND_TRIP, outcome.exit_code());
+        assert_eq!(orig, fs::read_to_string(&path).unwrap());
+        assert!(!path_new.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_raw_round_trip() {
+        let data = "10.0.0.1\tsomehost otherhost\n \t10.0.0.1 = odd \t\n";
+        let spans = try_parse_spans_with(
+            data,
+            ParseOptions {
+                lenient: true,
+                ..ParseOptions::default()
+            },
+        )
+        .unwrap();
+        let mut parts: Vec<HostsPart> = spans.iter().map(|(_, part)| part.clone()).collect();
+        assert_eq!(HostsPart::Raw(" \t10.0.0.1 = odd \t".into()), parts[1]);
+        parts[0].remove_hostname("otherhost");
+        let generated = generate_hosts_file(data.len(), &parts, data, &spans);
+        assert_eq!(
+            format!("{:20}\tsomehost\n \t10.0.0.1 = odd \t\n\n", "10.0.0.1"),
+            generated
+        );
+
+        // rendered verbatim even without an original
+        let raw = vec![HostsPart::Raw("\u{a0}odd;line#".into())];
+        assert_eq!("\u{a0}odd;line#\n", generate_hosts_file(0, &raw, "", &[]));
+    }
+
+    #[test]
+    fn test_lenient() {
+        let dir = temp_dir("lenient");
+        let path = dir.join("hosts");
+        let orig = "127.0.0.1\tlocalhost\n10.0.0.1 = oddball\n";
+        fs::write(&path, orig).unwrap();
+        let cfg = config(&["somehost"]);
+
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+        let mut opts = args(vec![Action::Define(ip, "somehost".into())]);
+        assert!(catch_unwind(AssertUnwindSafe(|| {
+            run(&mut opts, &cfg, "testhost", None, &path)
+        }))
+        .is_err());
+        assert_eq!(orig, fs::read_to_string(&path).unwrap());
+
+        opts.lenient = true;
+        run(&mut opts, &cfg, "testhost", None, &path);
+        assert_eq!(
+            format!("{}{:20}\tsomehost\n", orig, "10.0.0.2"),
+            fs::read_to_string(&path).unwrap()
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_summary() {
+        let data = "127.0.0.1\tlocalhost\n10.0.0.1\tsomehost alias\n10.0.0.2\totherhost\n";
+        let spans = try_parse_hosts_with_spans(data).unwrap();
+        let mut hosts = parse_trimmed(data);
+        let before = &spans[..hosts.len()];
+        let cfg = config(&["somehost", "alias", "otherhost", "new.example"]);
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 3));
+        let mut opts = args(vec![
+            Action::Define(ip, "new.example".into()),
+            Action::Define(ip, "new.example".into()),
+            Action::Remove("otherhost".into()),
+            Action::Remove("alias".into()),
+        ]);
+        let report = perform_actions(&mut opts, &mut hosts, &cfg).unwrap();
+        assert_eq!(
+            "hostsmod: added=1 removed=1 modified=1 unchanged=1 file=/etc/hosts dry_run=true",
+            summary(
+                before,
+                &hosts,
+                report.unchanged.len(),
+                Path::new("/etc/hosts"),
+                true
+            )
+        );
+        assert_eq!(
+            "hostsmod: added=0 removed=0 modified=0 unchanged=0 file=hosts dry_run=false",
+            summary(before, &parse_trimmed(data), 0, Path::new("hosts"), false)
+        );
+    }
+
+    #[test]
+    fn test_outcome() {
+        let dir = temp_dir("outcome");
+        let path = dir.join("hosts");
+        let orig = "127.0.0.1\tlocalhost\n10.0.0.1\tsomehost\n";
+        fs::write(&path, orig).unwrap();
+        let cfg = config(&["somehost", "otherhost"]);
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+
+        // redundant, with and without dry run
+        let mut opts = args(vec![Action::Define(ip, "somehost".into())]);
+        assert_eq!(
+            Outcome::Unchanged,
+            run(&mut opts, &cfg, "testhost", None, &path)
+        );
+        opts.dry_run = true;
+        assert_eq!(
+            Outcome::Unchanged,
+            run(&mut opts, &cfg, "testhost", None, &path)
+        );
+
+        let mut opts = args(vec![Action::Define(ip, "otherhost".into())]);
+        opts.dry_run = true;
+        assert_eq!(
+            Outcome::DryRun,
+            run(&mut opts, &cfg, "testhost", None, &path)
+        );
+        assert_eq!(orig, fs::read_to_string(&path).unwrap());
+        opts.dry_run = false;
+        assert_eq!(
+            Outcome::Modified,
+            run(&mut opts, &cfg, "testhost", None, &path)
+        );
+
+        assert_eq!(0, Outcome::Unchanged.exit_code());
+        assert_eq!(0, Outcome::Modified.exit_code());
+        assert_eq!(EXIT_DRY_RUN, Outcome::DryRun.exit_code());
+        assert_ne!(Outcome::Unchanged.message(), Outcome::DryRun.message());
+        assert!(Outcome::Unchanged
+            .message()
+            .starts_with("no changes needed"));
+        assert!(Outcome::DryRun.message().starts_with("dry run"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_normalize() {
+        let dir = temp_dir("normalize");
+        let path = dir.join("hosts");
+        let orig = "  # hosts  \r\n2001:0db8::0001   v6.example\r\n\r\n\r\n127.0.0.1 localhost localhost\r\n";
+        fs::write(&path, orig).unwrap();
+        let cfg = config(&[]);
+        let mut opts = args(vec![]);
+        opts.normalize = true;
+        assert_eq!(
+            Outcome::Modified,
+            run(&mut opts, &cfg, "testhost", None, &path)
+        );
+        assert_eq!(
+            format!(
+                "{:20}\tlocalhost\n# hosts\n{:20}\tv6.example\n",
+                "127.0.0.1", "2001:db8::1"
+            ),
+            fs::read_to_string(&path).unwrap()
+        );
+        assert_eq!(
+            Outcome::Unchanged,
+            run(&mut opts, &cfg, "testhost", None, &path)
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_temp_suffix() {
+        let dir = temp_dir("temp_suffix");
+        let path = dir.join("hosts");
+        let path_staging = dir.join("hosts.staging");
+        let orig = "127.0.0.1\tlocalhost\n";
+        fs::write(&path, orig).unwrap();
+        let mut cfg = config(&["somehost"]);
+        cfg.temp_suffix = Some(".staging".into());
+
+        // a stale temp file is never overwritten, so the write fails
+        fs::write(&path_staging, "stale").unwrap();
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let mut opts = args(vec![Action::Define(ip, "somehost".into())]);
+        assert!(catch_unwind(AssertUnwindSafe(|| {
+            run(&mut opts, &cfg, "testhost", None, &path)
+        }))
+        .is_err());
+        assert_eq!(orig, fs::read_to_string(&path).unwrap());
+        assert_eq!("stale", fs::read_to_string(&path_staging).unwrap());
+
+        fs::remove_file(&path_staging).unwrap();
+        run(&mut opts, &cfg, "testhost", None, &path);
+        assert!(fs::read_to_string(&path).unwrap().contains("somehost"));
+        assert!(!path_staging.exists());
+        assert!(!dir.join("hosts.new").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_file_mode() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = temp_dir("file_mode");
+        let path = dir.join("hosts");
+        fs::write(&path, "127.0.0.1\tlocalhost\n").unwrap();
+        let mut cfg: HostsmodConfig =
+            serde_yaml::from_str("whitelist: []\nfile_mode: 0o600\n").unwrap();
+        cfg.whitelist.insert("somehost".into());
+
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let mut opts = args(vec![Action::Define(ip, "somehost".into())]);
+        run(&mut opts, &cfg, "testhost", None, &path);
+        let metadata = fs::metadata(&path).unwrap();
+        assert_eq!(0o600, metadata.permissions().mode() & 0o7777);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_follow_symlinks() {
+        let dir = temp_dir("follow_symlinks");
+        fs::create_dir(dir.join("real")).unwrap();
+        let path_real = dir.join("real").join("hosts");
+        let path = dir.join("hosts");
+        let path_new = dir.join("hosts.new");
+        let orig = "127.0.0.1\tlocalhost\n";
+        fs::write(&path_real, orig).unwrap();
+        std::os::unix::fs::symlink(&path_real, &path).unwrap();
+        let cfg = config(&["somehost"]);
+
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let mut opts = args(vec![Action::Define(ip, "somehost".into())]);
+        let outcome = run(&mut opts, &cfg, "testhost", None, &path);
+        assert_eq!(Outcome::SymlinkRefused, outcome);
+        assert_eq!(EXIT_SYMLINK, outcome.exit_code());
+        assert_eq!(orig, fs::read_to_string(&path_real).unwrap());
+        opts.rollback = true;
+        assert_eq!(
+            Outcome::SymlinkRefused,
+            run(&mut opts, &cfg, "testhost", None, &path)
+        );
+        opts.rollback = false;
+
+        opts.follow_symlinks = true;
+        run(&mut opts, &cfg, "testhost", None, &path);
+        assert!(fs::symlink_metadata(&path)
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        assert!(fs::read_to_string(&path_real).unwrap().contains("somehost"));
+        assert!(!path_new.exists());
+        assert!(!dir.join("real").join("hosts.new").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_max_entries() {
+        let dir = temp_dir("max_entries");
+        let path = dir.join("hosts");
+        let path_new = dir.join("hosts.new");
+        let orig = "127.0.0.1\tlocalhost\n10.0.0.1\tone.example\n";
+        fs::write(&path, orig).unwrap();
+        let mut cfg = config(&["one.example", "two.example"]);
+        cfg.max_entries = Some(2);
+
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+        let mut opts = args(vec![Action::Define(ip, "two.example".into())]);
+        let outcome = run(&mut opts, &cfg, "testhost", None, &path);
+        assert_eq!(Outcome::TooManyEntries, outcome);
+        assert_eq!(EXIT_MAX_ENTRIES, outcome.exit_code());
+        assert_eq!(orig, fs::read_to_string(&path).unwrap());
+        assert!(!path_new.exists());
+
+        // shrinking is fine even if still above the limit
+        cfg.max_entries = Some(1);
+        let mut opts = args(vec![Action::Remove("one.example".into())]);
+        run(&mut opts, &cfg, "testhost", None, &path);
+        assert!(!fs::read_to_string(&path).unwrap().contains("one.example"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_remove_glob() {
+        let data = r##"127.0.0.1	localhost
+10.0.0.1	api.dev.example
+10.0.0.2	web.dev.example www.example
+10.0.0.3	db.dev.example
+10.0.0.4	dev.example
+"##;
+        let mut hosts = parse_trimmed(data);
+        let mut opts = args(vec![Action::Remove("*.dev.example".into())]);
+        let cfg = config(&["api.dev.example", "web.dev.example"]);
+        assert!(perform_actions(&mut opts, &mut hosts, &cfg).is_err());
+
+        let cfg = config(&["*.dev.example"]);
+        assert!(
+            perform_actions(&mut opts, &mut hosts, &cfg)
+                .unwrap()
+                .changed
+        );
+        assert_eq!(
+            "127.0.0.1\tlocalhost\n10.0.0.2\twww.example\n10.0.0.4\tdev.example\n",
+            list_entries(&hosts)
+        );
+
+        // the pattern matches ignoring case, as does the whitelist
+        let mut hosts = parse_trimmed("10.0.0.1\tAPI.Dev.example\n10.0.0.4\tdev.example\n");
+        let mut opts = args(vec![Action::Remove("*.DEV.example".into())]);
+        assert!(
+            perform_actions(&mut opts, &mut hosts, &cfg)
+                .unwrap()
+                .changed
+        );
+        assert_eq!("10.0.0.4\tdev.example\n", list_entries(&hosts));
+    }
+
+    #[test]
+    fn test_sort_on_write() {
+        let dir = temp_dir("sort_on_write");
+        let path = dir.join("hosts");
+        fs::write(
+            &path,
+            "# header\n10.0.0.9\tzhost\n\n127.0.0.1\tlocalhost\n::1\tlocalhost\n",
+        )
+        .unwrap();
+        let mut cfg = config(&["somehost"]);
+        cfg.sort_on_write = true;
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let mut opts = args(vec![Action::Define(ip, "somehost".into())]);
+        run(&mut opts, &cfg, "testhost", None, &path);
+        let generated = fs::read_to_string(&path).unwrap();
+        let hosts = try_parse_hosts(&generated).unwrap();
+        assert_eq!(
+            "10.0.0.1\tsomehost\n10.0.0.9\tzhost\n127.0.0.1\tlocalhost\n::1\tlocalhost\n",
+            list_entries(&hosts)
+        );
+        assert_eq!(
+            HostsPart::Comment(" header".into(), CommentMarker::Hash, "".into()),
+            hosts[4]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_dedup_entries() {
+        let data = r##"127.0.0.1	localhost
+10.0.0.1	web.example api.example api.example
+# 10.0.0.1	api.example
+10.0.0.1	api.example
+10.0.0.2	api.example
+127.0.0.1	localhost
+"##;
+        let mut hosts = parse_trimmed(data);
+        assert!(dedup_entries(&mut hosts));
+        assert_eq!(4, hosts.len());
+        assert_eq!(
+            "127.0.0.1\tlocalhost\n10.0.0.1\tweb.example api.example\n10.0.0.2\tapi.example\n",
+            list_entries(&hosts)
+        );
+        assert!(!dedup_entries(&mut hosts));
+    }
+}
+
+/// Round trips of generated, syntactically valid hosts files through parsing and rendering.
+#[cfg(test)]
+mod proptests {
+    use crate::file::{BlankLines, HostsFile};
+    use crate::parse::{try_parse_spans_with, HostsPart, ParseOptions};
+    use crate::{generate_hosts_file, verify_round_trip};
+    use proptest::prelude::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    const OPTIONS: ParseOptions = ParseOptions {
+        semicolon_comments: true,
+        lenient: false,
+    };
+
+    fn ip() -> impl Strategy<Value = String> {
+        prop_oneof![
+            any::<Ipv4Addr>().prop_map(|ip| ip.to_string()),
+            any::<Ipv6Addr>().prop_map(|ip| ip.to_string()),
+            // non-canonical spelling, eg. 2001:0db8:0000:...
+            any::<Ipv6Addr>().prop_map(|ip| {
+                let segments: Vec<String> =
+                    ip.segments().iter().map(|s| format!("{:04x}", s)).collect();
+                segments.join(":")
+            }),
+        ]
+    }
+
+    fn separator() -> impl Strategy<Value = String> {
+        "[ \t]{1,3}"
+    }
+
+    fn entry() -> impl Strategy<Value = String> {
+        (
+            ip(),
+            prop::collection::vec(
+                (
+                    separator(),
+                    "[a-z][a-z0-9-]{0,8}(\\.[a-z][a-z0-9]{0,4}){0,2}",
+                ),
+                1..4,
+            ),
+            prop::option::of("[ \t]{0,2}#[ -~]{1,12}"),
+            "[ \t]{0,2}",
+        )
+            .prop_map(|(ip, hosts, comment, trailing)| {
+                let mut line = ip;
+                for (separator, host) in hosts {
+                    line.push_str(&separator);
+                    line.push_str(&host);
+                }
+                match comment {
+                    Some(comment) => format!("{} {}", line, comment),
+                    None => line + &trailing,
+                }
+            })
+    }
+
+    fn marker() -> impl Strategy<Value = String> {
+        "[ \t]{0,2}[#;][ \t]{0,2}"
+    }
+
+    fn line() -> impl Strategy<Value = String> {
+        prop_oneof![
+            4 => entry(),
+            2 => (marker(), entry()).prop_map(|(marker, entry)| marker + &entry),
+            2 => (marker(), "[ -~]{1,20}").prop_map(|(marker, text)| marker + &text),
+            1 => "[ \t]{0,3}",
+        ]
+    }
+
+    /// Any number of lines, ending in a line break after the last non-empty one.
+    fn hosts_file() -> impl Strategy<Value = String> {
+        (
+            prop::collection::vec(line(), 0..16),
+            prop_oneof![entry(), (marker(), entry()).prop_map(|(m, e)| m + &e)],
+            prop_oneof![Just("\n"), Just("\r\n"), Just("\n\r")],
+        )
+            .prop_map(|(mut lines, last, line_break)| {
+                lines.push(last);
+                lines.join(line_break) + line_break
+            })
+    }
+
+    fn parts(data: &str) -> Vec<HostsPart<'_>> {
+        let spans = try_parse_spans_with(data, OPTIONS).unwrap();
+        let mut file = HostsFile::from(spans.into_iter().map(|(_, part)| part).collect::<Vec<_>>());
+        file.trim_trailing_empty();
+        file.normalize_blank_lines(BlankLines::Preserve);
+        file.into_parts()
+    }
+
+    proptest! {
+        #[test]
+        fn untouched_file_is_reproduced(data in hosts_file()) {
+            let spans = try_parse_spans_with(&data, OPTIONS).unwrap();
+            let generated = generate_hosts_file(data.len(), &parts(&data), &data, &spans);
+            prop_assert_eq!(&data, &generated);
+        }
+
+        #[test]
+        fn rendered_file_parses_back(data in hosts_file()) {
+            // without the original text, every part is rendered from scratch
+            let parts = parts(&data);
+            let generated = generate_hosts_file(data.len(), &parts, "", &[]);
+            prop_assert_eq!(Ok(()), verify_round_trip(&parts, &generated, OPTIONS));
+        }
+    }
+}
@@ -1,38 +1,101 @@
-use crate::parse::{comb_ipaddr, maybe_hostname_alias};
-use nom::branch::alt;
-use nom::bytes::complete::{tag, take_while1};
-use nom::combinator::{eof, map};
-use nom::sequence::{preceded, separated_pair, terminated};
-use nom::IResult;
+use crate::action::{try_parse_action, Action};
+use crate::parse::is_valid_hostname;
 use std::net::IpAddr;
-
-#[derive(Debug, Eq, PartialEq)]
-pub enum Action {
-    Remove(String),
-    Define(IpAddr, String),
-    DefineExclusive(IpAddr, String),
-}
+use std::path::PathBuf;
 
 #[derive(Debug, StructOpt)]
 #[structopt(settings = & [structopt::clap::AppSettings::ColoredHelp])]
 pub struct HostsArgs {
-    /// Will make no change and simply output what would have changed.
+    /// Will make no change and simply output what would have changed. Exits with status 4 if there
+    /// were changes, 0 if the hosts file already is as intended.
     #[structopt(short = "n", long = "dry-run")]
     pub dry_run: bool,
+    /// Will output only the added (`+`) and removed (`-`) lines instead of the whole hosts file,
+    /// for `--dry-run` and `--verbose`
+    #[structopt(long = "changed-only")]
+    pub changed_only: bool,
+    /// Will print a single line summing up the changes to stderr when done, eg.
+    /// `hostsmod: added=1 removed=0 modified=0 unchanged=0 file=/etc/hosts dry_run=false`
+    #[structopt(long = "summary")]
+    pub summary: bool,
     /// Will output generated hosts file to stdout
     #[structopt(short = "v", long = "verbose")]
     pub verbose: bool,
+    /// Removing a hostname will also affect commented-out entries
+    #[structopt(long = "include-commented")]
+    pub include_commented: bool,
+    /// Removing the last hostname of an entry will comment it out instead of deleting it
+    #[structopt(long = "comment-on-remove")]
+    pub comment_on_remove: bool,
+    /// Will keep lines which cannot be parsed as they are instead of failing, warning about each
+    #[structopt(long = "lenient")]
+    pub lenient: bool,
+    /// Will tidy up the whole hosts file: sorted and deduplicated entries, canonical IP addresses,
+    /// a tab after each IP address, no trailing whitespace and `\n` line breaks
+    #[structopt(long = "normalize")]
+    pub normalize: bool,
+    /// Will remove commented-out entries duplicating an active one, eg. `# 10.0.0.1 host` next to
+    /// `10.0.0.1 host`
+    #[structopt(long = "tidy")]
+    pub tidy: bool,
+    /// Will update the file a symlinked hosts file points to instead of refusing to replace the
+    /// symlink
+    #[structopt(long = "follow-symlinks")]
+    pub follow_symlinks: bool,
+    /// Will restore the most recent backup of the hosts file, see config variable `backup`
+    #[structopt(long = "rollback")]
+    pub rollback: bool,
+    /// Permits an action to undo an earlier one for the same hostname, eg. `IP+=host` followed by
+    /// `-host`. Such actions are otherwise rejected as likely mistakes
+    #[structopt(long = "allow-conflicting-actions")]
+    pub allow_conflicting_actions: bool,
+    /// Will only verify the config variables `ensure_present` and `ensure_absent` hold, exits with
+    /// a nonzero status and a report otherwise
+    #[structopt(long = "check")]
+    pub check: bool,
+    /// Will only report structural issues of the hosts file, eg. a hostname mapped to several
+    /// addresses, exits with a nonzero status if there are any
+    #[structopt(long = "validate")]
+    pub validate: bool,
+    /// Adds a hostname to the whitelist for this invocation only. Requires being run by root or
+    /// the config variable `allow_runtime_whitelist`
+    #[structopt(long = "allow", name = "HOST", number_of_values = 1,
+    parse(try_from_str = try_parse_hostname))]
+    pub allow: Vec<String>,
+    /// Modifies the given file instead of `/etc/hosts`. A file the invoking user may write is
+    /// modified with their privileges, even without root. When run setuid, any other file is
+    /// refused
+    #[structopt(long = "file", parse(from_os_str))]
+    pub file: Option<PathBuf>,
+    /// Will only validate the configuration, exits with a nonzero status and a report of any
+    /// problem found
+    #[structopt(long = "validate-config")]
+    pub validate_config: bool,
+    /// Will print the configuration in effect for this invocation, including runtime flags like
+    /// `--allow`
+    #[structopt(long = "show-config")]
+    pub show_config: bool,
+    /// Will create a default configuration and a hosts file with the reserved entries, if they do
+    /// not exist yet. Requires being run by root
+    #[structopt(long = "init")]
+    pub init: bool,
     /// Will generate a sample configuration on stdout
     #[structopt(long = "sample-config")]
     pub generate_sample_config: bool,
     /// Actions are the modifications to hosts that should be made. Prefix with `--` to stop other
-    /// argument parsing! There are three cases:
+    /// argument parsing! There are six cases:
     ///
     /// -host    -> Remove hostname from file. If no IP mapping remains, entry will be removed.
+    ///             May contain `*` and `?` wildcards to remove all matching hostnames.
+    /// -host/v6 -> Remove hostname only from IPv6 entries, `-host/v4` from IPv4 ones.
     /// IP=host  -> Define an entry exclusively, IP mapping gets added or changed. Will remove
     ///             any other mapping with the same hostname!
     /// IP+=host -> Define an entry, IP mapping gets added. Will not change existing mapping
     ///             with same hostname.
+    /// @N:IP+=host -> Like `IP+=host`, but a new entry is inserted as line N (counting from 0)
+    ///             instead of next to related entries.
+    /// host#text -> Set the trailing comment of all active entries with hostname to `text`,
+    ///             `host#` removes it.
     ///
     /// IP can be any IPv4 or IPv6 IP. It is only checked for valid format!
     ///
@@ -44,79 +107,148 @@ pub struct HostsArgs {
     help = "Defines intended modifications to hosts file. use `--help` for full description.",
     name="ACTIONS")]
     pub actions: Vec<Action>,
+    /// Alternative to ACTIONS, see `--help` of each subcommand.
+    #[structopt(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// Subcommands, a more discoverable front-end for the terse ACTIONS syntax.
+#[derive(Debug, StructOpt)]
+pub enum Command {
+    /// Adds a mapping of IP to hostname, same as `IP+=host` or `IP=host` with `--exclusive`.
+    Add {
+        /// Removes any other mapping with the same hostname
+        #[structopt(short = "x", long = "exclusive")]
+        exclusive: bool,
+        /// Any IPv4 or IPv6 address
+        ip: IpAddr,
+        /// Hostname to map to the IP address
+        #[structopt(parse(try_from_str = try_parse_hostname))]
+        host: String,
+    },
+    /// Removes a hostname, same as `-host`.
+    Remove {
+        /// Hostname to remove. If no IP mapping remains, the entry will be removed.
+        #[structopt(parse(try_from_str = try_parse_hostname))]
+        host: String,
+    },
+    /// Lists all active entries of the hosts file.
+    List,
+    /// Prints the IP addresses a hostname is mapped to, exits with a nonzero status if none.
+    Query {
+        /// Hostname to look up
+        host: String,
+    },
+    /// Prints the configuration in effect, or modifies it, see `--help` of each subcommand.
+    Config {
+        #[structopt(subcommand)]
+        command: Option<ConfigCommand>,
+    },
 }
 
-fn try_parse_action(str_action: &str) -> Result<Action, String> {
-    comb_action(str_action)
-        .map_err(|err| format!("unable to parse action {:?}: {}", str_action, err))
-        .map(|(_, action)| action)
+/// Modifications of the configuration file, requiring root.
+#[derive(Debug, StructOpt)]
+pub enum ConfigCommand {
+    /// Manages the hostnames which may be modified.
+    Whitelist(WhitelistCommand),
 }
 
-fn comb_action(input: &str) -> IResult<&str, Action> {
-    alt((
-        map(
-            terminated(preceded(tag("-"), take_while1(maybe_hostname_alias)), eof),
-            |host: &str| Action::Remove(host.to_string()),
-        ),
-        map(
-            terminated(
-                separated_pair(comb_ipaddr, tag("+="), take_while1(maybe_hostname_alias)),
-                eof,
-            ),
-            |(ip, host)| Action::Define(ip, host.to_string()),
-        ),
-        map(
-            terminated(
-                separated_pair(comb_ipaddr, tag("="), take_while1(maybe_hostname_alias)),
-                eof,
-            ),
-            |(ip, host)| Action::DefineExclusive(ip, host.to_string()),
-        ),
-    ))(input)
+/// Modifications of the `whitelist` config variable.
+#[derive(Debug, StructOpt)]
+pub enum WhitelistCommand {
+    /// Adds a hostname to the whitelist.
+    Add {
+        /// Hostname to allow modifying
+        #[structopt(parse(try_from_str = try_parse_hostname))]
+        host: String,
+    },
+    /// Removes a hostname from the whitelist.
+    Remove {
+        /// Hostname to no longer allow modifying
+        #[structopt(parse(try_from_str = try_parse_hostname))]
+        host: String,
+    },
+}
+
+impl Command {
+    /// Returns the action a subcommand stands for, `None` for read-only subcommands.
+    pub fn action(&self) -> Option<Action> {
+        match self {
+            Command::Add {
+                exclusive: false,
+                ip,
+                host,
+            } => Some(Action::Define(*ip, host.clone())),
+            Command::Add {
+                exclusive: true,
+                ip,
+                host,
+            } => Some(Action::DefineExclusive(*ip, host.clone())),
+            Command::Remove { host } => Some(Action::Remove(host.clone())),
+            Command::List | Command::Query { .. } | Command::Config { .. } => None,
+        }
+    }
+}
+
+fn try_parse_hostname(str_host: &str) -> Result<String, String> {
+    if !is_valid_hostname(str_host) {
+        return Err(format!("invalid hostname {:?}", str_host));
+    }
+    Ok(str_host.to_string())
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::opts::{comb_action, Action};
-    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
-    use std::str::FromStr;
+    use crate::action::Action;
+    use crate::opts::{Command, ConfigCommand, HostsArgs, WhitelistCommand};
+    use std::net::{IpAddr, Ipv4Addr};
+    use structopt::StructOpt;
 
     #[test]
-    fn test_parse_actions() {
-        {
-            let (remainder, parsed) = comb_action("-somehost").unwrap();
-            assert_eq!("", remainder);
-            assert_eq!(Action::Remove("somehost".into()), parsed);
-        }
-        {
-            let (remainder, parsed) = comb_action("127.1.65.77+=somehost").unwrap();
-            assert_eq!("", remainder);
-            assert_eq!(
-                Action::Define(IpAddr::V4(Ipv4Addr::new(127, 1, 65, 77)), "somehost".into()),
-                parsed
-            );
-        }
-        {
-            let (remainder, parsed) = comb_action("2003::f+=somehost").unwrap();
-            assert_eq!("", remainder);
-            assert_eq!(
-                Action::Define(
-                    IpAddr::V6(Ipv6Addr::from_str("2003::f").unwrap()),
-                    "somehost".into()
-                ),
-                parsed
-            );
+    fn test_subcommands() {
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let opts =
+            HostsArgs::from_iter_safe(&["hostsmod", "-n", "add", "10.0.0.1", "somehost"]).unwrap();
+        assert!(opts.dry_run);
+        assert!(opts.actions.is_empty());
+        assert_eq!(
+            Some(Action::Define(ip, "somehost".into())),
+            opts.command.unwrap().action()
+        );
+        let opts =
+            HostsArgs::from_iter_safe(&["hostsmod", "add", "-x", "10.0.0.1", "somehost"]).unwrap();
+        assert_eq!(
+            Some(Action::DefineExclusive(ip, "somehost".into())),
+            opts.command.unwrap().action()
+        );
+        assert!(HostsArgs::from_iter_safe(&["hostsmod", "add", "10.0.0.1", "some host"]).is_err());
+
+        let opts = HostsArgs::from_iter_safe(&["hostsmod", "list"]).unwrap();
+        match opts.command {
+            Some(cmd @ Command::List) => assert_eq!(None, cmd.action()),
+            other => panic!("expected list subcommand, found: {:?}", other),
         }
-        {
-            let (remainder, parsed) = comb_action("::1=somehost").unwrap();
-            assert_eq!("", remainder);
-            assert_eq!(
-                Action::DefineExclusive(
-                    IpAddr::V6(Ipv6Addr::from_str("::1").unwrap()),
-                    "somehost".into()
-                ),
-                parsed
-            );
+
+        let opts =
+            HostsArgs::from_iter_safe(&["hostsmod", "config", "whitelist", "add", "somehost"])
+                .unwrap();
+        match opts.command {
+            Some(Command::Config {
+                command: Some(ConfigCommand::Whitelist(WhitelistCommand::Add { host })),
+            }) => assert_eq!("somehost", host),
+            other => panic!("expected config whitelist subcommand, found: {:?}", other),
         }
+
+        // terse syntax keeps working
+        let opts =
+            HostsArgs::from_iter_safe(&["hostsmod", "--", "10.0.0.1+=somehost", "-other"]).unwrap();
+        assert!(opts.command.is_none());
+        assert_eq!(
+            vec![
+                Action::Define(ip, "somehost".into()),
+                Action::Remove("other".into())
+            ],
+            opts.actions
+        );
     }
 }
@@ -1,15 +1,19 @@
 use nom::branch::alt;
 use nom::bytes::complete::{is_not, tag, take_while, take_while1};
-use nom::combinator::{complete, eof, map, map_res, opt, peek};
+use nom::combinator::{complete, eof, map, map_res, opt, peek, value, verify};
 use nom::multi::{separated_list0, separated_list1};
-use nom::sequence::{preceded, terminated, tuple};
+use nom::sequence::{terminated, tuple};
 use nom::{AsChar, IResult};
+use serde::{Deserialize, Serialize};
 use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::io::BufRead;
 use std::net::IpAddr;
+use std::ops::Range;
 use std::str::FromStr;
 
 /// Part of a hosts file, representing all of the possible values.
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum HostsPart<'a> {
     /// An entry as outlined in `man 5 hosts`. Starting with an IP address (v4 or v6), followed by
     /// at least one space or tab, then a hostname, alphanumeric+`.`+`-`. Optional host aliases may
@@ -20,17 +24,61 @@ pub enum HostsPart<'a> {
     /// An entry matching the `Entry` pattern, only commented out by a `#` character at the
     /// beginning of the line. This differentiation might be used to only disable entries while
     /// leaving the information still present in the file (eg. for human consumption).
-    CommentedEntry(IpAddr, Vec<Cow<'a, str>>, Option<Cow<'a, str>>),
+    ///
+    /// The last field holds any spaces or tabs indenting the `#` character.
+    CommentedEntry(
+        IpAddr,
+        Vec<Cow<'a, str>>,
+        Option<Cow<'a, str>>,
+        CommentMarker,
+        Cow<'a, str>,
+    ),
     /// A comment, consisting of a `#` character followed by arbitrary text until the next line
-    /// break..
-    Comment(Cow<'a, str>),
+    /// break. Only the first `#` is the marker, further ones like in `### header` belong to the
+    /// text. The last field holds any spaces or tabs indenting the `#` character.
+    Comment(Cow<'a, str>, CommentMarker, Cow<'a, str>),
     /// An empty part of a hosts file will contain only whitespace (or an empty string for a single
     /// line break).
     Empty(Cow<'a, str>),
+    /// A line which could not be parsed, kept verbatim to be written back unchanged. Never matches
+    /// any IP address or hostname. Only produced with `ParseOptions::lenient`.
+    Raw(Cow<'a, str>),
+}
+
+/// The character starting a comment or commented-out entry, kept to render it unchanged.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+pub enum CommentMarker {
+    /// `#`, as specified by `man 5 hosts`.
+    #[default]
+    Hash,
+    /// `;`, only recognized if enabled by `ParseOptions::semicolon_comments`.
+    Semicolon,
+}
+
+impl CommentMarker {
+    /// Returns the character starting the comment.
+    pub fn as_char(self) -> char {
+        match self {
+            CommentMarker::Hash => '#',
+            CommentMarker::Semicolon => ';',
+        }
+    }
+}
+
+/// Options for parsing non-standard hosts files, the default being strict `man 5 hosts`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ParseOptions {
+    /// Also accept `;` to start a comment or commented-out entry at the beginning of a line.
+    /// Trailing comments of entries still require `#`.
+    pub semicolon_comments: bool,
+    /// Keep lines which cannot be parsed as `HostsPart::Raw` instead of failing.
+    pub lenient: bool,
 }
 
 /// Small enum representing the address family of an IP address.
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
 pub enum HostsPartFamily {
     #[allow(missing_docs)]
     IPv4,
@@ -38,6 +86,23 @@ pub enum HostsPartFamily {
     IPv6,
 }
 
+impl std::fmt::Display for HostsPartFamily {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            HostsPartFamily::IPv4 => write!(f, "IPv4"),
+            HostsPartFamily::IPv6 => write!(f, "IPv6"),
+        }
+    }
+}
+
+/// Returns the address family (v4 or v6) of an IP address.
+pub fn ip_family(ip: &IpAddr) -> HostsPartFamily {
+    match ip {
+        IpAddr::V4(_) => HostsPartFamily::IPv4,
+        IpAddr::V6(_) => HostsPartFamily::IPv6,
+    }
+}
+
 impl<'a> HostsPart<'a> {
     /// Checks whether a hosts file part matches the provided IP address. Considers commented-out
     /// entries.
@@ -48,6 +113,71 @@ impl<'a> HostsPart<'a> {
         }
     }
 
+    /// Checks whether a hosts file part matches the provided IP address, treating IPv4-mapped IPv6
+    /// addresses (`::ffff:a.b.c.d`) as their IPv4 equivalent. Considers commented-out entries.
+    pub fn matches_ip_canonical(&self, ip_needle: &IpAddr) -> bool {
+        match self {
+            HostsPart::Entry(ip, ..) | HostsPart::CommentedEntry(ip, ..) => {
+                canonical_ip(ip) == canonical_ip(ip_needle)
+            }
+            _ => false,
+        }
+    }
+
+    /// Total order for canonical sorting of hosts file parts. Sorts by kind first (entries,
+    /// commented-out entries, comments, empty lines), then by IP address, then by primary
+    /// hostname. Remaining ties are broken by the other aliases, the comment and the raw text.
+    pub fn cmp_canonical(&self, other: &HostsPart) -> Ordering {
+        fn rank(part: &HostsPart) -> u8 {
+            match part {
+                HostsPart::Entry(..) => 0,
+                HostsPart::CommentedEntry(..) => 1,
+                HostsPart::Comment(..) => 2,
+                HostsPart::Empty(..) => 3,
+                HostsPart::Raw(..) => 4,
+            }
+        }
+        match (self, other) {
+            (
+                HostsPart::Entry(ip_a, hosts_a, comment_a),
+                HostsPart::Entry(ip_b, hosts_b, comment_b),
+            )
+            | (
+                HostsPart::CommentedEntry(ip_a, hosts_a, comment_a, ..),
+                HostsPart::CommentedEntry(ip_b, hosts_b, comment_b, ..),
+            ) => ip_a
+                .cmp(ip_b)
+                .then_with(|| hosts_a.cmp(hosts_b))
+                .then_with(|| comment_a.cmp(comment_b)),
+            (HostsPart::Comment(a, ..), HostsPart::Comment(b, ..))
+            | (HostsPart::Empty(a), HostsPart::Empty(b))
+            | (HostsPart::Raw(a), HostsPart::Raw(b)) => a.cmp(b),
+            _ => rank(self).cmp(&rank(other)),
+        }
+    }
+
+    /// Changes the IP address of an entry in place, does nothing for other parts. Considers
+    /// commented-out entries.
+    pub fn set_ip(&mut self, ip_new: IpAddr) {
+        if let HostsPart::Entry(ip, ..) | HostsPart::CommentedEntry(ip, ..) = self {
+            *ip = ip_new;
+        }
+    }
+
+    /// Sets or, given `None`, strips the trailing comment of an entry, does nothing for other
+    /// parts. The comment text follows the `#` character. Considers commented-out entries.
+    pub fn set_comment(&mut self, comment_new: Option<Cow<'a, str>>) {
+        if let HostsPart::Entry(_, _, comment) | HostsPart::CommentedEntry(_, _, comment, ..) = self
+        {
+            *comment = comment_new;
+        }
+    }
+
+    /// Strips the trailing comment of an entry, see `set_comment`.
+    pub fn clear_comment(&mut self) {
+        self.set_comment(None);
+    }
+
     /// Checks whether a hosts file part contains the provided hostname. Aliases are considered, as
     /// are commented-out entries.
     pub fn matches_hostname(&self, host_needle: &str) -> bool {
@@ -59,61 +189,144 @@ impl<'a> HostsPart<'a> {
         }
     }
 
-    /// Checks whether a hosts file part is empty.
-    pub fn is_empty(&self) -> bool {
+    /// Checks whether a hosts file part is an entry for a loopback address, including IPv4-mapped
+    /// IPv6 ones. Considers commented-out entries.
+    pub fn is_loopback_entry(&self) -> bool {
         match self {
-            HostsPart::Empty(..) => true,
+            HostsPart::Entry(ip, ..) | HostsPart::CommentedEntry(ip, ..) => {
+                canonical_ip(ip).is_loopback()
+            }
             _ => false,
         }
     }
 
+    /// Checks whether a hosts file part is empty.
+    pub fn is_empty(&self) -> bool {
+        matches!(self, HostsPart::Empty(..))
+    }
+
+    /// Checks whether a hosts file part is a truly blank line, containing not even whitespace.
+    pub fn is_blank(&self) -> bool {
+        matches!(self, HostsPart::Empty(empty) if empty.is_empty())
+    }
+
+    /// Checks whether a hosts file part is an empty line consisting of at least one whitespace
+    /// character.
+    pub fn is_whitespace_only(&self) -> bool {
+        matches!(self, HostsPart::Empty(empty) if !empty.is_empty())
+    }
+
     /// Checks whether a hosts file part is a commented-out entry.
     #[allow(dead_code)]
     pub fn is_commented(&self) -> bool {
-        match self {
-            HostsPart::CommentedEntry(..) => true,
-            _ => false,
-        }
+        matches!(self, HostsPart::CommentedEntry(..))
     }
 
     /// If a hosts file part contains an IP address, returns that addresses family (v4 or v6).
     /// Considers commented-out entries.
     pub fn get_family(&self) -> Option<HostsPartFamily> {
         match self {
-            HostsPart::Entry(ip, ..) | HostsPart::CommentedEntry(ip, ..) => {
-                if ip.is_ipv4() {
-                    Some(HostsPartFamily::IPv4)
-                } else if ip.is_ipv6() {
-                    Some(HostsPartFamily::IPv6)
-                } else {
-                    unimplemented!("IpAddr is neither V4 nor V6, no idea what to do");
+            HostsPart::Entry(ip, ..) | HostsPart::CommentedEntry(ip, ..) => Some(ip_family(ip)),
+            _ => None,
+        }
+    }
+
+    /// Converts a hosts file part into one owning all of its data, detaching it from the lifetime
+    /// of the parsed input.
+    pub fn into_owned(self) -> HostsPart<'static> {
+        fn own(hosts: Vec<Cow<str>>) -> Vec<Cow<'static, str>> {
+            hosts
+                .into_iter()
+                .map(|host| Cow::Owned(host.into_owned()))
+                .collect()
+        }
+        match self {
+            HostsPart::Entry(ip, hosts, opt_comment) => HostsPart::Entry(
+                ip,
+                own(hosts),
+                opt_comment.map(|comment| Cow::Owned(comment.into_owned())),
+            ),
+            HostsPart::CommentedEntry(ip, hosts, opt_comment, marker, indent) => {
+                HostsPart::CommentedEntry(
+                    ip,
+                    own(hosts),
+                    opt_comment.map(|comment| Cow::Owned(comment.into_owned())),
+                    marker,
+                    Cow::Owned(indent.into_owned()),
+                )
+            }
+            HostsPart::Comment(comment, marker, indent) => HostsPart::Comment(
+                Cow::Owned(comment.into_owned()),
+                marker,
+                Cow::Owned(indent.into_owned()),
+            ),
+            HostsPart::Empty(empty) => HostsPart::Empty(Cow::Owned(empty.into_owned())),
+            HostsPart::Raw(raw) => HostsPart::Raw(Cow::Owned(raw.into_owned())),
+        }
+    }
+
+    /// Appends a hostname to the aliases of an entry, unless already present. Returns whether the
+    /// hostname was added. Considers commented-out entries.
+    pub fn add_hostname(&mut self, host_new: Cow<'a, str>) -> bool {
+        match self {
+            HostsPart::Entry(_, hosts, ..) | HostsPart::CommentedEntry(_, hosts, ..) => {
+                if hosts.contains(&host_new) {
+                    return false;
                 }
+                hosts.push(host_new);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns the first hostname of an entry, which is its canonical name (eg. for reverse
+    /// lookups). Considers commented-out entries.
+    pub fn primary_hostname(&self) -> Option<&str> {
+        match self {
+            HostsPart::Entry(_, hosts, ..) | HostsPart::CommentedEntry(_, hosts, ..) => {
+                hosts.first().map(|host| host.as_ref())
             }
             _ => None,
         }
     }
 
-    // pub fn add_hostname<'b: 'a>(&mut self, host_new: Cow<'b, str>) {
-    //     match self {
-    //         HostsPart::Entry(_, hosts, ..) | HostsPart::CommentedEntry(_, hosts, ..) => {
-    //             if !hosts.contains(&host_new) {
-    //                 hosts.push(host_new);
-    //             }
-    //         }
-    //         _ => {}
-    //     }
-    // }
-    //
-    // pub fn remove_hostname<'b: 'a>(&mut self, host_new: Cow<'b, str>) {
-    //     match self {
-    //         HostsPart::Entry(_, hosts, ..) | HostsPart::CommentedEntry(_, hosts, ..) => {
-    //             if !hosts.contains(&host_new) {
-    //                 hosts.push(host_new);
-    //             }
-    //         }
-    //         _ => {}
-    //     }
-    // }
+    /// Makes a hostname the canonical name of an entry by moving it to the front of the aliases,
+    /// inserting it if absent. The order of the remaining aliases is kept. Considers
+    /// commented-out entries.
+    pub fn set_primary(&mut self, host_primary: &str) {
+        if let HostsPart::Entry(_, hosts, ..) | HostsPart::CommentedEntry(_, hosts, ..) = self {
+            match hosts.iter().position(|host| host == host_primary) {
+                Some(idx) => hosts[..=idx].rotate_right(1),
+                None => hosts.insert(0, Cow::Owned(host_primary.to_string())),
+            }
+        }
+    }
+
+    /// Removes a hostname from the aliases of an entry. Returns whether the hostname was present.
+    /// Considers commented-out entries.
+    pub fn remove_hostname(&mut self, host_needle: &str) -> bool {
+        match self {
+            HostsPart::Entry(_, hosts, ..) | HostsPart::CommentedEntry(_, hosts, ..) => {
+                let len = hosts.len();
+                hosts.retain(|host| host != host_needle);
+                hosts.len() != len
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Converts IPv4-mapped IPv6 addresses (`::ffff:a.b.c.d`) to their IPv4 form, leaving all other
+/// addresses untouched.
+pub fn canonical_ip(ip: &IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(ip6) => ip6
+            .to_ipv4_mapped()
+            .map(IpAddr::V4)
+            .unwrap_or(IpAddr::V6(*ip6)),
+        IpAddr::V4(_) => *ip,
+    }
 }
 
 fn maybe_ip_addr(byt: char) -> bool {
@@ -131,43 +344,91 @@ pub(crate) fn maybe_hostname_alias(byt: char) -> bool {
     byt.is_alphanumeric() || byt == '-' || byt == '_' || byt == '.'
 }
 
+/// Checks `host` consists of `maybe_hostname_alias` characters within the DNS length limits: at
+/// most 253 characters, each dot-separated label between 1 and 63.
+pub(crate) fn is_valid_hostname(host: &str) -> bool {
+    host.len() <= 253
+        && host.chars().all(maybe_hostname_alias)
+        && host
+            .split('.')
+            .all(|label| !label.is_empty() && label.len() <= 63)
+}
+
 fn is_space(byt: char) -> bool {
     // eprintln!("is_space: {:?}", byt);
     byt == ' ' || byt == '\t'
 }
 
-pub fn parse_hosts_file(input: &str) -> IResult<&str, Vec<HostsPart>> {
+/// Any whitespace except line breaks, eg. a form feed, kept verbatim in `HostsPart::Empty`.
+fn is_empty_space(byt: char) -> bool {
+    byt.is_whitespace() && byt != '\n' && byt != '\r'
+}
+
+pub fn parse_hosts_file(input: &str) -> IResult<&str, Vec<HostsPart<'_>>> {
+    parse_hosts_file_with(input, ParseOptions::default())
+}
+
+/// Like `parse_hosts_file`, accepting the non-standard syntax enabled by `options`.
+pub fn parse_hosts_file_with(
+    input: &str,
+    options: ParseOptions,
+) -> IResult<&str, Vec<HostsPart<'_>>> {
     // dbg!(input);
-    complete(separated_list0(
-        comb_linebreak,
-        alt((
-            map(
-                comb_commented_entry,
-                |(ip, hosts, opt_comment): (IpAddr, Vec<Cow<str>>, Option<&str>)| {
-                    HostsPart::CommentedEntry(ip, hosts, opt_comment.map(Cow::Borrowed))
-                },
-            ),
-            map(comb_comment, |comment| {
-                HostsPart::Comment(Cow::Borrowed(comment))
-            }),
-            map(
-                comb_entry,
-                |(ip, hosts, opt_comment): (IpAddr, Vec<Cow<str>>, Option<&str>)| {
-                    HostsPart::Entry(ip, hosts, opt_comment.map(Cow::Borrowed))
-                },
-            ),
-            // map(is_not("\r\n"), |ws: &str| {
-            //     HostsPart::Empty(Cow::Borrowed(ws))
-            // }),
-            map(
-                terminated(take_while(is_space), peek(alt((comb_linebreak, eof)))),
-                |anything| HostsPart::Empty(Cow::Borrowed(anything)),
-            ),
-        )),
+    complete(separated_list0(comb_linebreak, |i| comb_part(options, i)))(input)
+}
+
+fn comb_part(options: ParseOptions, input: &str) -> IResult<&str, HostsPart<'_>> {
+    if !options.lenient {
+        return comb_part_strict(options, input);
+    }
+    alt((
+        terminated(
+            |i| comb_part_strict(options, i),
+            peek(alt((comb_linebreak, eof))),
+        ),
+        map(is_not("\r\n"), |raw| HostsPart::Raw(Cow::Borrowed(raw))),
     ))(input)
 }
 
-fn comb_entry<'a>(input: &'a str) -> IResult<&str, (IpAddr, Vec<Cow<'a, str>>, Option<&str>)> {
+fn comb_part_strict(options: ParseOptions, input: &str) -> IResult<&str, HostsPart<'_>> {
+    alt((
+        map(
+            |i| comb_commented_entry(options, i),
+            |(indent, marker, (ip, hosts, opt_comment)): (&str, CommentMarker, EntryParts)| {
+                HostsPart::CommentedEntry(
+                    ip,
+                    hosts,
+                    opt_comment.map(Cow::Borrowed),
+                    marker,
+                    Cow::Borrowed(indent),
+                )
+            },
+        ),
+        map(
+            |i| comb_comment(options, i),
+            |(indent, marker, comment)| {
+                HostsPart::Comment(Cow::Borrowed(comment), marker, Cow::Borrowed(indent))
+            },
+        ),
+        map(
+            comb_entry,
+            |(ip, hosts, opt_comment): (IpAddr, Vec<Cow<str>>, Option<&str>)| {
+                HostsPart::Entry(ip, hosts, opt_comment.map(Cow::Borrowed))
+            },
+        ),
+        // map(is_not("\r\n"), |ws: &str| {
+        //     HostsPart::Empty(Cow::Borrowed(ws))
+        // }),
+        map(
+            terminated(take_while(is_empty_space), peek(alt((comb_linebreak, eof)))),
+            |anything| HostsPart::Empty(Cow::Borrowed(anything)),
+        ),
+    ))(input)
+}
+
+type EntryParts<'a> = (IpAddr, Vec<Cow<'a, str>>, Option<&'a str>);
+
+fn comb_entry(input: &str) -> IResult<&str, EntryParts<'_>> {
     tuple((
         terminated(comb_ipaddr, take_while1(is_space)),
         terminated(
@@ -179,24 +440,40 @@ fn comb_entry<'a>(input: &'a str) -> IResult<&str, (IpAddr, Vec<Cow<'a, str>>, O
             ),
             take_while(is_space),
         ),
-        opt(comb_comment),
+        opt(map(
+            |i| comb_comment(ParseOptions::default(), i),
+            |(_, _, comment)| comment,
+        )),
+    ))(input)
+}
+
+fn comb_marker(options: ParseOptions, input: &str) -> IResult<&str, CommentMarker> {
+    alt((
+        value(CommentMarker::Hash, tag("#")),
+        value(
+            CommentMarker::Semicolon,
+            verify(tag(";"), |_: &str| options.semicolon_comments),
+        ),
     ))(input)
 }
 
-fn comb_comment(input: &str) -> IResult<&str, &str> {
-    preceded(preceded(take_while(is_space), tag("#")), is_not("\r\n"))(input)
+fn comb_comment(options: ParseOptions, input: &str) -> IResult<&str, (&str, CommentMarker, &str)> {
+    tuple((
+        take_while(is_space),
+        |i| comb_marker(options, i),
+        is_not("\r\n"),
+    ))(input)
 }
 
-fn comb_commented_entry<'a>(
-    input: &'a str,
-) -> IResult<&str, (IpAddr, Vec<Cow<'a, str>>, Option<&str>)> {
-    preceded(
-        preceded(
-            take_while(is_space),
-            terminated(tag("#"), take_while(is_space)),
-        ),
+fn comb_commented_entry(
+    options: ParseOptions,
+    input: &str,
+) -> IResult<&str, (&str, CommentMarker, EntryParts<'_>)> {
+    tuple((
+        take_while(is_space),
+        terminated(|i| comb_marker(options, i), take_while(is_space)),
         comb_entry,
-    )(input)
+    ))(input)
 }
 
 fn comb_linebreak(input: &str) -> IResult<&str, &str> {
@@ -217,9 +494,71 @@ pub(crate) fn comb_ipaddr(input: &str) -> IResult<&str, IpAddr> {
 /// Parses hosts file and returns `Vec` of resulting parts.
 #[allow(clippy::needless_lifetimes)]
 pub fn try_parse_hosts<'a>(read: &'a str) -> Result<Vec<HostsPart<'a>>, String> {
-    let (remainder, parsed) =
-        parse_hosts_file(read).map_err(|err| format!("Error parsing hosts: {:?}", err))?;
-    if remainder.len() > 0 {
+    try_parse_hosts_with(read, ParseOptions::default())
+}
+
+/// Like `try_parse_hosts`, taking raw bytes, eg. as handed out by fuzzers. Never panics, input
+/// which is not a valid hosts file is an `Err`.
+///
+/// Bytes which are not valid UTF-8, like a comment in a legacy encoding, are replaced by
+/// `U+FFFD`. Parts of such a file own their data. In an entry, they are a syntax error.
+pub fn try_parse_hosts_bytes(read: &[u8]) -> Result<Vec<HostsPart<'_>>, String> {
+    match String::from_utf8_lossy(read) {
+        Cow::Borrowed(read) => try_parse_hosts(read),
+        Cow::Owned(read) => try_parse_hosts(&read)
+            .map(|parsed| parsed.into_iter().map(HostsPart::into_owned).collect()),
+    }
+}
+
+/// Like `try_parse_hosts`, pairing each part with its line number (starting at 1). Every part
+/// spans exactly one line, so the line number counts the line breaks before it.
+pub fn try_parse_hosts_with_lines(read: &str) -> Result<Vec<(usize, HostsPart<'_>)>, String> {
+    try_parse_hosts(read).map(|parsed| {
+        parsed
+            .into_iter()
+            .enumerate()
+            .map(|(idx, part)| (idx + 1, part))
+            .collect()
+    })
+}
+
+/// Like `try_parse_hosts`, pairing each part with its byte range in `read`. The ranges exclude the
+/// line breaks, so slicing `read` by them yields the text of each line.
+pub fn try_parse_hosts_with_spans(
+    read: &str,
+) -> Result<Vec<(Range<usize>, HostsPart<'_>)>, String> {
+    try_parse_spans_with(read, ParseOptions::default())
+}
+
+/// Like `try_parse_hosts_with_spans`, accepting the non-standard syntax enabled by `options`.
+pub(crate) fn try_parse_spans_with<'a>(
+    read: &'a str,
+    options: ParseOptions,
+) -> Result<Vec<(Range<usize>, HostsPart<'a>)>, String> {
+    let comb_spanned = |input: &'a str| -> IResult<&'a str, (Range<usize>, HostsPart<'a>)> {
+        let (remainder, part) = comb_part(options, input)?;
+        let start = read.len() - input.len();
+        Ok((remainder, (start..read.len() - remainder.len(), part)))
+    };
+    let (remainder, parsed) = complete(separated_list0(comb_linebreak, comb_spanned))(read)
+        .map_err(|err| format!("Error parsing hosts: {:?}", err))?;
+    if !remainder.is_empty() {
+        return Err(format!(
+            "unable to parse hosts file, remainder: {:?}",
+            remainder
+        ));
+    }
+    Ok(parsed)
+}
+
+/// Like `try_parse_hosts`, accepting the non-standard syntax enabled by `options`.
+pub fn try_parse_hosts_with(
+    read: &str,
+    options: ParseOptions,
+) -> Result<Vec<HostsPart<'_>>, String> {
+    let (remainder, parsed) = parse_hosts_file_with(read, options)
+        .map_err(|err| format!("Error parsing hosts: {:?}", err))?;
+    if !remainder.is_empty() {
         return Err(format!(
             "unable to parse hosts file, remainder: {:?}",
             remainder
@@ -228,9 +567,151 @@ pub fn try_parse_hosts<'a>(read: &'a str) -> Result<Vec<HostsPart<'a>>, String>
     Ok(parsed)
 }
 
+/// Like `try_parse_hosts`, keeping every line which cannot be parsed as `HostsPart::Raw`, see
+/// `ParseOptions::lenient`.
+pub fn parse_lenient(read: &str) -> Result<Vec<HostsPart<'_>>, String> {
+    try_parse_hosts_with(
+        read,
+        ParseOptions {
+            lenient: true,
+            ..ParseOptions::default()
+        },
+    )
+}
+
+/// Parses as many parts from the start of `input` as possible, eg. for a hosts snippet embedded in
+/// a larger document. Instead of failing on trailing text like `try_parse_hosts`, returns the byte
+/// offset of the first character not consumed. This is the line break in front of the first line
+/// that could not be parsed, or `input.len()` if everything was parsed. A line which could only
+/// be parsed partly is left out as a whole.
+pub fn parse_hosts_prefix(input: &str) -> (Vec<HostsPart<'_>>, usize) {
+    let (remainder, parsed) = match parse_hosts_file(input) {
+        Ok(result) => result,
+        Err(_) => return (vec![], 0),
+    };
+    let offset = input.len() - remainder.len();
+    if remainder.is_empty() || comb_linebreak(remainder).is_ok() {
+        return (parsed, offset);
+    }
+    // the last line was only partly parsed, leave it out along with the line break in front
+    match input[..offset].rfind('\n') {
+        Some(idx) => {
+            let prefix = &input[..idx];
+            parse_hosts_prefix(prefix.strip_suffix('\r').unwrap_or(prefix))
+        }
+        None => (vec![], 0),
+    }
+}
+
+/// Error returned when parsing a hosts file from a reader.
+#[derive(Debug)]
+pub enum HostsParseError {
+    /// Reading from the underlying source failed.
+    Io(std::io::Error),
+    /// A line could not be parsed. Contains the line number (starting at 1) and its content.
+    Syntax(usize, String),
+}
+
+impl std::fmt::Display for HostsParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            HostsParseError::Io(err) => write!(f, "unable to read hosts file: {}", err),
+            HostsParseError::Syntax(line, content) => {
+                write!(f, "unable to parse hosts file line {}: {:?}", line, content)
+            }
+        }
+    }
+}
+
+impl std::error::Error for HostsParseError {}
+
+impl From<std::io::Error> for HostsParseError {
+    fn from(err: std::io::Error) -> Self {
+        HostsParseError::Io(err)
+    }
+}
+
+/// Parses a hosts file line by line from a reader, yielding owned parts without holding the whole
+/// file in memory. Produces the same parts as `try_parse_hosts` would for the complete input.
+pub fn parse_hosts_reader<R: BufRead>(
+    read: R,
+) -> impl Iterator<Item = Result<HostsPart<'static>, HostsParseError>> {
+    HostsReaderIter {
+        read,
+        buf: String::new(),
+        line: 0,
+        after_newline: false,
+        done: false,
+    }
+}
+
+struct HostsReaderIter<R> {
+    read: R,
+    buf: String,
+    line: usize,
+    /// Whether the previous line ended in a lone `\n`, which a leading `\r` completes to `\n\r`.
+    after_newline: bool,
+    done: bool,
+}
+
+impl<R: BufRead> Iterator for HostsReaderIter<R> {
+    type Item = Result<HostsPart<'static>, HostsParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        self.buf.clear();
+        let len = match self.read.read_line(&mut self.buf) {
+            Ok(len) => len,
+            Err(err) => {
+                self.done = true;
+                return Some(Err(err.into()));
+            }
+        };
+        self.line += 1;
+        // same as separated_list0, the input after the last line break is a part of its own
+        let mut line = self.buf.as_str();
+        // second half of a "\n\r" line break, which comb_linebreak prefers over a following "\r\n"
+        if self.after_newline && line.starts_with('\r') {
+            line = &line[1..];
+        }
+        self.after_newline = false;
+        if line.ends_with('\n') {
+            line = &line[..line.len() - 1];
+            if line.ends_with('\r') {
+                line = &line[..line.len() - 1];
+            } else {
+                self.after_newline = true;
+            }
+        } else {
+            self.done = true;
+        }
+        if len == 0 {
+            self.done = true;
+        }
+        Some(
+            match terminated(|i| comb_part(ParseOptions::default(), i), eof)(line) {
+                Ok((_, part)) => Ok(part.into_owned()),
+                Err(_) => {
+                    self.done = true;
+                    Err(HostsParseError::Syntax(self.line, line.to_string()))
+                }
+            },
+        )
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::parse::{parse_hosts_file, HostsPart};
+    use crate::parse::{
+        canonical_ip, parse_hosts_file, parse_hosts_prefix, parse_hosts_reader, parse_lenient,
+        try_parse_hosts, try_parse_hosts_bytes, try_parse_hosts_with, try_parse_hosts_with_lines,
+        try_parse_hosts_with_spans, CommentMarker, HostsPart, HostsPartFamily, ParseOptions,
+    };
+    use std::collections::HashMap;
+    use std::fmt::Write;
+    use std::io::Cursor;
     use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
     use std::str::FromStr;
 
@@ -279,7 +760,7 @@ ff02::2 ip6-allrouters
                 vec!["ip6-allrouters".into()],
                 None,
             ),
-            HostsPart::Comment(" comment".into()),
+            HostsPart::Comment(" comment".into(), CommentMarker::Hash, "".into()),
             HostsPart::Empty("".into()),
             HostsPart::Entry(
                 IpAddr::V4(Ipv4Addr::new(198, 51, 100, 11)),
@@ -295,6 +776,8 @@ ff02::2 ip6-allrouters
                 IpAddr::V4(Ipv4Addr::new(10, 4, 79, 99)),
                 vec!["deactivated.host".into(), "deactivated.host.1".into()],
                 None,
+                CommentMarker::Hash,
+                "".into(),
             ),
             HostsPart::Empty("    ".into()),
             HostsPart::Empty("".into()),
@@ -318,4 +801,443 @@ ff02::2 ip6-allrouters
             }
         }
     }
+
+    #[test]
+    fn test_parse_hosts_reader_large() {
+        let mut data = String::new();
+        for i in 0..5000u32 {
+            match i % 4 {
+                0 => writeln!(data, "# block {}", i),
+                1 => writeln!(data, "0.0.0.0\tblocked-{}.example ads-{}.example", i, i),
+                2 => writeln!(data, "::{:x} v6-{}.example # note", i, i),
+                _ => writeln!(data, "  "),
+            }
+            .unwrap();
+        }
+        data.push_str("\r\n10.0.0.1 last.example");
+
+        let streamed = parse_hosts_reader(Cursor::new(data.as_bytes()))
+            .collect::<Result<Vec<_>, _>>()
+            .expect("unable to stream sample hosts file");
+        let parsed = try_parse_hosts(&data).expect("unable to parse sample hosts file");
+        assert_eq!(5002, streamed.len());
+        assert_eq!(parsed, streamed);
+    }
+
+    #[test]
+    fn test_parse_hosts_reader_line_breaks() {
+        let inputs = [
+            "10.0.0.1 a\r\n\r10.0.0.2 b",
+            "10.0.0.1 a\n\r10.0.0.2 b",
+            "10.0.0.1 a\n\r\n10.0.0.2 b\r\n",
+            "10.0.0.1 a\n\r\n\r# b\n\r",
+            "# a\r\n\r\n\n\r\r\n10.0.0.2 b\n",
+            "10.0.0.1 a\r\r\n",
+        ];
+        for data in &inputs {
+            let streamed = parse_hosts_reader(Cursor::new(data.as_bytes()))
+                .collect::<Result<Vec<_>, _>>()
+                .ok();
+            let parsed = try_parse_hosts(data).ok();
+            assert_eq!(parsed, streamed, "{:?}", data);
+        }
+    }
+
+    #[test]
+    fn test_parse_hosts_reader_error() {
+        let mut iter = parse_hosts_reader(Cursor::new("127.0.0.1 localhost\n#\n::1 localhost\n"));
+        assert!(iter.next().unwrap().is_ok());
+        match iter.next() {
+            Some(Err(crate::parse::HostsParseError::Syntax(2, line))) => assert_eq!("#", line),
+            other => panic!("expected syntax error in line 2, found: {:?}", other),
+        }
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn test_matches_ip_canonical() {
+        let ip4 = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mapped = IpAddr::V6(Ipv6Addr::from_str("::ffff:127.0.0.1").unwrap());
+        let part = HostsPart::Entry(mapped, vec!["localhost".into()], None);
+        assert!(!part.matches_ip(&ip4));
+        assert!(part.matches_ip_canonical(&ip4));
+        assert!(HostsPart::Entry(ip4, vec!["localhost".into()], None).matches_ip_canonical(&mapped));
+        assert_eq!(ip4, canonical_ip(&mapped));
+        let ip6 = IpAddr::V6(Ipv6Addr::from(1));
+        assert_eq!(ip6, canonical_ip(&ip6));
+        assert!(!part.matches_ip_canonical(&ip6));
+    }
+
+    #[test]
+    fn test_blank_whitespace_only() {
+        let blank = HostsPart::Empty("".into());
+        let spaces = HostsPart::Empty(" \t ".into());
+        let comment = HostsPart::Comment("".into(), CommentMarker::Hash, "".into());
+        assert!(blank.is_empty() && blank.is_blank() && !blank.is_whitespace_only());
+        assert!(spaces.is_empty() && !spaces.is_blank() && spaces.is_whitespace_only());
+        assert!(!comment.is_empty() && !comment.is_blank() && !comment.is_whitespace_only());
+    }
+
+    #[test]
+    fn test_parse_unusual_whitespace() {
+        let data = "127.0.0.1\tlocalhost\n\x0c\n \x0b\u{a0}\t\n";
+        let parsed = try_parse_hosts_with_spans(data).unwrap();
+        assert_eq!(
+            vec![
+                HostsPart::Entry(
+                    IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+                    vec!["localhost".into()],
+                    None
+                ),
+                HostsPart::Empty("\x0c".into()),
+                HostsPart::Empty(" \x0b\u{a0}\t".into()),
+                HostsPart::Empty("".into()),
+            ],
+            parsed
+                .iter()
+                .map(|(_, part)| part.clone())
+                .collect::<Vec<_>>()
+        );
+        for (span, part) in &parsed {
+            if let HostsPart::Empty(empty) = part {
+                assert_eq!(&data[span.clone()], empty);
+            }
+        }
+        assert!(parsed[2].1.is_whitespace_only());
+    }
+
+    #[test]
+    fn test_primary_hostname() {
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let mut part = HostsPart::Entry(ip, vec!["a".into(), "b".into(), "c".into()], None);
+        assert_eq!(Some("a"), part.primary_hostname());
+        part.set_primary("c");
+        assert_eq!(
+            HostsPart::Entry(ip, vec!["c".into(), "a".into(), "b".into()], None),
+            part
+        );
+        part.set_primary("d");
+        assert_eq!(Some("d"), part.primary_hostname());
+        assert_eq!(
+            HostsPart::Entry(
+                ip,
+                vec!["d".into(), "c".into(), "a".into(), "b".into()],
+                None
+            ),
+            part
+        );
+        let mut comment = HostsPart::Comment(" a".into(), CommentMarker::Hash, "".into());
+        comment.set_primary("a");
+        assert_eq!(None, comment.primary_hostname());
+    }
+
+    #[test]
+    fn test_set_ip() {
+        let ip4 = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let ip6 = IpAddr::V6(Ipv6Addr::from_str("2001:db8::1").unwrap());
+        let mut part = HostsPart::CommentedEntry(
+            ip4,
+            vec!["somehost".into()],
+            None,
+            CommentMarker::Hash,
+            "".into(),
+        );
+        assert_eq!(Some(HostsPartFamily::IPv4), part.get_family());
+        part.set_ip(ip6);
+        assert!(part.matches_ip(&ip6));
+        assert_eq!(Some(HostsPartFamily::IPv6), part.get_family());
+
+        let mut empty = HostsPart::Empty("".into());
+        empty.set_ip(ip6);
+        assert_eq!(HostsPart::Empty("".into()), empty);
+    }
+
+    #[test]
+    fn test_set_comment() {
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let mut part = HostsPart::Entry(ip, vec!["somehost".into()], None);
+        part.set_comment(Some(" first".into()));
+        assert_eq!(
+            HostsPart::Entry(ip, vec!["somehost".into()], Some(" first".into())),
+            part
+        );
+        part.set_comment(Some(" second".into()));
+        assert_eq!(
+            HostsPart::Entry(ip, vec!["somehost".into()], Some(" second".into())),
+            part
+        );
+        part.clear_comment();
+        assert_eq!(HostsPart::Entry(ip, vec!["somehost".into()], None), part);
+
+        let mut comment = HostsPart::Comment(" stays".into(), CommentMarker::Hash, "".into());
+        comment.clear_comment();
+        assert_eq!(
+            HostsPart::Comment(" stays".into(), CommentMarker::Hash, "".into()),
+            comment
+        );
+    }
+
+    #[test]
+    fn test_cmp_canonical() {
+        let ip_a = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let ip_b = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+        let ip_c = IpAddr::V6(Ipv6Addr::from(1));
+        let sorted = vec![
+            HostsPart::Entry(ip_a, vec!["a".into(), "z".into()], None),
+            HostsPart::Entry(ip_a, vec!["b".into()], None),
+            HostsPart::Entry(ip_b, vec!["a".into()], None),
+            HostsPart::Entry(ip_c, vec!["a".into()], None),
+            HostsPart::CommentedEntry(ip_a, vec!["c".into()], None, CommentMarker::Hash, "".into()),
+            HostsPart::Comment(" a".into(), CommentMarker::Hash, "".into()),
+            HostsPart::Comment(" b".into(), CommentMarker::Hash, "".into()),
+            HostsPart::Empty("".into()),
+        ];
+        let mut shuffled = vec![
+            sorted[5].clone(),
+            sorted[3].clone(),
+            sorted[7].clone(),
+            sorted[1].clone(),
+            sorted[4].clone(),
+            sorted[6].clone(),
+            sorted[0].clone(),
+            sorted[2].clone(),
+        ];
+        shuffled.sort_by(HostsPart::cmp_canonical);
+        assert_eq!(sorted, shuffled);
+    }
+
+    #[test]
+    fn test_hash() {
+        use std::collections::hash_map::DefaultHasher;
+        use std::collections::HashSet;
+        use std::hash::{Hash, Hasher};
+
+        let parsed =
+            try_parse_hosts("10.0.0.1\tsomehost #note\n10.0.0.1 somehost #note\n").unwrap();
+        let owned = HostsPart::Entry(
+            IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+            vec![String::from("somehost").into()],
+            Some(String::from("note").into()),
+        );
+        let hash = |part: &HostsPart| {
+            let mut hasher = DefaultHasher::new();
+            part.hash(&mut hasher);
+            hasher.finish()
+        };
+        assert_eq!(parsed[0], owned);
+        assert_eq!(hash(&parsed[0]), hash(&owned));
+        let set: HashSet<_> = parsed.iter().chain(Some(&owned)).collect();
+        assert_eq!(2, set.len());
+    }
+
+    #[test]
+    fn test_parse_hosts_prefix() {
+        let snippet = "127.0.0.1\tlocalhost\n# comment\n10.0.0.1\ta.example";
+        let data = format!("{}\n---\nunrelated: text\n", snippet);
+        let (parsed, offset) = parse_hosts_prefix(&data);
+        assert_eq!(snippet.len(), offset);
+        assert_eq!("\n---\nunrelated: text\n", &data[offset..]);
+        assert_eq!(try_parse_hosts(snippet).unwrap(), parsed);
+
+        let (parsed, offset) = parse_hosts_prefix(snippet);
+        assert_eq!(snippet.len(), offset);
+        assert_eq!(3, parsed.len());
+
+        // a truncated or garbled last line is left out entirely
+        for tail in &[
+            "10.0.0.2\tb.exa mple = 3",
+            "10.0.0.2\tb.example\t\u{1}",
+            "10.0.0.2",
+        ] {
+            let data = format!("{}\n{}", snippet, tail);
+            let (parsed, offset) = parse_hosts_prefix(&data);
+            assert_eq!(snippet.len(), offset, "{:?}", tail);
+            assert_eq!(try_parse_hosts(snippet).unwrap(), parsed);
+        }
+        assert_eq!((vec![], 0), parse_hosts_prefix("10.0.0.2\tb.exa mple = 3"));
+        let data = "127.0.0.1\tlocalhost\r\n10.0.0.2\tb.exa mple = 3";
+        let (parsed, offset) = parse_hosts_prefix(data);
+        assert_eq!("\r\n10.0.0.2\tb.exa mple = 3", &data[offset..]);
+        assert_eq!(1, parsed.len());
+    }
+
+    #[test]
+    fn test_parse_lenient() {
+        let data = "127.0.0.1\tlocalhost\n10.0.0.1\tbad#host = 3\n  ??? \n# comment\n";
+        assert!(try_parse_hosts(data).is_err());
+        let parsed = parse_lenient(data).unwrap();
+        assert_eq!(
+            vec![
+                HostsPart::Entry(
+                    IpAddr::V4(Ipv4Addr::LOCALHOST),
+                    vec!["localhost".into()],
+                    None
+                ),
+                HostsPart::Entry(
+                    IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+                    vec!["bad".into()],
+                    Some("host = 3".into())
+                ),
+                HostsPart::Raw("  ??? ".into()),
+                HostsPart::Comment(" comment".into(), CommentMarker::Hash, "".into()),
+                HostsPart::Empty("".into()),
+            ],
+            parsed
+        );
+    }
+
+    #[test]
+    fn test_semicolon_comments() {
+        let data = "; comment\n;10.0.0.1\tsome.example\n# other\n";
+        assert!(try_parse_hosts(data).is_err());
+        let options = ParseOptions {
+            semicolon_comments: true,
+            ..ParseOptions::default()
+        };
+        let parsed = try_parse_hosts_with(data, options).unwrap();
+        assert_eq!(
+            vec![
+                HostsPart::Comment(" comment".into(), CommentMarker::Semicolon, "".into()),
+                HostsPart::CommentedEntry(
+                    IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+                    vec!["some.example".into()],
+                    None,
+                    CommentMarker::Semicolon,
+                    "".into()
+                ),
+                HostsPart::Comment(" other".into(), CommentMarker::Hash, "".into()),
+                HostsPart::Empty("".into()),
+            ],
+            parsed
+        );
+        if let HostsPart::Comment(comment, marker, _) = &parsed[0] {
+            assert_eq!("; comment", format!("{}{}", marker.as_char(), comment));
+        }
+    }
+
+    #[test]
+    fn test_parse_malformed_no_panic() {
+        let data = "127.0.0.1\tlocalhost # c\r\n# 10.0.0.1 commented\n;x\n\n::ffff:127.0.0.1 v4mapped\n\u{e9}\x0c\n";
+        // every truncation, on and off character boundaries
+        for len in 0..=data.len() {
+            let _ = try_parse_hosts_bytes(&data.as_bytes()[..len]);
+            let _ = try_parse_hosts_with(
+                data.get(..len).unwrap_or(""),
+                ParseOptions {
+                    semicolon_comments: true,
+                    lenient: true,
+                },
+            );
+        }
+        for malformed in &[
+            "127.0.0.1",
+            "127.0.0.1\t",
+            "::1 \u{0}",
+            "1.2.3.4.5 host",
+            "::::::1 host",
+            "#",
+            "\r",
+            "\n\r\r\n",
+            "127.0.0.1 host\t#",
+            "999.0.0.1 host",
+            "fe80::1%eth0 host",
+        ] {
+            let _ = try_parse_hosts(malformed);
+            let _ = parse_hosts_prefix(malformed);
+        }
+        // deterministic pseudo-random bytes, biased towards characters meaningful to the parser
+        let alphabet = b"0123456789abcdef.:#; \t\r\n-_%\xff";
+        let mut state = 0x2545_f491_u32;
+        for _ in 0..500 {
+            let input: Vec<u8> = (0..64)
+                .map(|_| {
+                    state ^= state << 13;
+                    state ^= state >> 17;
+                    state ^= state << 5;
+                    alphabet[state as usize % alphabet.len()]
+                })
+                .collect();
+            let _ = try_parse_hosts_bytes(&input);
+        }
+        assert!(try_parse_hosts_bytes(b"\xff").is_err());
+    }
+
+    #[test]
+    fn test_parse_hosts_bytes() {
+        // a Latin-1 encoded comment
+        let parsed = try_parse_hosts_bytes(b"# caf\xe9\n127.0.0.1\tlocalhost\n").unwrap();
+        assert_eq!(
+            vec![
+                HostsPart::Comment(" caf\u{fffd}".into(), CommentMarker::Hash, "".into()),
+                HostsPart::Entry(
+                    IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+                    vec!["localhost".into()],
+                    None
+                ),
+                HostsPart::Empty("".into()),
+            ],
+            parsed
+        );
+        assert!(try_parse_hosts_bytes(b"127.0.0.1\tcaf\xe9\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_hosts_with_lines() {
+        let data = "127.0.0.1\tlocalhost\n# comment\r\n\n10.0.20.4\tintranet.someclub.example\n\r::1\tlocalhost\n";
+        let parsed = try_parse_hosts_with_lines(data).unwrap();
+        let lines: Vec<usize> = parsed.iter().map(|(line, _)| *line).collect();
+        assert_eq!(vec![1, 2, 3, 4, 5, 6], lines);
+        assert!(parsed[3].1.matches_hostname("intranet.someclub.example"));
+        assert!(parsed[4].1.matches_hostname("localhost"));
+        for (line, part) in &parsed {
+            if part.matches_hostname("intranet.someclub.example") {
+                assert_eq!(
+                    Some("10.0.20.4\tintranet.someclub.example"),
+                    data.lines().nth(line - 1)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_hosts_with_spans() {
+        let data = "127.0.0.1\tlocalhost\r\n# comment\n  \n\n10.0.20.4\tintranet.someclub.example #  trailing!\n\r::1 localhost\n";
+        let parsed = try_parse_hosts_with_spans(data).unwrap();
+        assert_eq!(
+            try_parse_hosts(data).unwrap(),
+            parsed
+                .iter()
+                .map(|(_, part)| part.clone())
+                .collect::<Vec<_>>()
+        );
+        let mut rebuilt = String::new();
+        let mut end = 0;
+        for (span, _) in &parsed {
+            let gap = &data[end..span.start];
+            assert!(["", "\n", "\r\n", "\n\r"].contains(&gap), "{:?}", gap);
+            rebuilt.push_str(gap);
+            rebuilt.push_str(&data[span.clone()]);
+            end = span.end;
+        }
+        assert_eq!(data, rebuilt);
+        assert_eq!("# comment", &data[parsed[1].0.clone()]);
+        assert_eq!("  ", &data[parsed[2].0.clone()]);
+    }
+
+    #[test]
+    fn test_family_map_key() {
+        let data = "10.0.0.1\tsomehost\n::1\tlocalhost\n# 10.0.0.2\totherhost\n\n";
+        let mut counts: HashMap<HostsPartFamily, usize> = HashMap::new();
+        for family in try_parse_hosts(data)
+            .unwrap()
+            .iter()
+            .filter_map(HostsPart::get_family)
+        {
+            *counts.entry(family).or_default() += 1;
+        }
+        assert_eq!(Some(&2), counts.get(&HostsPartFamily::IPv4));
+        assert_eq!(Some(&1), counts.get(&HostsPartFamily::IPv6));
+        assert_eq!("IPv4", HostsPartFamily::IPv4.to_string());
+        assert_eq!("IPv6", HostsPartFamily::IPv6.to_string());
+    }
 }
@@ -0,0 +1,505 @@
+use std::fs::{
+    canonicalize, metadata, read_dir, read_to_string, remove_file, rename, symlink_metadata, File,
+    OpenOptions, Permissions,
+};
+use std::io::{self, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const BACKUP_INFIX: &str = ".bak-";
+/// Permissions of a newly created file if not configured otherwise.
+const DEFAULT_MODE: u32 = 0o644;
+
+/// Atomically replaces the file at `path` by `content`. The content is written to `path_new` first,
+/// which must not exist yet, synced to disk and then moved into place. Finally, the parent
+/// directory is synced as well so the rename itself survives a crash.
+///
+/// Should `path_new` reside on a different file system than `path`, the content is written to a
+/// temporary sibling of `path` instead and moved into place from there.
+///
+/// The new file gets the permission bits `mode` or, given `None`, those of the file it replaces,
+/// `0644` if there is none. The process umask does not apply. With the `selinux` feature, the
+/// SELinux context and user xattrs of the replaced file are copied over as well.
+pub fn write_atomically(
+    path: &Path,
+    path_new: &Path,
+    content: &str,
+    mode: Option<u32>,
+) -> Result<(), String> {
+    write_atomically_with(path, path_new, content, mode, |from, to| rename(from, to))
+}
+
+fn write_atomically_with<F>(
+    path: &Path,
+    path_new: &Path,
+    content: &str,
+    mode: Option<u32>,
+    rename: F,
+) -> Result<(), String>
+where
+    F: Fn(&Path, &Path) -> io::Result<()>,
+{
+    let mode = mode_of(path, mode)?;
+    write_new(path_new, content, mode)?;
+    #[cfg(feature = "selinux")]
+    copy_xattrs(path, path_new);
+    match rename(path_new, path) {
+        Ok(()) => {}
+        Err(err) if err.raw_os_error() == Some(libc::EXDEV) => {
+            // cross-device rename, retry from within the target directory
+            let path_tmp = sibling_tmp(path);
+            let moved = write_new(&path_tmp, content, mode).and_then(|()| {
+                #[cfg(feature = "selinux")]
+                copy_xattrs(path, &path_tmp);
+                rename(&path_tmp, path).map_err(|err| {
+                    // the hosts file is untouched, do not leave the copy behind either
+                    let _ = remove_file(&path_tmp);
+                    format!("unable to move {:?} into place! {}", path_tmp, err)
+                })
+            });
+            if let Err(err) = moved {
+                // a stale path_new would block the next run
+                let _ = remove_file(path_new);
+                return Err(err);
+            }
+            remove_file(path_new).map_err(|err| {
+                format!(
+                    "hosts file was replaced, but unable to remove {:?}: {}",
+                    path_new, err
+                )
+            })?;
+        }
+        Err(err) => {
+            return Err(format!("unable to move new hosts file into place! {}", err));
+        }
+    }
+    sync_parent_dir(path)
+}
+
+/// Stores `content` as a new timestamped backup next to `path`, named
+/// `<name>.bak-<seconds>.<nanoseconds>`. Returns the path of the backup.
+pub fn create_backup(path: &Path, content: &str) -> Result<PathBuf, String> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|err| format!("system time is before UNIX epoch: {}", err))?;
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(
+        "{}{}.{:09}",
+        BACKUP_INFIX,
+        now.as_secs(),
+        now.subsec_nanos()
+    ));
+    let path_backup = path.with_file_name(name);
+    write_new(&path_backup, content, mode_of(path, None)?)?;
+    Ok(path_backup)
+}
+
+/// Lists all backups of `path`, oldest first.
+pub fn list_backups(path: &Path) -> Result<Vec<PathBuf>, String> {
+    let dir = parent_dir(path);
+    let prefix = format!(
+        "{}{}",
+        path.file_name().unwrap_or_default().to_string_lossy(),
+        BACKUP_INFIX
+    );
+    let mut backups = vec![];
+    for dir_entry in
+        read_dir(dir).map_err(|err| format!("unable to list backups in {:?}: {}", dir, err))?
+    {
+        let dir_entry =
+            dir_entry.map_err(|err| format!("unable to list backups in {:?}: {}", dir, err))?;
+        let name = dir_entry.file_name();
+        let stamp = match name.to_str().and_then(|name| name.strip_prefix(&prefix)) {
+            Some(stamp) => stamp,
+            None => continue,
+        };
+        let mut split = stamp.splitn(2, '.');
+        if let (Some(Ok(secs)), Some(Ok(nanos))) = (
+            split.next().map(str::parse::<u64>),
+            split.next().map(str::parse::<u32>),
+        ) {
+            backups.push(((secs, nanos), dir_entry.path()));
+        }
+    }
+    backups.sort();
+    Ok(backups.into_iter().map(|(_, path)| path).collect())
+}
+
+/// Removes the oldest backups of `path` so that at most `keep` remain. Backups vanishing in the
+/// meantime, eg. by manual deletion, are skipped. Returns the paths of the removed backups.
+pub fn prune_backups(path: &Path, keep: usize) -> Result<Vec<PathBuf>, String> {
+    let mut backups = list_backups(path)?;
+    let excess = backups.len().saturating_sub(keep);
+    backups.truncate(excess);
+    let mut removed = vec![];
+    for path_backup in backups {
+        match remove_file(&path_backup) {
+            Ok(()) => removed.push(path_backup),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => {
+                return Err(format!(
+                    "unable to remove old backup {:?}: {}",
+                    path_backup, err
+                ))
+            }
+        }
+    }
+    Ok(removed)
+}
+
+/// Restores the most recent backup of `path` using the same atomic discipline as
+/// `write_atomically`. The restored backup is removed afterwards, so repeated rollbacks step back
+/// through older backups. Returns the path of the restored backup.
+pub fn rollback(path: &Path, path_new: &Path) -> Result<PathBuf, String> {
+    let path_backup = list_backups(path)?
+        .pop()
+        .ok_or_else(|| format!("no backup of {:?} found, unable to roll back", path))?;
+    let content = read_to_string(&path_backup)
+        .map_err(|err| format!("unable to read backup {:?}: {}", path_backup, err))?;
+    write_atomically(path, path_new, &content, None)?;
+    remove_file(&path_backup).map_err(|err| {
+        format!(
+            "unable to remove restored backup {:?}: {}",
+            path_backup, err
+        )
+    })?;
+    Ok(path_backup)
+}
+
+/// Resolves `path` if it is a symlink, as replacing it would turn the link into a regular file.
+/// Returns the file it points to, `None` if `path` is no symlink.
+pub fn resolve_symlink(path: &Path) -> Result<Option<PathBuf>, String> {
+    let metadata = symlink_metadata(path)
+        .map_err(|err| format!("unable to inspect hosts file {:?}: {}", path, err))?;
+    if !metadata.file_type().is_symlink() {
+        return Ok(None);
+    }
+    canonicalize(path)
+        .map(Some)
+        .map_err(|err| format!("unable to resolve symlink {:?}: {}", path, err))
+}
+
+/// Returns the sibling of `path` named like it plus `suffix`, eg. `/etc/hosts.new`.
+pub fn sibling_with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+/// Returns `mode` if given, else the permission bits of `path` or the default if it does not exist.
+fn mode_of(path: &Path, mode: Option<u32>) -> Result<u32, String> {
+    match mode {
+        Some(mode) => Ok(mode),
+        None => match metadata(path) {
+            Ok(metadata) => Ok(metadata.permissions().mode() & 0o7777),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(DEFAULT_MODE),
+            Err(err) => Err(format!("unable to inspect {:?}: {}", path, err)),
+        },
+    }
+}
+
+fn write_new(path_new: &Path, content: &str, mode: u32) -> Result<(), String> {
+    let mut file_new = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path_new)
+        .map_err(|err| {
+            format!(
+                "unable to open new hosts file {:?} for writing! Stale file from previous run? {}",
+                path_new, err
+            )
+        })?;
+    file_new
+        .set_permissions(Permissions::from_mode(mode))
+        .map_err(|err| format!("unable to set mode of new hosts file: {}", err))?;
+    file_new
+        .write_all(content.as_bytes())
+        .map_err(|err| format!("unable to write generated hosts file: {}", err))?;
+    file_new
+        .set_len(content.len() as u64)
+        .map_err(|err| format!("unable to truncate hosts file to right len: {}", err))?;
+    file_new
+        .flush()
+        .map_err(|err| format!("unable to flush hosts file: {}", err))?;
+    file_new
+        .sync_all()
+        .map_err(|err| format!("unable to sync new hosts file to disk: {}", err))
+}
+
+/// Copies the SELinux context and all `user.` attributes of `from` to `to`. Anything that cannot be
+/// read or restored is reported as a warning, the write proceeds regardless.
+#[cfg(feature = "selinux")]
+fn copy_xattrs(from: &Path, to: &Path) {
+    let names = match xattr::list(from) {
+        Ok(names) => names,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return,
+        Err(err) => {
+            eprintln!(
+                "warning: unable to read extended attributes of {:?}: {}",
+                from, err
+            );
+            return;
+        }
+    };
+    for name in names {
+        let relevant = name
+            .to_str()
+            .is_ok_and(|name| name == xattr::SELINUX || name.starts_with("user."));
+        if !relevant {
+            continue;
+        }
+        if let Err(err) = xattr::get(from, &name).and_then(|value| xattr::set(to, &name, &value)) {
+            eprintln!("warning: unable to restore {:?} on {:?}: {}", name, to, err);
+        }
+    }
+}
+
+#[cfg(feature = "selinux")]
+mod xattr {
+    use std::ffi::{CStr, CString};
+    use std::io;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+
+    pub const SELINUX: &str = "security.selinux";
+
+    fn c_path(path: &Path) -> io::Result<CString> {
+        CString::new(path.as_os_str().as_bytes())
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))
+    }
+
+    /// Calls `f` first to learn the size, then again to fill a buffer of that size.
+    fn read_sized<F>(f: F) -> io::Result<Vec<u8>>
+    where
+        F: Fn(*mut u8, usize) -> isize,
+    {
+        let len = f(std::ptr::null_mut(), 0);
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut buf = vec![0u8; len as usize];
+        let len = f(buf.as_mut_ptr(), buf.len());
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        buf.truncate(len as usize);
+        Ok(buf)
+    }
+
+    pub fn list(path: &Path) -> io::Result<Vec<CString>> {
+        let path = c_path(path)?;
+        let buf = read_sized(|buf, len| unsafe {
+            libc::listxattr(path.as_ptr(), buf as *mut libc::c_char, len)
+        })?;
+        Ok(buf
+            .split(|b| *b == 0)
+            .filter(|name| !name.is_empty())
+            .map(|name| CString::new(name).expect("split at NUL"))
+            .collect())
+    }
+
+    pub fn get(path: &Path, name: &CStr) -> io::Result<Vec<u8>> {
+        let path = c_path(path)?;
+        read_sized(|buf, len| unsafe {
+            libc::getxattr(path.as_ptr(), name.as_ptr(), buf as *mut libc::c_void, len)
+        })
+    }
+
+    pub fn set(path: &Path, name: &CStr, value: &[u8]) -> io::Result<()> {
+        let path = c_path(path)?;
+        let ret = unsafe {
+            libc::setxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                value.as_ptr() as *const libc::c_void,
+                value.len(),
+                0,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+fn sibling_tmp(path: &Path) -> PathBuf {
+    sibling_with_suffix(path, ".hostsmod-tmp")
+}
+
+fn parent_dir(path: &Path) -> &Path {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    }
+}
+
+fn sync_parent_dir(path: &Path) -> Result<(), String> {
+    let dir = parent_dir(path);
+    File::open(dir)
+        .and_then(|dir| dir.sync_all())
+        .map_err(|err| format!("unable to sync directory {:?} to disk: {}", dir, err))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::persist::{
+        create_backup, list_backups, prune_backups, rollback, sibling_tmp, write_atomically,
+        write_atomically_with,
+    };
+    use std::fs;
+    use std::io;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::PathBuf;
+
+    /// Creates a fresh, empty directory for a single test.
+    pub(crate) fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("hostsmod-test-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).expect("unable to create temporary test directory");
+        dir
+    }
+
+    #[test]
+    fn test_write_atomically() {
+        let dir = temp_dir("write_atomically");
+        let path = dir.join("hosts");
+        let path_new = dir.join("hosts.new");
+        fs::write(&path, "127.0.0.1\tlocalhost\n").unwrap();
+
+        write_atomically(&path, &path_new, "::1\tlocalhost\n", None).unwrap();
+        assert_eq!("::1\tlocalhost\n", fs::read_to_string(&path).unwrap());
+        assert!(!path_new.exists());
+
+        // a stale file from a previous run is never overwritten
+        fs::write(&path_new, "stale").unwrap();
+        assert!(write_atomically(&path, &path_new, "10.0.0.1\tsomehost\n", None).is_err());
+        assert_eq!("::1\tlocalhost\n", fs::read_to_string(&path).unwrap());
+        assert_eq!("stale", fs::read_to_string(&path_new).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_write_atomically_mode() {
+        let dir = temp_dir("write_atomically_mode");
+        let path = dir.join("hosts");
+        let path_new = dir.join("hosts.new");
+        let mode = |path: &PathBuf| fs::metadata(path).unwrap().permissions().mode() & 0o7777;
+
+        write_atomically(&path, &path_new, "127.0.0.1\tlocalhost\n", None).unwrap();
+        assert_eq!(0o644, mode(&path));
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+        write_atomically(&path, &path_new, "::1\tlocalhost\n", None).unwrap();
+        assert_eq!(0o640, mode(&path));
+        write_atomically(&path, &path_new, "127.0.0.1\tlocalhost\n", Some(0o604)).unwrap();
+        assert_eq!(0o604, mode(&path));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "selinux")]
+    #[test]
+    fn test_write_atomically_xattrs() {
+        use crate::persist::xattr;
+        use std::ffi::CString;
+
+        let dir = temp_dir("write_atomically_xattrs");
+        let path = dir.join("hosts");
+        let path_new = dir.join("hosts.new");
+        fs::write(&path, "127.0.0.1\tlocalhost\n").unwrap();
+        let name = CString::new("user.hostsmod").unwrap();
+        if let Err(err) = xattr::set(&path, &name, b"kept") {
+            eprintln!("skipping, no user xattrs on {:?}: {}", dir, err);
+            fs::remove_dir_all(&dir).unwrap();
+            return;
+        }
+        // reapplied as is where SELinux is active, otherwise there is nothing to carry over
+        let context = CString::new(xattr::SELINUX).unwrap();
+        let context_before = xattr::get(&path, &context).ok();
+
+        write_atomically(&path, &path_new, "::1\tlocalhost\n", None).unwrap();
+        assert_eq!(b"kept".to_vec(), xattr::get(&path, &name).unwrap());
+        assert_eq!(context_before, xattr::get(&path, &context).ok());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_write_atomically_cross_device() {
+        let dir = temp_dir("write_atomically_cross_device");
+        let path = dir.join("hosts");
+        let path_new = dir.join("hosts.new");
+        fs::write(&path, "127.0.0.1\tlocalhost\n").unwrap();
+
+        let rename_exdev = |from: &std::path::Path, to: &std::path::Path| {
+            if from == path_new {
+                Err(io::Error::from_raw_os_error(libc::EXDEV))
+            } else {
+                fs::rename(from, to)
+            }
+        };
+        write_atomically_with(&path, &path_new, "::1\tlocalhost\n", None, rename_exdev).unwrap();
+        assert_eq!("::1\tlocalhost\n", fs::read_to_string(&path).unwrap());
+        assert_eq!(1, fs::read_dir(&dir).unwrap().count());
+
+        let rename_fail = |_: &std::path::Path, _: &std::path::Path| {
+            Err(io::Error::from_raw_os_error(libc::EXDEV))
+        };
+        let err = write_atomically_with(&path, &path_new, "", None, rename_fail).unwrap_err();
+        assert!(err.contains("into place"), "{}", err);
+        assert_eq!("::1\tlocalhost\n", fs::read_to_string(&path).unwrap());
+        assert_eq!(1, fs::read_dir(&dir).unwrap().count());
+
+        // unable to write the temporary sibling
+        let path_tmp = sibling_tmp(&path);
+        fs::write(&path_tmp, "stale").unwrap();
+        assert!(write_atomically_with(&path, &path_new, "", None, rename_exdev).is_err());
+        assert!(!path_new.exists());
+        assert_eq!("::1\tlocalhost\n", fs::read_to_string(&path).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_rollback() {
+        let dir = temp_dir("rollback");
+        let path = dir.join("hosts");
+        let path_new = dir.join("hosts.new");
+        assert!(rollback(&path, &path_new).is_err());
+
+        let orig = "127.0.0.1\tlocalhost\n";
+        fs::write(&path, orig).unwrap();
+        let path_backup = create_backup(&path, orig).unwrap();
+        write_atomically(&path, &path_new, "10.0.0.1\tsomehost\n", None).unwrap();
+        assert_eq!(vec![path_backup.clone()], list_backups(&path).unwrap());
+
+        assert_eq!(path_backup, rollback(&path, &path_new).unwrap());
+        assert_eq!(orig, fs::read_to_string(&path).unwrap());
+        assert!(list_backups(&path).unwrap().is_empty());
+        assert!(rollback(&path, &path_new).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_prune_backups() {
+        let dir = temp_dir("prune_backups");
+        let path = dir.join("hosts");
+        let backups = (0..5)
+            .map(|i| create_backup(&path, &format!("{}", i)).unwrap())
+            .collect::<Vec<_>>();
+        // unrelated files are left alone
+        fs::write(dir.join("hosts.bak-garbage"), "").unwrap();
+        fs::write(dir.join("other.bak-1.000000000"), "").unwrap();
+        assert_eq!(backups, list_backups(&path).unwrap());
+
+        // manually deleted backups do not count
+        fs::remove_file(&backups[1]).unwrap();
+        assert_eq!(vec![backups[0].clone()], prune_backups(&path, 3).unwrap());
+        assert_eq!(backups[2..].to_vec(), list_backups(&path).unwrap());
+        assert!(prune_backups(&path, 3).unwrap().is_empty());
+        assert_eq!(
+            "4",
+            fs::read_to_string(list_backups(&path).unwrap().pop().unwrap()).unwrap()
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
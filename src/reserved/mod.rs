@@ -0,0 +1,305 @@
+use crate::file::HostsFile;
+use crate::parse::HostsPart;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// Placeholder for the hostname of the machine in `DONT_TOUCH`.
+pub const RESERVED_HOSTNAME: &str = "%HOSTNAME%";
+/// Placeholder for the fully-qualified domain name of the machine, see `resolve_reserved`.
+pub const RESERVED_FQDN: &str = "%FQDN%";
+#[allow(missing_docs)]
+pub const RESERVED_LOCALHOST: &str = "localhost";
+#[allow(missing_docs)]
+pub const RESERVED_IP6_LOCALHOST: &str = "ip6-localhost";
+#[allow(missing_docs)]
+pub const RESERVED_IP6_LOOPBACK: &str = "ip6-loopback";
+#[allow(missing_docs)]
+pub const RESERVED_IP6_ALLNODES: &str = "ip6-allnodes";
+#[allow(missing_docs)]
+pub const RESERVED_IP6_ALLROUTERS: &str = "ip6-allrouters";
+
+const IP4_LOCAL: Ipv4Addr = Ipv4Addr::new(127, 0, 0, 1);
+const IP4_LOCAL_ALT: Ipv4Addr = Ipv4Addr::new(127, 0, 1, 1);
+const IP6_LOCAL: Ipv6Addr = Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1);
+const IP6_ALL_NODES: Ipv6Addr = Ipv6Addr::new(65282, 0, 0, 0, 0, 0, 0, 1);
+const IP6_ALL_ROUTERS: Ipv6Addr = Ipv6Addr::new(65282, 0, 0, 0, 0, 0, 0, 2);
+
+/// Mappings vital for the function of most systems, which must not be changed.
+pub const DONT_TOUCH: &[HostsEntry] = &[
+    HostsEntry {
+        ip: IpAddr::V4(IP4_LOCAL),
+        hostname: Cow::Borrowed(RESERVED_LOCALHOST),
+    },
+    HostsEntry {
+        ip: IpAddr::V4(IP4_LOCAL_ALT),
+        hostname: Cow::Borrowed(RESERVED_HOSTNAME),
+    },
+    HostsEntry {
+        ip: IpAddr::V6(IP6_LOCAL),
+        hostname: Cow::Borrowed(RESERVED_LOCALHOST),
+    },
+    HostsEntry {
+        ip: IpAddr::V6(IP6_LOCAL),
+        hostname: Cow::Borrowed(RESERVED_IP6_LOCALHOST),
+    },
+    HostsEntry {
+        ip: IpAddr::V6(IP6_LOCAL),
+        hostname: Cow::Borrowed(RESERVED_IP6_LOOPBACK),
+    },
+    HostsEntry {
+        ip: IpAddr::V6(IP6_ALL_NODES),
+        hostname: Cow::Borrowed(RESERVED_IP6_ALLNODES),
+    },
+    HostsEntry {
+        ip: IpAddr::V6(IP6_ALL_ROUTERS),
+        hostname: Cow::Borrowed(RESERVED_IP6_ALLROUTERS),
+    },
+];
+
+/// A single mapping of IP address to hostname.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HostsEntry<'a> {
+    #[allow(missing_docs)]
+    pub ip: IpAddr,
+    #[allow(missing_docs)]
+    pub hostname: Cow<'a, str>,
+}
+
+impl<'a> HostsEntry<'a> {
+    /// Returns the hostname, with `RESERVED_HOSTNAME` substituted by `hostname`.
+    pub fn resolve_hostname<'s>(&'s self, hostname: &'s str) -> &'s str {
+        if self.hostname == RESERVED_HOSTNAME {
+            hostname
+        } else {
+            &self.hostname
+        }
+    }
+}
+
+/// Substitutes `RESERVED_HOSTNAME` by `hostname` and `RESERVED_FQDN` by `fqdn` in the `protected`
+/// mappings. Without a known FQDN, mappings using `RESERVED_FQDN` are left out.
+pub fn resolve_reserved(
+    protected: &[HostsEntry],
+    hostname: &str,
+    fqdn: Option<&str>,
+) -> Vec<HostsEntry<'static>> {
+    protected
+        .iter()
+        .filter_map(|entry| {
+            let host = if entry.hostname == RESERVED_FQDN {
+                fqdn?
+            } else {
+                entry.resolve_hostname(hostname)
+            };
+            Some(HostsEntry {
+                ip: entry.ip,
+                hostname: Cow::Owned(host.to_string()),
+            })
+        })
+        .collect()
+}
+
+/// Finds all parts using a protected hostname without containing any protected mapping, eg. a
+/// remapped `localhost`.
+pub(crate) fn find_conflicts<'p, 'a>(
+    parts: &'p [HostsPart<'a>],
+    protected: &[HostsEntry],
+    hostname: &str,
+) -> Vec<&'p HostsPart<'a>> {
+    let is_protected = |part: &HostsPart, entry: &HostsEntry| {
+        part.matches_hostname(entry.resolve_hostname(hostname))
+            && part.matches_ip_canonical(&entry.ip)
+    };
+    parts
+        .iter()
+        .filter(|part| {
+            protected
+                .iter()
+                .any(|entry| part.matches_hostname(entry.resolve_hostname(hostname)))
+                && !protected.iter().any(|entry| is_protected(part, entry))
+        })
+        .collect()
+}
+
+/// Reason a modification of the hosts file violates the protected mappings, see
+/// `verify_reserved`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ReservedViolation {
+    /// A protected mapping present before is missing afterwards.
+    Removed(IpAddr, String),
+    /// A part uses a protected hostname without containing any protected mapping.
+    Conflict(HostsPart<'static>),
+}
+
+impl std::fmt::Display for ReservedViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ReservedViolation::Removed(ip, host) => {
+                write!(f, "reserved entry {} {} was removed", ip, host)
+            }
+            ReservedViolation::Conflict(part) => {
+                write!(f, "reserved hostname was remapped: {:?}", part)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ReservedViolation {}
+
+/// Records which of the `protected` mappings `parts` contain, in the order of `protected`, to be
+/// passed to `verify_reserved` after modifying them. `RESERVED_HOSTNAME` stands for `hostname`.
+pub fn reserved_present(
+    parts: &[HostsPart],
+    protected: &[HostsEntry],
+    hostname: &str,
+) -> Vec<bool> {
+    protected
+        .iter()
+        .map(|entry| has_mapping(parts, entry, hostname))
+        .collect()
+}
+
+/// Verifies the modified `after` leaves the `protected` mappings intact: none `present` before,
+/// as recorded by `reserved_present`, may be missing and no part may use a protected hostname
+/// without a protected mapping. `RESERVED_HOSTNAME` stands for `hostname`.
+pub fn verify_reserved(
+    present: &[bool],
+    after: &[HostsPart],
+    protected: &[HostsEntry],
+    hostname: &str,
+) -> Result<(), ReservedViolation> {
+    for (entry, _) in protected
+        .iter()
+        .zip(present)
+        .filter(|(_, present)| **present)
+    {
+        if !has_mapping(after, entry, hostname) {
+            return Err(ReservedViolation::Removed(
+                entry.ip,
+                entry.resolve_hostname(hostname).to_string(),
+            ));
+        }
+    }
+    match find_conflicts(after, protected, hostname).first() {
+        Some(part) => Err(ReservedViolation::Conflict((*part).clone().into_owned())),
+        None => Ok(()),
+    }
+}
+
+fn has_mapping(parts: &[HostsPart], entry: &HostsEntry, hostname: &str) -> bool {
+    let host = entry.resolve_hostname(hostname);
+    parts
+        .iter()
+        .any(|part| part.matches_hostname(host) && part.matches_ip_canonical(&entry.ip))
+}
+
+impl<'a> HostsFile<'a> {
+    /// Returns all parts colliding with the `protected` mappings: they use a protected hostname,
+    /// but contain none of the protected mappings. `RESERVED_HOSTNAME` stands for `hostname`.
+    /// Considers commented-out entries.
+    pub fn reserved_conflicts(
+        &self,
+        protected: &[HostsEntry],
+        hostname: &str,
+    ) -> Vec<&HostsPart<'a>> {
+        find_conflicts(self.parts(), protected, hostname)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::file::HostsFile;
+    use crate::reserved::{
+        reserved_present, resolve_reserved, verify_reserved, HostsEntry, ReservedViolation,
+        DONT_TOUCH, RESERVED_FQDN,
+    };
+    use std::borrow::Cow;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    #[test]
+    fn test_reserved_conflicts() {
+        let data = r##"127.0.0.1	localhost
+127.0.1.1	testhost
+::1	localhost ip6-localhost ip6-loopback
+ff02::1 ip6-allnodes
+ff02::2 ip6-allrouters
+10.0.0.1	somehost
+"##;
+        let file = HostsFile::parse(data).unwrap();
+        assert!(file.reserved_conflicts(DONT_TOUCH, "testhost").is_empty());
+        assert!(file.parts()[1].is_loopback_entry());
+        assert!(file.parts()[2].is_loopback_entry());
+        assert!(!file.parts()[5].is_loopback_entry());
+
+        let data = "127.0.0.1\tlocalhost\n10.0.0.1\tlocalhost\n# 10.0.0.2\ttesthost\n";
+        let file = HostsFile::parse(data).unwrap();
+        let conflicts = file.reserved_conflicts(DONT_TOUCH, "testhost");
+        assert_eq!(vec![&file.parts()[1], &file.parts()[2]], conflicts);
+        assert_eq!(1, file.reserved_conflicts(DONT_TOUCH, "otherhost").len());
+    }
+
+    #[test]
+    fn test_verify_reserved() {
+        let before = HostsFile::parse("127.0.0.1\tlocalhost\n127.0.1.1\ttesthost\n").unwrap();
+        let present = reserved_present(before.parts(), DONT_TOUCH, "testhost");
+        assert_eq!(2, present.iter().filter(|present| **present).count());
+        let allowed =
+            HostsFile::parse("127.0.0.1\tlocalhost somehost\n127.0.1.1\ttesthost\n").unwrap();
+        assert_eq!(
+            Ok(()),
+            verify_reserved(&present, allowed.parts(), DONT_TOUCH, "testhost")
+        );
+
+        let removed = HostsFile::parse("127.0.0.1\tlocalhost\n").unwrap();
+        assert_eq!(
+            Err(ReservedViolation::Removed(
+                IpAddr::V4(Ipv4Addr::new(127, 0, 1, 1)),
+                "testhost".into()
+            )),
+            verify_reserved(&present, removed.parts(), DONT_TOUCH, "testhost")
+        );
+        // was never present, nothing to remove
+        assert_eq!(
+            Ok(()),
+            verify_reserved(
+                &reserved_present(removed.parts(), DONT_TOUCH, "testhost"),
+                removed.parts(),
+                DONT_TOUCH,
+                "testhost"
+            )
+        );
+
+        let remapped =
+            HostsFile::parse("127.0.0.1\tlocalhost\n127.0.1.1\ttesthost\n10.0.0.1\tlocalhost\n")
+                .unwrap();
+        assert_eq!(
+            Err(ReservedViolation::Conflict(remapped.parts()[2].clone())),
+            verify_reserved(&present, remapped.parts(), DONT_TOUCH, "testhost")
+        );
+    }
+
+    #[test]
+    fn test_resolve_fqdn() {
+        let ip = IpAddr::V4(Ipv4Addr::new(127, 0, 1, 1));
+        let protected = [HostsEntry {
+            ip,
+            hostname: Cow::Borrowed(RESERVED_FQDN),
+        }];
+        assert!(resolve_reserved(&protected, "testhost", None).is_empty());
+        let resolved = resolve_reserved(&protected, "testhost", Some("testhost.example"));
+        assert_eq!("testhost.example", resolved[0].hostname);
+
+        let before = HostsFile::parse("127.0.1.1\ttesthost.example testhost\n").unwrap();
+        let after = HostsFile::parse("127.0.1.1\ttesthost\n").unwrap();
+        assert_eq!(
+            Err(ReservedViolation::Removed(ip, "testhost.example".into())),
+            verify_reserved(
+                &reserved_present(before.parts(), &resolved, "testhost"),
+                after.parts(),
+                &resolved,
+                "testhost"
+            )
+        );
+    }
+}